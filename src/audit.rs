@@ -0,0 +1,187 @@
+// Records who ran which command where, for compliance. Enabled with `TAG1BOT_AUDIT_LOG`, and
+// admins can review what a user has run with `audit @user`.
+
+use regex::Regex;
+use rusqlite::params;
+use std::env;
+
+use crate::db::{self, DbError};
+use crate::slack;
+use crate::util;
+
+const REGEX_AUDIT: &str = r"(?i)^audit <@(\w+)(?:\|[^>]*)?>$";
+
+// How many commands `audit @user` lists.
+const AUDIT_LIMIT: u32 = 20;
+
+// The first word of each command that is recorded, and whether the rest of the command is sent to
+// ChatGPT. Karma changes and seen tracking aren't commands, so are never recorded.
+const AUDITED_COMMANDS: &[(&str, bool)] = &[
+    ("alert", false),
+    ("audit", false),
+    ("channelinfo", false),
+    ("chatgpt", true),
+    ("convert", false),
+    ("detect", true),
+    ("disable", false),
+    ("enable", false),
+    ("flip", false),
+    ("history", false),
+    ("karma", false),
+    ("roll", false),
+    ("seen", false),
+    ("stats", false),
+    ("tldr", false),
+    ("uptime", false),
+    ("weather", false),
+];
+
+// A recorded command.
+#[derive(Debug)]
+struct AuditEntry {
+    ts: u64,
+    channel_id: String,
+    command: String,
+    text: Option<String>,
+}
+
+// If auditing is enabled and the message is a command, record it. The text of commands sent to
+// ChatGPT isn't recorded if `TAG1BOT_AUDIT_EXCLUDE_AI` is set.
+pub(crate) fn record(message: &slack::Message) {
+    if env::var("TAG1BOT_AUDIT_LOG").is_err() {
+        return;
+    }
+    let (command, is_ai) = match command_name(&message.text) {
+        Some(command) => command,
+        None => return,
+    };
+    let text = if is_ai && env::var("TAG1BOT_AUDIT_EXCLUDE_AI").is_ok() {
+        None
+    } else {
+        Some(message.text.trim())
+    };
+    if let Err(e) = insert_audit(message, command, text) {
+        log::error!("failed to record {} in audit log: {}", command, e);
+    }
+}
+
+// Determine which command, if any, the text is. Returns the command and whether it's sent to
+// ChatGPT.
+fn command_name(text: &str) -> Option<(&'static str, bool)> {
+    let first_word = text.split_whitespace().next()?.to_lowercase();
+    AUDITED_COMMANDS
+        .iter()
+        .copied()
+        .find(|(command, _)| *command == first_word)
+}
+
+// Check if an admin is asking `audit @user`.
+pub(crate) async fn process_message(message: &slack::Message) -> Option<(String, String)> {
+    let trimmed_text = message.text.trim();
+
+    let re = Regex::new(REGEX_AUDIT).expect("failed to compile REGEX_AUDIT");
+    let cap = re.captures(trimmed_text)?;
+    let user_id = &cap[1];
+
+    // Only workspace admins can see what others have run.
+    if !slack::message_is_authorized(message, slack::AuthLevel::Admin).await {
+        return slack::reply_unauthorized(message, slack::AuthLevel::Admin).await;
+    }
+
+    let reply_message = match recent_commands(user_id) {
+        Ok(entries) if entries.is_empty() => {
+            if env::var("TAG1BOT_AUDIT_LOG").is_ok() {
+                format!("<@{}> hasn't run any commands.", user_id)
+            } else {
+                "The audit log is disabled, set `TAG1BOT_AUDIT_LOG` to enable it.".to_string()
+            }
+        }
+        Ok(entries) => {
+            let lines: Vec<String> = entries
+                .iter()
+                .map(|entry| {
+                    let text = match entry.text.as_ref() {
+                        Some(text) => format!("`{}`", text.replace('`', "'")),
+                        None => format!("`{}` _(not logged)_", entry.command),
+                    };
+                    format!(
+                        "• {} in <#{}>: {}",
+                        util::time_ago(entry.ts, false),
+                        entry.channel_id,
+                        text
+                    )
+                })
+                .collect();
+            format!(
+                "Most recent commands run by <@{}>:\n{}",
+                user_id,
+                lines.join("\n")
+            )
+        }
+        Err(e) => {
+            log::error!("failed to load audit log for {}: {}", user_id, e);
+            let reply_message = "Sorry, I failed to load the audit log.".to_string();
+            return slack::reply_ephemeral(message, reply_message).await;
+        }
+    };
+
+    // The audit log is only for the admin that asked.
+    slack::reply_ephemeral(message, reply_message).await
+}
+
+fn insert_audit(
+    message: &slack::Message,
+    command: &str,
+    text: Option<&str>,
+) -> Result<(), DbError> {
+    let db = db::get_conn()?;
+    db.execute(
+        "INSERT INTO audit_log (ts, user_id, channel_id, command, text) VALUES(?1, ?2, ?3, ?4, ?5)",
+        params![
+            util::timestamp_now(),
+            message.user_id,
+            message.channel_id,
+            command,
+            text
+        ],
+    )?;
+    Ok(())
+}
+
+fn recent_commands(user_id: &str) -> Result<Vec<AuditEntry>, DbError> {
+    let db = db::get_conn()?;
+    let mut statement = db.prepare(
+        "SELECT ts, channel_id, command, text FROM audit_log WHERE user_id = ?1 ORDER BY id DESC LIMIT ?2",
+    )?;
+    let entries = statement
+        .query_map(params![user_id, AUDIT_LIMIT], |row| {
+            Ok(AuditEntry {
+                ts: row.get(0)?,
+                channel_id: row.get(1)?,
+                command: row.get(2)?,
+                text: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_commands() {
+        assert_eq!(
+            command_name("convert 1 USD to EUR"),
+            Some(("convert", false))
+        );
+        assert_eq!(command_name("  Seen foo?"), Some(("seen", false)));
+        assert_eq!(command_name("chatgpt hello"), Some(("chatgpt", true)));
+        assert_eq!(command_name("detect hola"), Some(("detect", true)));
+        // Conversation, including karma, isn't a command.
+        assert_eq!(command_name("foo++"), None);
+        assert_eq!(command_name("seeing is believing"), None);
+        assert_eq!(command_name(""), None);
+    }
+}
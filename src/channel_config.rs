@@ -0,0 +1,170 @@
+// Enables and disables features per channel.
+// For example, `disable karma here` or `enable chatgpt here`.
+
+use regex::Regex;
+use rusqlite::params;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::db::{self, DbError};
+use crate::slack;
+
+const REGEX_CHANNEL_CONFIG: &str = r"(?i)^(enable|disable) (\w+) here$";
+
+// Features that can be enabled or disabled per channel.
+pub(crate) const FEATURES: &[&str] = &["karma", "seen", "dice", "convert", "weather", "chatgpt"];
+
+// Cache of channel configuration, keyed by `(channel, feature)`. Features without an entry are
+// enabled.
+lazy_static! {
+    static ref CHANNEL_CONFIG: RwLock<HashMap<(String, String), bool>> =
+        RwLock::new(load_channel_config().unwrap_or_else(|e| {
+            log::error!("failed to load channel config: {}", e);
+            HashMap::new()
+        }));
+}
+
+// Check if an admin is enabling or disabling a feature in this channel.
+pub(crate) async fn process_message(message: &slack::Message) -> Option<(String, String)> {
+    let trimmed_text = message.text.trim();
+
+    let re = Regex::new(REGEX_CHANNEL_CONFIG).expect("failed to compile REGEX_CHANNEL_CONFIG");
+    let cap = re.captures(trimmed_text)?;
+    let enable = cap[1].eq_ignore_ascii_case("enable");
+    let feature = cap[2].to_lowercase();
+
+    // Only workspace admins can change channel configuration.
+    if !slack::message_is_authorized(message, slack::AuthLevel::Admin).await {
+        return slack::reply_unauthorized(message, slack::AuthLevel::Admin).await;
+    }
+
+    let reply_message = if !FEATURES.contains(&feature.as_str()) {
+        format!(
+            "Sorry, I don't know `{}`. Features are: {}.",
+            feature,
+            FEATURES.join(", ")
+        )
+    } else if let Err(e) = set_enabled(&message.channel_id, &feature, enable) {
+        log::error!("failed to update channel config: {}", e);
+        format!("Sorry, I failed to update `{}` for this channel.", feature)
+    } else if enable {
+        format!("`{}` is now enabled in <#{}>.", feature, message.channel_id)
+    } else {
+        format!(
+            "`{}` is now disabled in <#{}>.",
+            feature, message.channel_id
+        )
+    };
+
+    // Always reply in a thread: determine if reply is in a new thread or an existing thread.
+    let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
+        thread_ts.clone()
+    } else {
+        message.ts.clone()
+    };
+    Some((reply_thread_ts, reply_message))
+}
+
+// Determine if a feature is enabled in a channel. Features are enabled everywhere by default.
+pub(crate) fn is_enabled(channel: &str, feature: &str) -> bool {
+    let channel_config = CHANNEL_CONFIG.read().expect("channel config lock poisoned");
+    *channel_config
+        .get(&(channel.to_string(), feature.to_string()))
+        .unwrap_or(&true)
+}
+
+// Enable or disable a feature in a channel, updating both the database and the cache.
+fn set_enabled(channel: &str, feature: &str, enabled: bool) -> Result<(), DbError> {
+    let db = db::get_conn()?;
+    db.execute(
+        "INSERT INTO channel_config (channel, feature, enabled) VALUES(?1, ?2, ?3)
+        ON CONFLICT (channel, feature) DO UPDATE SET enabled = excluded.enabled",
+        params![channel, feature, enabled],
+    )?;
+
+    let mut channel_config = CHANNEL_CONFIG
+        .write()
+        .expect("channel config lock poisoned");
+    channel_config.insert((channel.to_string(), feature.to_string()), enabled);
+    Ok(())
+}
+
+// Load all channel configuration from the database.
+fn load_channel_config() -> Result<HashMap<(String, String), bool>, DbError> {
+    let db = db::get_conn()?;
+    let mut statement = db.prepare("SELECT channel, feature, enabled FROM channel_config")?;
+    let channel_config_iterator =
+        statement.query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?;
+
+    let mut channel_config = HashMap::new();
+    for entry in channel_config_iterator {
+        let (key, enabled) = entry?;
+        channel_config.insert(key, enabled);
+    }
+    Ok(channel_config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::karma;
+    use crate::testing;
+
+    #[test]
+    fn set_enabled_updates_cache_and_database() {
+        testing::setup();
+        set_enabled("CCONFIG", "dice", false).unwrap();
+        assert!(!is_enabled("CCONFIG", "dice"));
+        assert!(is_enabled("CCONFIG", "seen"));
+        set_enabled("CCONFIG", "dice", true).unwrap();
+        assert!(is_enabled("CCONFIG", "dice"));
+        assert_eq!(
+            load_channel_config()
+                .unwrap()
+                .get(&("CCONFIG".to_string(), "dice".to_string())),
+            Some(&true)
+        );
+    }
+
+    #[test]
+    fn features_default_to_enabled() {
+        testing::setup();
+        for feature in FEATURES {
+            assert!(is_enabled("CUNCONFIGURED", feature), "{}", feature);
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_features_for_unknown_feature() {
+        let message = testing::message_from(testing::TEST_ADMIN_ID, "CCONFIG", "enable foo here");
+        let replies = testing::replies(&message).await;
+        assert_eq!(
+            replies,
+            vec![(
+                "1700000000.000100".to_string(),
+                "Sorry, I don't know `foo`. Features are: karma, seen, dice, convert, weather, chatgpt."
+                    .to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn pipeline_disable_karma_here() {
+        let message =
+            testing::message_from(testing::TEST_ADMIN_ID, "CNOKARMA", "disable karma here");
+        let replies = testing::replies(&message).await;
+        assert_eq!(
+            replies,
+            vec![(
+                "1700000000.000100".to_string(),
+                "`karma` is now disabled in <#CNOKARMA>.".to_string()
+            )]
+        );
+
+        let message = testing::message_from(testing::TEST_USER_ID, "CNOKARMA", "muted++");
+        assert!(testing::replies(&message).await.is_empty());
+        assert_eq!(karma::rank("muted").unwrap(), None);
+        // Other channels are unaffected.
+        assert!(is_enabled(testing::TEST_CHANNEL_ID, "karma"));
+    }
+}
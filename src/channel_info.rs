@@ -0,0 +1,160 @@
+// Reports details about the current channel for `channelinfo`, such as its topic, purpose, when it
+// was created and what it used to be called.
+
+use regex::Regex;
+
+use crate::slack;
+use crate::util;
+
+const REGEX_CHANNEL_INFO: &str = r"(?i)^channel ?info(?:\?)?$";
+
+// Check if user is asking about the channel.
+pub(crate) async fn process_message(message: &slack::Message) -> Option<(String, String)> {
+    let trimmed_text = message.text.trim();
+
+    let re = Regex::new(REGEX_CHANNEL_INFO).expect("failed to compile REGEX_CHANNEL_INFO");
+    if !re.is_match(trimmed_text) {
+        return None;
+    }
+
+    let reply_message = match message.channel().await {
+        Some(channel) => format_channel(channel),
+        None => {
+            let reply_message = "Sorry, I failed to look up this channel.".to_string();
+            return slack::reply_ephemeral(message, reply_message).await;
+        }
+    };
+
+    // Always reply in a thread: determine if reply is in a new thread or an existing thread.
+    let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
+        thread_ts.to_string()
+    } else {
+        message.ts.to_string()
+    };
+
+    Some((reply_thread_ts, reply_message))
+}
+
+// Describe a channel, leaving out anything it doesn't have. Direct messages have little more than
+// when they were created.
+fn format_channel(channel: &slack::Channel) -> String {
+    let (title, kind) = if channel.is_im == Some(true) {
+        ("Direct message".to_string(), "Direct message")
+    } else if channel.is_mpim {
+        ("Group direct message".to_string(), "Group direct message")
+    } else if channel.is_private {
+        (format!("#{}", channel.name), "Private channel")
+    } else {
+        (format!("#{}", channel.name), "Public channel")
+    };
+
+    let mut lines = vec![format!("*{}*", title)];
+    if !channel.topic.value.is_empty() {
+        lines.push(format!("• Topic: {}", channel.topic.value));
+    }
+    if !channel.purpose.value.is_empty() {
+        lines.push(format!("• Purpose: {}", channel.purpose.value));
+    }
+    if channel.created > 0 {
+        let created = util::time_ago(channel.created as u64, false);
+        if channel.creator.is_empty() {
+            lines.push(format!("• Created {}", created));
+        } else {
+            lines.push(format!("• Created {} by <@{}>", created, channel.creator));
+        }
+    }
+
+    let mut flags = vec![kind];
+    if channel.is_general {
+        flags.push("the workspace's general channel");
+    }
+    if channel.is_archived {
+        flags.push("archived");
+    }
+    if channel.is_read_only == Some(true) {
+        flags.push("read only");
+    }
+    if channel.is_ext_shared {
+        flags.push("shared with other organizations");
+    } else if channel.is_shared || channel.is_org_shared {
+        flags.push("shared");
+    }
+    if channel.is_im != Some(true) && !channel.is_mpim {
+        flags.push(if channel.is_member {
+            "I'm a member"
+        } else {
+            "I'm not a member"
+        });
+    }
+    lines.push(format!("• {}", flags.join(", ")));
+
+    if let Some(previous_names) = channel
+        .previous_names
+        .as_ref()
+        .filter(|names| !names.is_empty())
+    {
+        let previous_names: Vec<String> = previous_names
+            .iter()
+            .map(|name| format!("#{}", name))
+            .collect();
+        lines.push(format!("• Previously {}", previous_names.join(", ")));
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_public_channel() {
+        let channel: slack::Channel = serde_json::from_value(serde_json::json!({
+            "id": "C123",
+            "name": "ops",
+            "is_channel": true,
+            "created": 1_600_000_000,
+            "creator": "U123",
+            "is_archived": false,
+            "is_general": false,
+            "is_ext_shared": true,
+            "is_member": true,
+            "is_private": false,
+            "is_mpim": false,
+            "topic": { "value": "Keeping the lights on", "creator": "U123", "last_set": 1 },
+            "purpose": { "value": "", "creator": "", "last_set": 0 },
+            "previous_names": ["ops-old", "sysadmin"],
+        }))
+        .unwrap();
+        let reply = format_channel(&channel);
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(lines[0], "*#ops*");
+        assert_eq!(lines[1], "• Topic: Keeping the lights on");
+        assert!(lines[2].starts_with("• Created "), "{}", lines[2]);
+        assert!(lines[2].ends_with(" by <@U123>"), "{}", lines[2]);
+        assert_eq!(
+            lines[3],
+            "• Public channel, shared with other organizations, I'm a member"
+        );
+        assert_eq!(lines[4], "• Previously #ops-old, #sysadmin");
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    fn formats_direct_message() {
+        // Direct messages are missing most fields.
+        let channel: slack::Channel = serde_json::from_value(serde_json::json!({
+            "id": "D123",
+            "created": 1_600_000_000,
+            "is_im": true,
+            "user": "U123",
+        }))
+        .unwrap();
+        let reply = format_channel(&channel);
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(lines[0], "*Direct message*");
+        assert!(lines[1].starts_with("• Created "), "{}", lines[1]);
+        assert_eq!(lines[2], "• Direct message");
+        assert_eq!(lines.len(), 3);
+    }
+}
@@ -1,14 +1,66 @@
 use chatgpt::prelude::*;
-use regex::Regex;
-use rusqlite::params;
+use futures_lite::AsyncReadExt;
+use isahc::config::ResolveMap;
+use regex::{Regex, RegexSet};
+use rusqlite::{params, OptionalExtension};
 use serde::{Deserialize, Serialize};
 
-use std::env;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::{env, fs};
+use tokio::sync::OnceCell;
 
-use crate::db::DB;
+use crate::db::{self, DbError};
+use crate::error::Error;
 use crate::slack;
+use crate::stats;
+use crate::util;
 
 const REGEX_CHATGPT: &str = r#"(?i)^chatgpt (.*)$"#;
+const REGEX_CHATGPT_RESET: &str = r#"(?i)^(chatgpt )?reset$"#;
+const REGEX_DETECT: &str = r#"(?is)^detect (.+)$"#;
+const REGEX_TLDR: &str = r#"(?i)^tldr (\S+)$"#;
+
+// Parts of a page that aren't readable text.
+const REGEX_HTML_NOISE: &str =
+    r#"(?is)<script\b.*?</script>|<style\b.*?</style>|<noscript\b.*?</noscript>|<!--.*?-->"#;
+const REGEX_HTML_TAG: &str = r#"(?s)<[^>]*>"#;
+const REGEX_HTML_TITLE: &str = r#"(?is)<title[^>]*>(.*?)</title>"#;
+
+// Sets the bot's tone and guardrails for new conversations, if it exists.
+const SYSTEM_PROMPT_FILE: &str = "./prompts/system.md";
+
+// Instructions for detecting the language of text with `detect <text>`.
+const DETECT_PROMPT_FILE: &str = "./prompts/detect.md";
+
+// Used if DETECT_PROMPT_FILE is missing.
+const DEFAULT_DETECT_PROMPT: &str = "Reply with only the name of the language the text sent to you is written in, and how confident you are.";
+
+// Instructions for summarizing a page with `tldr <url>`.
+const TLDR_PROMPT_FILE: &str = "./prompts/tldr.md";
+
+// Used if TLDR_PROMPT_FILE is missing.
+const DEFAULT_TLDR_PROMPT: &str =
+    "Summarize the web page sent to you in a few sentences, without any introduction.";
+
+// Only read this much of a page for `tldr <url>`, ignoring the rest.
+const MAX_PAGE_BYTES: u64 = 1_000_000;
+
+// The most redirects to follow when fetching a page for `tldr <url>`.
+const MAX_REDIRECTS: usize = 5;
+
+// By default, how many messages of a conversation to keep, see `CHATGPT_MAX_HISTORY`.
+const DEFAULT_MAX_HISTORY: usize = 20;
+
+// By default, how many requests each user can make per minute, see `AI_RATE_LIMIT_PER_MINUTE`.
+const DEFAULT_RATE_LIMIT_PER_MINUTE: usize = 10;
+
+// By default, the longest prompt in characters that can be sent, see `AI_MAX_PROMPT_LENGTH`.
+const DEFAULT_MAX_PROMPT_LENGTH: usize = 8000;
+
+// The most tokens a reply can be limited to with `--tokens`.
+const MAX_TOKENS_CAP: u32 = 4000;
 
 // All messages in a given ChatGPT conversation.
 #[derive(Debug)]
@@ -22,29 +74,122 @@ pub(crate) struct ChatGPTId {
     id: u32,
 }
 
+// The system prompt, loaded once.
+lazy_static! {
+    static ref SYSTEM_PROMPT: Option<String> = load_prompt(SYSTEM_PROMPT_FILE);
+    // The language detection prompt, loaded once.
+    static ref DETECT_PROMPT: String =
+        load_prompt(DETECT_PROMPT_FILE).unwrap_or_else(|| DEFAULT_DETECT_PROMPT.to_string());
+    // The page summary prompt, loaded once.
+    static ref TLDR_PROMPT: String =
+        load_prompt(TLDR_PROMPT_FILE).unwrap_or_else(|| DEFAULT_TLDR_PROMPT.to_string());
+    // The ChatGPT client, created on first use and shared by all requests.
+    static ref CLIENT: OnceCell<ChatGPT> = OnceCell::new();
+    // When each user made their requests in the past minute, keyed by user id.
+    static ref RECENT_REQUESTS: Mutex<HashMap<String, VecDeque<u64>>> = Mutex::new(HashMap::new());
+}
+
+// Per-request settings, given as flags before the prompt, ie `chatgpt --temp 0.2 --tokens 500 ...`.
+#[derive(Debug, Default, PartialEq)]
+struct RequestOptions {
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct ConversationHistory {
     pub history: Vec<ChatMessage>,
 }
 
+// Determine if the text is a ChatGPT command, ie `chatgpt <prompt>`, `detect <text>` or
+// `tldr <url>`.
+pub(crate) fn is_command(trimmed_text: &str) -> bool {
+    let set =
+        RegexSet::new([REGEX_CHATGPT, REGEX_DETECT, REGEX_TLDR]).expect("failed to build RegexSet");
+    set.is_match(trimmed_text)
+}
+
 // Check if user is talking to chatgpt.
 pub(crate) async fn process_message(message: &slack::Message) -> Option<(String, String)> {
     let trimmed_text = message.text.trim();
 
+    // Check if someone is asking to `reset` the conversation in this thread.
+    let re = Regex::new(REGEX_CHATGPT_RESET).expect("failed to compile REGEX_CHATGPT_RESET");
+    if let Some(cap) = re.captures(trimmed_text) {
+        // A plain `reset` is only for us if there's a conversation to reset.
+        let explicit = cap.get(1).is_some();
+        let thread_ts = match message.thread_ts.as_ref() {
+            Some(thread_ts) => thread_ts,
+            None if explicit => {
+                let reply_message =
+                    "Conversations are per thread, say `chatgpt reset` in the thread to reset it."
+                        .to_string();
+                return slack::reply_ephemeral(message, reply_message).await;
+            }
+            None => return None,
+        };
+        let reply_message = match delete_chatgpt_context(thread_ts) {
+            Ok(true) => "Done, I've forgotten our conversation in this thread.".to_string(),
+            Ok(false) if explicit => "There's no conversation to reset in this thread.".to_string(),
+            Ok(false) => return None,
+            Err(e) => {
+                log::error!("failed to delete thread from database: {}", e);
+                let reply_message = "Sorry, I failed to reset this conversation.".to_string();
+                return slack::reply_ephemeral(message, reply_message).await;
+            }
+        };
+        return Some((thread_ts.clone(), reply_message));
+    }
+
+    // Check if someone is asking to `detect <text>`.
+    let re = Regex::new(REGEX_DETECT).expect("failed to compile REGEX_DETECT");
+    if let Some(cap) = re.captures(trimmed_text) {
+        let text = cap[1].trim();
+        if let Some(reply) = reply_prompt_too_long(message, text).await {
+            return reply;
+        }
+        if let Some(reply) = reply_rate_limited(message).await {
+            return reply;
+        }
+        return detect_language(message, text).await;
+    }
+
+    // Check if someone is asking to `tldr <url>`.
+    let re = Regex::new(REGEX_TLDR).expect("failed to compile REGEX_TLDR");
+    if let Some(cap) = re.captures(trimmed_text) {
+        if let Some(reply) = reply_rate_limited(message).await {
+            return reply;
+        }
+        return summarize_page(message, &cap[1]).await;
+    }
+
     // Check if someone is saying `chatgpt <foo>`.
     let re = Regex::new(REGEX_CHATGPT).expect("failed to compile REGEX_CHATGPT");
     let chatgpt_request = if re.is_match(trimmed_text) {
         let cap = re
             .captures(trimmed_text)
             .expect("failed to capture REGEX_CHATGPT");
-        cap.get(1).map_or("", |m| m.as_str())
+        cap.get(1).map_or("", |m| m.as_str().trim())
     } else {
         return None;
     };
 
-    // Get required chatgpt api_key from environment variable.
-    let api_key =
-        env::var("CHATGPT_API_KEY").unwrap_or_else(|_| panic!("CHATGPT_API_KEY is not set."));
+    // Strip any flags from the prompt that's sent.
+    let (options, chatgpt_request) = match parse_options(chatgpt_request) {
+        Ok((_, "")) => {
+            let reply_message = "Please include something to ask after the flags.".to_string();
+            return slack::reply_ephemeral(message, reply_message).await;
+        }
+        Ok(parsed) => parsed,
+        Err(reply_message) => return slack::reply_ephemeral(message, reply_message).await,
+    };
+
+    if let Some(reply) = reply_prompt_too_long(message, chatgpt_request).await {
+        return reply;
+    }
+    if let Some(reply) = reply_rate_limited(message).await {
+        return reply;
+    }
 
     // Always reply in a thread: determine if reply is in a new thread or an existing thread.
     let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
@@ -60,38 +205,44 @@ pub(crate) async fn process_message(message: &slack::Message) -> Option<(String,
         None
     };
 
-    // Create a new ChatGPT client.
-    let client = match ChatGPT::new_with_config(
-        api_key,
-        ModelConfigurationBuilder::default()
-            .engine(ChatGPTEngine::Gpt4)
-            .build()
-            .unwrap(),
-    ) {
-        Ok(key) => key,
+    // Reuse the shared ChatGPT client, with any settings for this request.
+    let mut client = match client().await {
+        Ok(client) => client.clone(),
         Err(e) => {
-            println!("failed to create ChatGPT client: {}", e);
+            log::error!("failed to create ChatGPT client: {}", e);
             return None;
         }
     };
+    if let Some(temperature) = options.temperature {
+        client.config.temperature = temperature;
+    }
+    if let Some(max_tokens) = options.max_tokens {
+        client.config.max_tokens = Some(max_tokens);
+    }
 
     // Use conversation if existing, or start a new conversation.
     let mut conversation = if let Some(context) = chatgpt_context {
         let conversation_history: ConversationHistory = match serde_json::from_str(&context) {
             Ok(c) => c,
             Err(e) => {
-                println!("failed to deserialize converation history: {}", e);
+                log::error!("failed to deserialize converation history: {}", e);
                 return None;
             }
         };
         Conversation::new_with_history(client, conversation_history.history)
+    } else if let Some(system_prompt) = system_prompt() {
+        client.new_conversation_directed(system_prompt)
     } else {
         client.new_conversation()
     };
 
     // Sending a message and getting the response.
+    stats::record(stats::Stat::ChatGpt);
     let response = match conversation.send_message(chatgpt_request).await {
-        Ok(r) => r.message().content.to_string(),
+        Ok(r) => {
+            record_usage(&r);
+            r.message().content.to_string()
+        }
         Err(e) => {
             format!(
                 "Sorry, something went wrong (complain to @jeremy please): {}",
@@ -107,75 +258,733 @@ pub(crate) async fn process_message(message: &slack::Message) -> Option<(String,
     };
     store_chatgpt_context(&reply_thread_ts, conversation_history).await;
 
-    println!("ChatGPT response: {}", response);
+    log::info!("ChatGPT response: {}", response);
 
     Some((reply_thread_ts, response))
 }
 
+// Parse flags at the start of a request, returning them and the rest of the request. Supports
+// `--temp <0-2>` and `--tokens <1-MAX_TOKENS_CAP>`. Parsing stops at the first word that isn't a
+// flag, so a prompt can still start with `--`. Invalid values return a reply for the user.
+fn parse_options(request: &str) -> std::result::Result<(RequestOptions, &str), String> {
+    let mut options = RequestOptions::default();
+    let mut rest = request.trim_start();
+    loop {
+        let flag = rest.split_whitespace().next().unwrap_or_default();
+        if flag != "--temp" && flag != "--tokens" {
+            return Ok((options, rest));
+        }
+        let after_flag = rest[flag.len()..].trim_start();
+        let value = after_flag.split_whitespace().next().unwrap_or_default();
+        if flag == "--temp" {
+            match value.parse::<f32>() {
+                Ok(temperature) if (0.0..=2.0).contains(&temperature) => {
+                    options.temperature = Some(temperature)
+                }
+                _ => return Err("`--temp` must be a number from 0 to 2.".to_string()),
+            }
+        } else {
+            match value.parse::<u32>() {
+                Ok(max_tokens) if (1..=MAX_TOKENS_CAP).contains(&max_tokens) => {
+                    options.max_tokens = Some(max_tokens)
+                }
+                _ => {
+                    return Err(format!(
+                        "`--tokens` must be a whole number from 1 to {}.",
+                        MAX_TOKENS_CAP
+                    ))
+                }
+            }
+        }
+        rest = after_flag[value.len()..].trim_start();
+    }
+}
+
+// If the prompt is too long to send, build the reply telling the user so.
+async fn reply_prompt_too_long(
+    message: &slack::Message,
+    prompt: &str,
+) -> Option<Option<(String, String)>> {
+    let max_length = max_prompt_length();
+    let length = prompt_too_long(prompt, max_length)?;
+    let reply_message = format!(
+        "Sorry, that's too long for me: your message is {} characters, the limit is {}.",
+        length, max_length
+    );
+    Some(slack::reply_ephemeral(message, reply_message).await)
+}
+
+// The longest prompt in characters that can be sent.
+fn max_prompt_length() -> usize {
+    env::var("AI_MAX_PROMPT_LENGTH")
+        .ok()
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_PROMPT_LENGTH)
+}
+
+// Returns the length of `prompt` in characters if it's longer than `max_length`.
+fn prompt_too_long(prompt: &str, max_length: usize) -> Option<usize> {
+    let length = prompt.chars().count();
+    (length > max_length).then_some(length)
+}
+
+// If the user is sending requests too fast, build the reply telling them so. Admins aren't rate
+// limited.
+async fn reply_rate_limited(message: &slack::Message) -> Option<Option<(String, String)>> {
+    let user_id = message.user_id.as_ref()?;
+    if slack::message_is_authorized(message, slack::AuthLevel::Admin).await {
+        return None;
+    }
+    let limit = env::var("AI_RATE_LIMIT_PER_MINUTE")
+        .ok()
+        .and_then(|limit| limit.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_RATE_LIMIT_PER_MINUTE);
+
+    let wait = {
+        let mut recent_requests = RECENT_REQUESTS.lock().expect("rate limit lock poisoned");
+        let requests = recent_requests.entry(user_id.clone()).or_default();
+        rate_limit(requests, util::timestamp_now(), limit).err()?
+    };
+    let reply_message = format!(
+        "You're sending AI requests too fast, try again in {}s.",
+        wait
+    );
+    Some(slack::reply_ephemeral(message, reply_message).await)
+}
+
+// Allow at most `limit` requests in any minute, or any number if `limit` is 0. Records the
+// request at `now` and returns `Ok` if allowed, otherwise returns how many seconds until the next
+// request is allowed.
+fn rate_limit(
+    requests: &mut VecDeque<u64>,
+    now: u64,
+    limit: usize,
+) -> std::result::Result<(), u64> {
+    if limit == 0 {
+        return Ok(());
+    }
+    // Forget requests more than a minute old.
+    while requests.front().is_some_and(|ts| now >= ts + 60) {
+        requests.pop_front();
+    }
+    if requests.len() >= limit {
+        let oldest = requests.front().copied().unwrap_or(now);
+        return Err((oldest + 60).saturating_sub(now).max(1));
+    }
+    requests.push_back(now);
+    Ok(())
+}
+
+// Detect the language of `text` with a single request, without any conversation history.
+async fn detect_language(message: &slack::Message, text: &str) -> Option<(String, String)> {
+    let client = match client().await {
+        Ok(client) => client.clone(),
+        Err(e) => {
+            log::error!("failed to create ChatGPT client: {}", e);
+            return None;
+        }
+    };
+
+    stats::record(stats::Stat::ChatGpt);
+    let mut conversation = client.new_conversation_directed(DETECT_PROMPT.as_str());
+    let reply_message = match conversation.send_message(text).await {
+        Ok(r) => {
+            record_usage(&r);
+            format!("Detected language: {}", r.message().content.trim())
+        }
+        Err(e) => {
+            log::error!("failed to detect language: {}", e);
+            let reply_message = "Sorry, I failed to detect the language.".to_string();
+            return slack::reply_ephemeral(message, reply_message).await;
+        }
+    };
+
+    // Always reply in a thread: determine if reply is in a new thread or an existing thread.
+    let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
+        thread_ts.to_string()
+    } else {
+        message.ts.to_string()
+    };
+    Some((reply_thread_ts, reply_message))
+}
+
+// Fetch the page at `url` and summarize it with a single request.
+async fn summarize_page(message: &slack::Message, url: &str) -> Option<(String, String)> {
+    let url = match parse_page_url(url) {
+        Some(url) => url,
+        None => {
+            let reply_message = "Sorry, that doesn't look like a web address.".to_string();
+            return slack::reply_ephemeral(message, reply_message).await;
+        }
+    };
+    let html = match fetch_page(&url).await {
+        Ok(html) => html,
+        Err(reply_message) => return slack::reply_ephemeral(message, reply_message).await,
+    };
+    let title = page_title(&html);
+    let text: String = html_to_text(&html)
+        .chars()
+        .take(max_prompt_length())
+        .collect();
+    if text.is_empty() {
+        let reply_message = format!("Sorry, I couldn't find any text to summarize at {}.", url);
+        return slack::reply_ephemeral(message, reply_message).await;
+    }
+
+    let client = match client().await {
+        Ok(client) => client.clone(),
+        Err(e) => {
+            log::error!("failed to create ChatGPT client: {}", e);
+            return None;
+        }
+    };
+
+    stats::record(stats::Stat::ChatGpt);
+    let mut conversation = client.new_conversation_directed(TLDR_PROMPT.as_str());
+    let summary = match conversation.send_message(text).await {
+        Ok(r) => {
+            record_usage(&r);
+            r.message().content.trim().to_string()
+        }
+        Err(e) => {
+            log::error!("failed to summarize {}: {}", url, e);
+            let reply_message = "Sorry, I failed to summarize that page.".to_string();
+            return slack::reply_ephemeral(message, reply_message).await;
+        }
+    };
+    let reply_message = match title {
+        Some(title) => format!("*{}*\n{}", title, summary),
+        None => summary,
+    };
+
+    // Always reply in a thread: determine if reply is in a new thread or an existing thread.
+    let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
+        thread_ts.to_string()
+    } else {
+        message.ts.to_string()
+    };
+    Some((reply_thread_ts, reply_message))
+}
+
+// Parse a web address, assuming https if no scheme is given.
+fn parse_page_url(text: &str) -> Option<Url> {
+    let url = if text.contains("://") {
+        Url::parse(text).ok()?
+    } else {
+        Url::parse(&format!("https://{}", text)).ok()?
+    };
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return None;
+    }
+    Some(url)
+}
+
+// Resolve the host in `url`, returning an address to fetch it from if all of its addresses are on
+// the public internet, so the bot can't be used to fetch internal pages.
+async fn public_address(url: &Url) -> Option<IpAddr> {
+    let host = url
+        .host_str()?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(443);
+    match tokio::net::lookup_host((host, port)).await {
+        Ok(addresses) => {
+            let addresses: Vec<IpAddr> = addresses.map(|a| a.ip()).collect();
+            if addresses.iter().all(|ip| is_public_ip(*ip)) {
+                addresses.first().copied()
+            } else {
+                None
+            }
+        }
+        Err(e) => {
+            log::warn!("failed to resolve {}: {}", host, e);
+            None
+        }
+    }
+}
+
+// Determine if an address is on the public internet.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local, fc00::/7.
+                || (first & 0xfe00) == 0xfc00
+                // Link local, fe80::/10.
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+// Fetch up to MAX_PAGE_BYTES of an HTML page, following up to MAX_REDIRECTS redirects. Each host,
+// including any redirected to, is checked before it's fetched, and is fetched from the address
+// that was checked, so it can't resolve to a public address for the check and to an internal one
+// for the fetch.
+async fn fetch_page(url: &Url) -> std::result::Result<String, String> {
+    let mut url = url.clone();
+    let mut redirects = 0;
+    let mut response = loop {
+        let address = match public_address(&url).await {
+            Some(address) => address,
+            None => return Err(format!("Sorry, I can't fetch {}.", url)),
+        };
+        let response = match fetch_from(&url, address).await {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!("failed to fetch {}: {}", url, e);
+                return Err(format!("Sorry, I failed to fetch {}.", url));
+            }
+        };
+        let location = match response.header("Location") {
+            Some(location) if response.status().is_redirection() => location.as_str(),
+            _ => break response,
+        };
+        if redirects == MAX_REDIRECTS {
+            return Err(format!("Sorry, {} redirected too many times.", url));
+        }
+        redirects += 1;
+        url = match redirect_url(&url, location) {
+            Some(redirect) => redirect,
+            None => return Err(format!("Sorry, I can't fetch {}.", location)),
+        };
+    };
+    if !response.status().is_success() {
+        return Err(format!(
+            "Sorry, fetching {} failed with status {}.",
+            url,
+            response.status()
+        ));
+    }
+    let is_html = response
+        .content_type()
+        .is_some_and(|mime| matches!(mime.essence(), "text/html" | "application/xhtml+xml"));
+    if !is_html {
+        return Err(format!("Sorry, {} isn't a web page I can summarize.", url));
+    }
+
+    let mut body = Vec::new();
+    if let Err(e) = response
+        .take_body()
+        .take(MAX_PAGE_BYTES)
+        .read_to_end(&mut body)
+        .await
+    {
+        log::warn!("failed to read {}: {}", url, e);
+        return Err(format!("Sorry, I failed to read {}.", url));
+    }
+    Ok(String::from_utf8_lossy(&body).to_string())
+}
+
+// Get `url` from `address`, without resolving its host again. Redirects aren't followed.
+async fn fetch_from(url: &Url, address: IpAddr) -> std::result::Result<surf::Response, String> {
+    let mut builder = isahc::HttpClient::builder();
+    if let Some(domain) = url.domain() {
+        let port = url.port_or_known_default().unwrap_or(443);
+        builder = builder.dns_resolve(ResolveMap::new().add(domain, port, address));
+    }
+    let client = builder.build().map_err(|e| e.to_string())?;
+    let client =
+        surf::Client::with_http_client(http_client::isahc::IsahcClient::from_client(client));
+    client.get(url.as_str()).await.map_err(|e| e.to_string())
+}
+
+// Where a redirect from `url` to `location` leads, if it's to another web page.
+fn redirect_url(url: &Url, location: &str) -> Option<Url> {
+    let redirect = url.join(location).ok()?;
+    if matches!(redirect.scheme(), "http" | "https") && redirect.host_str().is_some() {
+        Some(redirect)
+    } else {
+        None
+    }
+}
+
+// Extract the title of an HTML page.
+fn page_title(html: &str) -> Option<String> {
+    let re = Regex::new(REGEX_HTML_TITLE).expect("failed to compile REGEX_HTML_TITLE");
+    let title = collapse_whitespace(&decode_entities(&re.captures(html)?[1]));
+    (!title.is_empty()).then_some(title)
+}
+
+// Strip the markup from an HTML page, leaving the readable text.
+fn html_to_text(html: &str) -> String {
+    let re_noise = Regex::new(REGEX_HTML_NOISE).expect("failed to compile REGEX_HTML_NOISE");
+    let re_tag = Regex::new(REGEX_HTML_TAG).expect("failed to compile REGEX_HTML_TAG");
+    let text = re_noise.replace_all(html, " ");
+    let text = re_tag.replace_all(&text, " ");
+    collapse_whitespace(&decode_entities(&text))
+}
+
+// Decode the most common HTML entities. `&amp;` is decoded last so `&amp;lt;` becomes `&lt;`.
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Log and count the tokens used by a request, for tracking costs.
+fn record_usage(response: &CompletionResponse) {
+    log::info!(
+        "ChatGPT usage: {} prompt tokens, {} completion tokens, {} total",
+        response.usage.prompt_tokens,
+        response.usage.completion_tokens,
+        response.usage.total_tokens
+    );
+    stats::record_tokens(
+        response.usage.prompt_tokens,
+        response.usage.completion_tokens,
+    );
+}
+
 pub(crate) async fn load_chatgpt_context(thread: &str) -> Option<String> {
-    let db = DB.lock().unwrap_or_else(|_| panic!("DB mutex poisoned!"));
-    let mut statement = db
-        .prepare("SELECT context FROM chatgpt_context WHERE thread = :thread")
-        .expect("failed to prepare SELECT");
-
-    let mut context_iter = statement
-        .query_map(&[(":thread", thread)], |row| {
-            Ok(ChatGPTContext {
-                context: row.get(0).expect("failed to get context"),
-            })
-        })
-        .expect("failed to select from seen table");
+    let db = match db::get_conn() {
+        Ok(db) => db,
+        Err(e) => {
+            log::error!("failed to load thread from database: {}", e);
+            return None;
+        }
+    };
+    let context = db
+        .query_row(
+            "SELECT context FROM chatgpt_context WHERE thread = ?1",
+            params![thread],
+            |row| {
+                Ok(ChatGPTContext {
+                    context: row.get(0)?,
+                })
+            },
+        )
+        .optional();
 
     // Return context if exists.
-    if let Some(context) = context_iter.next() {
-        match context {
-            Ok(c) => return Some(c.context),
-            Err(e) => {
-                println!("failed to load thread from database: {}", e);
-                return None;
+    match context {
+        Ok(c) => c.map(|c| c.context),
+        Err(e) => {
+            log::error!("failed to load thread from database: {}", e);
+            None
+        }
+    }
+}
+
+// Get the shared ChatGPT client, creating it on first use. The API key is only read once, so
+// changing it requires a restart.
+async fn client() -> std::result::Result<&'static ChatGPT, Error> {
+    CLIENT
+        .get_or_try_init(|| async {
+            // Get required chatgpt api_key from environment variable.
+            let api_key = env::var("CHATGPT_API_KEY")
+                .map_err(|_| Error::Config("CHATGPT_API_KEY is not set".to_string()))?;
+            let mut config = ModelConfigurationBuilder::default();
+            config.engine(ChatGPTEngine::Gpt4);
+            // Optionally send requests through a proxy or gateway instead of to OpenAI.
+            if let Ok(base_url) = env::var("OPENAI_BASE_URL") {
+                let api_url = format!("{}/chat/completions", base_url.trim_end_matches('/'));
+                let api_url = Url::parse(&api_url).map_err(|e| {
+                    Error::Config(format!("invalid OPENAI_BASE_URL {}: {}", base_url, e))
+                })?;
+                config.api_url(api_url);
             }
+            ChatGPT::new_with_config(api_key, config.build().unwrap())
+                .map_err(|e| Error::Provider(e.to_string()))
+        })
+        .await
+}
+
+// The system prompt sent at the start of each new conversation, if any.
+pub(crate) fn system_prompt() -> Option<&'static str> {
+    SYSTEM_PROMPT.as_deref()
+}
+
+// Load a prompt from a file, if it exists and isn't empty.
+fn load_prompt(path: &str) -> Option<String> {
+    match fs::read_to_string(path) {
+        Ok(prompt) if !prompt.trim().is_empty() => Some(prompt.trim().to_string()),
+        Ok(_) => None,
+        Err(e) => {
+            log::warn!("no prompt loaded from {}: {}", path, e);
+            None
         }
     }
-    None
 }
 
 pub(crate) async fn store_chatgpt_context(thread: &str, context: ConversationHistory) {
+    // Only keep recent history, so long threads don't send ever larger requests.
+    let max_history = env::var("CHATGPT_MAX_HISTORY")
+        .ok()
+        .and_then(|max_history| max_history.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_HISTORY);
+    let context = ConversationHistory {
+        history: trim_history(context.history, max_history),
+    };
+
     // Convert context to String.
     let context_string = serde_json::to_string(&context).unwrap_or("".to_string());
 
     if !context_string.is_empty() {
-        let db = DB.lock().unwrap_or_else(|_| panic!("DB mutex poisoned!"));
-
-        let mut statement = db
-            .prepare("SELECT id FROM chatgpt_context WHERE thread = :thread")
-            .expect("failed to prepare SELECT");
-        let mut chatgpt_id_iter = statement
-            .query_map(&[(":thread", thread)], |row| {
-                Ok(ChatGPTId {
-                    id: row.get(0).expect("failed to get context"),
-                })
-            })
-            .expect("failed to select from seen table");
-
-        if let Some(id) = chatgpt_id_iter.next() {
-            let id = match id {
-                Ok(i) => i.id,
-                Err(e) => {
-                    println!("failed to load thread id from database: {}", e);
-                    return;
-                }
-            };
-            db.execute(
-                r#"UPDATE chatgpt_context SET thread = ?1, context = ?2 WHERE id = ?3"#,
-                params![thread, context_string, id],
-            )
-            .expect("failed to insert into chatgpt_context");
-        } else {
-            db.execute(
-                r#"INSERT INTO chatgpt_context (thread, context) VALUES(?1, ?2)"#,
-                params![thread, context_string,],
-            )
-            .expect("failed to insert into chatgpt_context");
-        };
+        if let Err(e) = upsert_chatgpt_context(thread, &context_string) {
+            log::error!("failed to store thread in database: {}", e);
+        }
+    }
+}
+
+// Keep only the last `max_messages` messages of a conversation, and any system messages.
+fn trim_history(history: Vec<ChatMessage>, max_messages: usize) -> Vec<ChatMessage> {
+    let conversation_messages = history
+        .iter()
+        .filter(|message| message.role != Role::System)
+        .count();
+    let mut skip = conversation_messages.saturating_sub(max_messages);
+    history
+        .into_iter()
+        .filter(|message| {
+            if message.role == Role::System || skip == 0 {
+                true
+            } else {
+                skip -= 1;
+                false
+            }
+        })
+        .collect()
+}
+
+// Create or replace the stored context for a thread.
+fn upsert_chatgpt_context(thread: &str, context_string: &str) -> std::result::Result<(), DbError> {
+    let db = db::get_conn()?;
+    let id: Option<u32> = db
+        .query_row(
+            "SELECT id FROM chatgpt_context WHERE thread = ?1",
+            params![thread],
+            |row| Ok(ChatGPTId { id: row.get(0)? }),
+        )
+        .optional()?
+        .map(|i| i.id);
+
+    if let Some(id) = id {
+        db.execute(
+            r#"UPDATE chatgpt_context SET thread = ?1, context = ?2 WHERE id = ?3"#,
+            params![thread, context_string, id],
+        )?;
+    } else {
+        db.execute(
+            r#"INSERT INTO chatgpt_context (thread, context) VALUES(?1, ?2)"#,
+            params![thread, context_string,],
+        )?;
+    };
+    Ok(())
+}
+
+// Delete the stored context for a thread. Returns `true` if there was context to delete.
+fn delete_chatgpt_context(thread: &str) -> std::result::Result<bool, DbError> {
+    let db = db::get_conn()?;
+    let deleted = db.execute(
+        "DELETE FROM chatgpt_context WHERE thread = ?1",
+        params![thread],
+    )?;
+    Ok(deleted > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, content: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn trim_history_keeps_recent_and_system_messages() {
+        let history = vec![
+            message(Role::System, "be nice"),
+            message(Role::User, "one"),
+            message(Role::Assistant, "two"),
+            message(Role::User, "three"),
+            message(Role::Assistant, "four"),
+        ];
+        let trimmed: Vec<String> = trim_history(history, 2)
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(trimmed, vec!["be nice", "three", "four"]);
+    }
+
+    #[test]
+    fn parse_page_urls() {
+        assert_eq!(
+            parse_page_url("https://example.com/a?b=c")
+                .unwrap()
+                .as_str(),
+            "https://example.com/a?b=c"
+        );
+        assert_eq!(
+            parse_page_url("example.com").unwrap().as_str(),
+            "https://example.com/"
+        );
+        assert!(parse_page_url("ftp://example.com").is_none());
+        assert!(parse_page_url("file:///etc/passwd").is_none());
+        assert!(parse_page_url("not a url").is_none());
+    }
+
+    #[test]
+    fn private_ips_are_rejected() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn follows_redirects_to_web_pages() {
+        let url = Url::parse("http://example.com/docs").unwrap();
+        assert_eq!(
+            redirect_url(&url, "https://example.com/docs")
+                .unwrap()
+                .as_str(),
+            "https://example.com/docs"
+        );
+        assert_eq!(
+            redirect_url(&url, "/docs/").unwrap().as_str(),
+            "http://example.com/docs/"
+        );
+        assert!(redirect_url(&url, "file:///etc/passwd").is_none());
+        assert!(redirect_url(&url, "ftp://example.com/").is_none());
+    }
+
+    #[test]
+    fn html_to_text_strips_markup() {
+        let html = "<html><head><title> Rust &amp; Slack </title><style>p { color: red; }</style>\
+            <script>alert('hi');</script></head><body><!-- hidden --><h1>Hello</h1>\
+            <p>Fish &lt;3 chips.</p></body></html>";
+        assert_eq!(page_title(html).as_deref(), Some("Rust & Slack"));
+        assert_eq!(html_to_text(html), "Rust & Slack Hello Fish <3 chips.");
+        assert_eq!(page_title("<p>no title</p>"), None);
+    }
+
+    #[test]
+    fn parse_request_options() {
+        assert_eq!(
+            parse_options("--temp 0.2 explain this"),
+            Ok((
+                RequestOptions {
+                    temperature: Some(0.2),
+                    max_tokens: None
+                },
+                "explain this"
+            ))
+        );
+        assert_eq!(
+            parse_options("--tokens 2000 --temp 1 write a poem"),
+            Ok((
+                RequestOptions {
+                    temperature: Some(1.0),
+                    max_tokens: Some(2000)
+                },
+                "write a poem"
+            ))
+        );
+        assert_eq!(
+            parse_options("what does --verbose do?"),
+            Ok((RequestOptions::default(), "what does --verbose do?"))
+        );
+        assert_eq!(
+            parse_options("--temp 0.5"),
+            Ok((
+                RequestOptions {
+                    temperature: Some(0.5),
+                    max_tokens: None
+                },
+                ""
+            ))
+        );
+        assert_eq!(
+            parse_options("--temp  0.2\n  explain this"),
+            Ok((
+                RequestOptions {
+                    temperature: Some(0.2),
+                    max_tokens: None
+                },
+                "explain this"
+            ))
+        );
+        assert!(parse_options("--temp 3 too hot").is_err());
+        assert!(parse_options("--temp warm hello").is_err());
+        assert!(parse_options("--tokens 0 hello").is_err());
+        assert!(parse_options("--tokens 100000 hello").is_err());
+    }
+
+    #[test]
+    fn prompt_length_counts_characters() {
+        assert_eq!(prompt_too_long("hello", 5), None);
+        assert_eq!(prompt_too_long("hello!", 5), Some(6));
+        // Multi-byte characters count once.
+        assert_eq!(prompt_too_long("héllo", 5), None);
+        assert_eq!(prompt_too_long("", 0), None);
+    }
+
+    #[test]
+    fn rate_limit_allows_requests_per_minute() {
+        let mut requests = VecDeque::new();
+        assert_eq!(rate_limit(&mut requests, 100, 2), Ok(()));
+        assert_eq!(rate_limit(&mut requests, 110, 2), Ok(()));
+        assert_eq!(rate_limit(&mut requests, 120, 2), Err(40));
+        // Rejected requests don't count.
+        assert_eq!(requests.len(), 2);
+        assert_eq!(rate_limit(&mut requests, 160, 2), Ok(()));
+        assert_eq!(rate_limit(&mut requests, 165, 2), Err(5));
+        assert_eq!(rate_limit(&mut requests, 170, 2), Ok(()));
+    }
+
+    #[test]
+    fn rate_limit_zero_is_unlimited() {
+        let mut requests = VecDeque::new();
+        for _ in 0..100 {
+            assert_eq!(rate_limit(&mut requests, 100, 0), Ok(()));
+        }
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn trim_history_keeps_short_conversations() {
+        let history = vec![message(Role::User, "one"), message(Role::Assistant, "two")];
+        assert_eq!(trim_history(history, 20).len(), 2);
     }
 }
@@ -1,19 +1,50 @@
 // Handles currency conversions and currency conversion alerts.
-// For example, `convert USD to EUR` or `notify me when 1 BTC is greater than 100000 USD`.
+// For example, `convert USD to EUR`, `convert 100 USD to EUR GBP JPY` or
+// `notify me when 1 BTC is greater than 100000 USD`.
 
 use chrono::prelude::*;
 use regex::{Regex, RegexSet};
-use rusqlite::params;
+use rusqlite::{params, OptionalExtension};
 use std::{collections::HashMap, env, time::Duration};
 
-use crate::db::DB;
+use crate::db::{self, DbError};
 use crate::slack;
+use crate::stats;
 use crate::util;
 
-const REGEX_CONVERT: &str =
-    r"(?i)^convert (from )?([0-9]*(\.[0-9]*)?( )?){1}([a-z]{3,4}) (to )?([a-z]{3,4})$";
+const REGEX_CONVERT: &str = r"(?i)^convert (from )?([0-9]*(\.[0-9]*)?( )?){1}([a-z]{3,4}) (to )?([a-z]{3,4}(?:(?:\s|,)+[a-z]{3,4})*)$";
+const REGEX_CONVERT_LOOSE: &str = r"(?i)^(?:convert\s+)?(?:from\s+)?([$£€¥])?\s*([0-9]+(?:\.[0-9]*)?|\.[0-9]+)\s*([a-z]{3,4})?\s+(?:to|in|into)\s+([$£€¥]|[a-z]{3,4}(?:(?:\s|,)+[a-z]{3,4})*)$";
 const REGEX_ALERT_GREATER: &str = r"(?i)^(?:alert|notify|tell|ping)(?:\s)*(me|all|everyone)?(?:\s)*(?:when|if)?(?:\s)*([0-9]*(?:\.[0-9]*)?){1}(?:\s)*([a-z]{3,4})(?:\s)*(?:is)?(?:\s)*(?:greater|greater than|greater then|gt|>|more|more than|more then)(?:\s)*([0-9]*(?:\.[0-9]*)?){1}(?:\s)*([a-z]{3,4})*$";
 const REGEX_ALERT_LESSER: &str = r"(?i)^(?:alert|notify|tell|ping)(?:\s)*(me|all|everyone)?(?:\s)*(?:when|if)?(?:\s)*([0-9]*(?:\.[0-9]*)?){1}(?:\s)*([a-z]{3,4})(?:\s)*(?:is)?(?:\s)*(?:lesser|less|lesser than|less than|lesser then|less than|lt|<)(?:\s)*([0-9]*(?:\.[0-9]*)?){1}(?:\s)*([a-z]{3,4})*$";
+const REGEX_ALERT_CHANGE: &str = r"(?i)^(?:alert|notify|tell|ping)(?:\s)*(me|all|everyone)?(?:\s)*(?:when|if)(?:\s)*([a-z]{3,4})(?:\s)*(?:moves|changes)(?:\s)*([0-9]+(?:\.[0-9]*)?)(?:\s)*%(?:\s)*(up|down)?(?:\s)*(?:(?:in|against|vs)(?:\s)*([a-z]{3,4}))?$";
+const REGEX_HISTORY: &str = r"(?i)^history ([a-z]{3,4})(?:\s|-|/)+(?:to )?([a-z]{3,4})$";
+
+// Currency symbols that can be used instead of codes, ie `$100 to EUR` or `50 EUR in $`.
+const CURRENCY_SYMBOLS: &[(&str, &str)] = &[("$", "USD"), ("£", "GBP"), ("€", "EUR"), ("¥", "JPY")];
+
+// Currencies that can be converted without `convert`, ie `100 USD in EUR`: ISO 4217 codes, and
+// bitcoin as XE quotes it.
+const KNOWN_CURRENCIES: &[&str] = &[
+    "AED", "AFN", "ALL", "AMD", "ANG", "AOA", "ARS", "AUD", "AWG", "AZN", "BAM", "BBD", "BDT",
+    "BGN", "BHD", "BIF", "BMD", "BND", "BOB", "BRL", "BSD", "BTN", "BWP", "BYN", "BZD", "CAD",
+    "CDF", "CHF", "CLP", "CNY", "COP", "CRC", "CUP", "CVE", "CZK", "DJF", "DKK", "DOP", "DZD",
+    "EGP", "ERN", "ETB", "EUR", "FJD", "FKP", "GBP", "GEL", "GHS", "GIP", "GMD", "GNF", "GTQ",
+    "GYD", "HKD", "HNL", "HTG", "HUF", "IDR", "ILS", "INR", "IQD", "IRR", "ISK", "JMD", "JOD",
+    "JPY", "KES", "KGS", "KHR", "KMF", "KPW", "KRW", "KWD", "KYD", "KZT", "LAK", "LBP", "LKR",
+    "LRD", "LSL", "LYD", "MAD", "MDL", "MGA", "MKD", "MMK", "MNT", "MOP", "MRU", "MUR", "MVR",
+    "MWK", "MXN", "MYR", "MZN", "NAD", "NGN", "NIO", "NOK", "NPR", "NZD", "OMR", "PAB", "PEN",
+    "PGK", "PHP", "PKR", "PLN", "PYG", "QAR", "RON", "RSD", "RUB", "RWF", "SAR", "SBD", "SCR",
+    "SDG", "SEK", "SGD", "SHP", "SLE", "SOS", "SRD", "SSP", "STN", "SVC", "SYP", "SZL", "THB",
+    "TJS", "TMT", "TND", "TOP", "TRY", "TTD", "TWD", "TZS", "UAH", "UGX", "USD", "UYU", "UZS",
+    "VES", "VND", "VUV", "WST", "XAF", "XAG", "XAU", "XCD", "XOF", "XPF", "YER", "ZAR", "ZMW",
+    "ZWL", "BTC",
+];
+
+// Never poll for alerts more often than this, even if configured to.
+const ALERT_INTERVAL_FLOOR: u64 = 60;
+
+// How many recorded quotes to show in response to `history FOO BAR`.
+const HISTORY_POINTS: u32 = 10;
 
 const CURRENCY_API: &str = "https://xecdapi.xe.com/v1/convert_from.json/";
 const CURRENCY_API_RANGE: &str = "https://xecdapi.xe.com/v1/historic_rate/period/";
@@ -31,6 +62,38 @@ struct CurrencyAlert {
     comparison: String,
     to_currency: String,
     to_amount: f32,
+    // Only set for percentage-change alerts.
+    baseline_rate: Option<f32>,
+    percent_change: Option<f32>,
+    // Who to notify, `me` or `everyone`. Alerts set before this was recorded notify the user.
+    notify: Option<String>,
+    // The thread the alert was set in. Alerts set before this was recorded post to the channel.
+    thread_ts: Option<String>,
+}
+
+// A parsed request to set a currency conversion alert.
+#[derive(Debug, PartialEq)]
+struct AlertSpec {
+    // Who to notify, `me` or `everyone`.
+    notify: &'static str,
+    from_amount: f32,
+    from_currency: String,
+    // Either `more` or `less`.
+    comparison: &'static str,
+    to_amount: f32,
+    to_currency: String,
+}
+
+// A parsed request to set a percentage-change alert.
+#[derive(Debug, PartialEq)]
+struct ChangeAlertSpec {
+    // Who to notify, `me` or `everyone`.
+    notify: &'static str,
+    from_currency: String,
+    percent_change: f32,
+    // Either `up`, `down` or `moves`.
+    comparison: &'static str,
+    to_currency: String,
 }
 
 // Check if user is asking for currency conversion.
@@ -43,114 +106,325 @@ pub(crate) async fn process_message(message: &slack::Message) -> Option<(String,
     // If response_string is set, do nothing more.
     let response_string = if response_string.is_some() {
         response_string
+    // Otherwise, test if this is a request for recorded quote history.
+    } else if let Some(history) = currency_history(trimmed_text) {
+        Some(history)
+    // Otherwise, test if this is a request to set a percentage-change alert.
+    } else if let Some(alert) = currency_change_alert(message, trimmed_text).await {
+        Some(alert)
     // Otherwise, test if this is a request to set an alert.
     } else {
         currency_alert(message, trimmed_text).await
     };
 
     // If we have a response thread, return thread and message.
-    if let Some(response_string) = response_string {
-        let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
-            thread_ts.clone()
-        } else {
-            message.ts.clone()
+    match response_string {
+        Some(Ok(response_string)) => {
+            let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
+                thread_ts.clone()
+            } else {
+                message.ts.clone()
+            };
+            Some((reply_thread_ts, response_string))
+        }
+        // Errors are only of interest to the user that made the request.
+        Some(Err(response_string)) => slack::reply_ephemeral(message, response_string).await,
+        None => None,
+    }
+}
+
+// Determine if the text is an explicit conversion, ie `convert 1 USD to EUR`, or a request for
+// history, ie `history BTC USD`.
+pub(crate) fn is_command(trimmed_text: &str) -> bool {
+    let re = Regex::new(REGEX_HISTORY).expect("failed to compile REGEX_HISTORY");
+    (has_convert_keyword(trimmed_text) && parse_convert(trimmed_text).is_some())
+        || re.is_match(trimmed_text)
+}
+
+// Determine if this is a request to convert currency.
+// Returns `Some(Err(message))` if the conversion fails.
+pub(crate) async fn currency_convert(trimmed_text: &str) -> Option<Result<String, String>> {
+    // No conversion command, exit now.
+    let (amount, from_currency, to_currencies) = parse_convert(trimmed_text)?;
+
+    // Requests without `convert`, ie `100 USD in EUR`, may just be conversation.
+    let explicit = has_convert_keyword(trimmed_text);
+
+    // Perform the remote currency quote request, with a single request for all targets.
+    let values = match get_currency_quotes(&from_currency, &to_currencies, amount).await {
+        Ok(values) => values,
+        // Something went wrong with currency conversion, pass along the message.
+        Err(message) if explicit => return Some(Err(message)),
+        Err(message) => {
+            log::info!("ignoring failed conversion {}: {}", trimmed_text, message);
+            return None;
+        }
+    };
+
+    stats::record(stats::Stat::Conversion);
+
+    // Optionally also show the inverse rate, computed from the same quote.
+    let show_inverse = env::var("CONVERT_SHOW_INVERSE").is_ok();
+
+    // Link each converted value to a chart of the past 24 hours.
+    let mut quotes = Vec::new();
+    for (to_currency, value) in to_currencies.iter().zip(values) {
+        let mut quote = match get_currency_range_24h(&from_currency, to_currency, amount).await {
+            Ok(chart) => format!("<{}|{} {}>", chart, format_amount(value), to_currency),
+            Err(e) => {
+                log::warn!(
+                    "failed to chart {} to {}: {}",
+                    from_currency,
+                    to_currency,
+                    e
+                );
+                format!("{} {}", format_amount(value), to_currency)
+            }
         };
-        Some((reply_thread_ts, response_string))
+        if show_inverse && value > 0.0 {
+            quote.push_str(&format!(
+                " (and 1 {} is {} {})",
+                to_currency,
+                format_amount(round_quote(amount / value)),
+                from_currency
+            ));
+        }
+        quotes.push(quote);
+    }
+
+    if quotes.len() == 1 {
+        Some(Ok(format!(
+            "{} {} is currently {}.",
+            format_amount(amount),
+            from_currency,
+            quotes[0],
+        )))
     } else {
-        None
+        Some(Ok(format!(
+            "{} {} is currently:\n{}",
+            format_amount(amount),
+            from_currency,
+            quotes
+                .iter()
+                .map(|quote| format!("• {}", quote))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )))
     }
 }
 
-// Determine if this is a request to convert currency.
-pub(crate) async fn currency_convert(trimmed_text: &str) -> Option<String> {
-    // Check if someone is asking `convert from # FOO to BAR?`.
+// Parse `convert from # FOO to BAR`, returning the amount, the currency to convert from, and
+// the currencies to convert to. Also accepts looser requests with an amount, where `convert` is
+// optional, `in` is the same as `to`, and currencies can be symbols, ie `$100 in EUR`.
+fn parse_convert(trimmed_text: &str) -> Option<(f32, String, Vec<String>)> {
     let re = Regex::new(REGEX_CONVERT).expect("failed to compile REGEX_CONVERT");
-    let (amount, from_currency, to_currency) = if re.is_match(trimmed_text) {
-        let cap = re
-            .captures(trimmed_text)
-            .expect("failed to capture REGEX_CONVERT");
+    let re_loose = Regex::new(REGEX_CONVERT_LOOSE).expect("failed to compile REGEX_CONVERT_LOOSE");
+    let (amount, from_currency, to_currency) = if let Some(cap) = re.captures(trimmed_text) {
         (
             cap.get(2).map_or("", |m| m.as_str()),
             cap.get(5).map_or("", |m| m.as_str()),
             cap.get(7).map_or("", |m| m.as_str()),
         )
     } else {
-        // No conversion command, exit now.
-        return None;
+        let cap = re_loose.captures(trimmed_text)?;
+        // The currency to convert from must be given as a code or a symbol.
+        let from_currency = match (cap.get(3), cap.get(1)) {
+            (Some(code), _) => code.as_str(),
+            (None, Some(symbol)) => currency_symbol(symbol.as_str())?,
+            (None, None) => return None,
+        };
+        let to_currency = cap.get(4).map_or("", |m| m.as_str());
+        (
+            cap.get(2).map_or("", |m| m.as_str()),
+            from_currency,
+            currency_symbol(to_currency).unwrap_or(to_currency),
+        )
     };
 
     // Convert number string to f32, defaulting to 1.0 if empty or invalid.
     let amount = amount.trim().parse::<f32>().unwrap_or(1.0);
 
-    // Always work in upper case.
+    // Always work in upper case. Multiple target currencies can be separated by spaces or commas.
     let from_currency = from_currency.to_uppercase();
-    let to_currency = to_currency.to_uppercase();
+    let to_currencies: Vec<String> = to_currency
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|c| !c.is_empty())
+        .map(|c| c.to_uppercase())
+        .collect();
 
-    // Perform the remote currency quote request.
-    let value = get_currency_quote(&from_currency, &to_currency, amount).await;
+    // Without `convert`, only known currencies are converted, so conversation like `2 cats to
+    // feed` is never sent to XE.
+    if !has_convert_keyword(trimmed_text)
+        && !std::iter::once(&from_currency)
+            .chain(&to_currencies)
+            .all(|currency| KNOWN_CURRENCIES.contains(&currency.as_str()))
+    {
+        return None;
+    }
 
-    if let Ok(value) = value {
-        Some(format!(
-            "{} {} is currently <{}|{} {}>.",
-            amount,
-            from_currency,
-            get_currency_range_24h(&from_currency, &to_currency, amount)
-                .await
-                .unwrap(),
-            value,
-            to_currency,
-        ))
-    } else if let Err(message) = value {
-        // Something went wrong with currency conversion, pass along the message.
-        Some(message)
-    } else {
-        None
+    Some((amount, from_currency, to_currencies))
+}
+
+// Determine if the request starts with `convert`.
+fn has_convert_keyword(trimmed_text: &str) -> bool {
+    trimmed_text
+        .get(..7)
+        .is_some_and(|command| command.eq_ignore_ascii_case("convert"))
+}
+
+// The currency code for a currency symbol, ie `USD` for `$`.
+fn currency_symbol(symbol: &str) -> Option<&'static str> {
+    CURRENCY_SYMBOLS
+        .iter()
+        .find(|(currency_symbol, _)| *currency_symbol == symbol)
+        .map(|(_, code)| *code)
+}
+
+// Determine if this is a request for the recorded history of a currency pair, ie `history BTC USD`.
+// Quotes are only recorded by the alert and watchlist threads, not by interactive conversions.
+pub(crate) fn currency_history(trimmed_text: &str) -> Option<Result<String, String>> {
+    let re = Regex::new(REGEX_HISTORY).expect("failed to compile REGEX_HISTORY");
+    let cap = re.captures(trimmed_text)?;
+    let from_currency = cap[1].to_uppercase();
+    let to_currency = cap[2].to_uppercase();
+
+    let history = match load_quote_history(&from_currency, &to_currency, HISTORY_POINTS) {
+        Ok(history) => history,
+        Err(e) => {
+            log::error!("failed to load quote history: {}", e);
+            return Some(Err(format!(
+                "Sorry, I failed to load the history of {} to {}.",
+                from_currency, to_currency
+            )));
+        }
+    };
+
+    if history.is_empty() {
+        return Some(Ok(format!(
+            "I haven't recorded any quotes for {} to {} yet.",
+            from_currency, to_currency
+        )));
     }
+
+    let lines: Vec<String> = history
+        .iter()
+        .map(|(rate, ts)| {
+            format!(
+                "• 1 *{}* was {} *{}* {}",
+                from_currency,
+                format_amount(*rate),
+                to_currency,
+                util::time_ago(*ts, false)
+            )
+        })
+        .collect();
+    Some(Ok(format!(
+        "Recorded quotes for {} to {}:\n{}",
+        from_currency,
+        to_currency,
+        lines.join("\n")
+    )))
 }
 
-// Determine if this is a request to set a ccurrency conversion alert.
-pub(crate) async fn currency_alert(message: &slack::Message, trimmed_text: &str) -> Option<String> {
-    let set = RegexSet::new(&[REGEX_ALERT_GREATER, REGEX_ALERT_LESSER])
-        .expect("failed to build RegexSet");
-    if set.is_match(trimmed_text) {
-        let matches: Vec<_> = set.matches(trimmed_text).into_iter().collect();
-        let set_match = matches[0];
-        let cap = if set_match == 0 {
-            let re =
-                Regex::new(REGEX_ALERT_GREATER).expect("failed to compile REGEX_ALERT_GREATER");
-            re.captures(trimmed_text)
-                .expect("failed to capture REGEX_ALERT_GREATER")
-        } else {
-            let re = Regex::new(REGEX_ALERT_LESSER).expect("failed to compile REGEX_ALERT_LESSER");
-            re.captures(trimmed_text)
-                .expect("failed to capture REGEX_ALERT_LESSER")
-        };
-        let who = cap.get(1).map_or("", |m| m.as_str());
-        let from_amount = cap.get(2).map_or("", |m| m.as_str());
-        let from_currency = cap.get(3).map_or("", |m| m.as_str());
-        let to_amount = cap.get(4).map_or("", |m| m.as_str());
-        let to_currency = cap.get(5).map_or("", |m| m.as_str());
+// Determine if this is a request to set a percentage-change alert, ie
+// `alert me when BTC moves 5% up`. The change is measured against USD unless another currency
+// is given, ie `alert me when BTC moves 5% in EUR`.
+// Returns `Some(Err(message))` if the alert can't be set.
+pub(crate) async fn currency_change_alert(
+    message: &slack::Message,
+    trimmed_text: &str,
+) -> Option<Result<String, String>> {
+    let alert = parse_change_alert(trimmed_text)?;
+    let ChangeAlertSpec {
+        notify,
+        ref from_currency,
+        percent_change,
+        comparison,
+        ref to_currency,
+    } = alert;
 
-        let from_amount = from_amount.trim().parse::<f32>().unwrap_or(1.0);
-        let to_amount = to_amount.trim().parse::<f32>().unwrap_or(1.0);
+    if percent_change <= 0.0 {
+        return Some(Err(format!(
+            "Sorry, `{}%` isn't a change I can watch for.",
+            percent_change
+        )));
+    }
 
-        let from_currency = from_currency.to_uppercase();
-        let to_currency = to_currency.to_uppercase();
+    // Record the current rate as the baseline to compare against.
+    let baseline_rate = match get_currency_quote(from_currency, to_currency, 1.0).await {
+        Ok(baseline_rate) if baseline_rate > 0.0 => baseline_rate,
+        Ok(_) => {
+            return Some(Err(format!(
+                "Sorry, I can't track changes of {} in {}.",
+                from_currency, to_currency
+            )))
+        }
+        Err(e) => return Some(Err(e)),
+    };
 
-        let who = if who.is_empty() || who == "me" {
-            " you"
-        } else {
-            ""
-        };
+    let user_id = message.user_id.as_deref().unwrap_or("");
+
+    // Don't store the same alert twice.
+    match change_alert_exists(&message.channel_id, user_id, &alert) {
+        Ok(true) => return Some(Ok("You already have that alert.".to_string())),
+        Ok(false) => (),
+        Err(e) => {
+            log::error!("failed to check for existing currency alert: {}", e);
+            return Some(Err("Sorry, I failed to save your alert.".to_string()));
+        }
+    }
 
-        let comparison = if set_match == 0 { "more" } else { "less" };
+    // Add alert to the database.
+    let thread_ts = message.thread_ts.as_deref().unwrap_or(&message.ts);
+    if let Err(e) = insert_change_alert(
+        &message.channel_id,
+        user_id,
+        thread_ts,
+        &alert,
+        baseline_rate,
+    ) {
+        log::error!("failed to store currency alert: {}", e);
+        return Some(Err("Sorry, I failed to save your alert.".to_string()));
+    }
+
+    let direction = match comparison {
+        "up" => " up",
+        "down" => " down",
+        _ => "",
+    };
+    Some(Ok(format!(
+        "I will alert {} when 1 {} moves {}%{} from {} {}.",
+        notify_who(notify),
+        from_currency,
+        percent_change,
+        direction,
+        format_amount(baseline_rate),
+        to_currency
+    )))
+}
+
+// Determine if this is a request to set a ccurrency conversion alert.
+// Returns `Some(Err(message))` if the alert can't be set.
+pub(crate) async fn currency_alert(
+    message: &slack::Message,
+    trimmed_text: &str,
+) -> Option<Result<String, String>> {
+    if let Some(alert) = parse_alert(trimmed_text) {
+        let AlertSpec {
+            notify,
+            from_amount,
+            ref from_currency,
+            comparison,
+            to_amount,
+            ref to_currency,
+        } = alert;
 
         // Before we set an alert, be sure the request isn't already rue.
-        let value = get_currency_quote(&from_currency, &to_currency, from_amount).await;
+        let value = get_currency_quote(from_currency, to_currency, from_amount).await;
 
         // If currency conversion failed, pass the error along and exit.
         if let Err(e) = value {
-            return Some(e);
+            return Some(Err(e));
         }
 
         // Be sure the alert isn't already true.
@@ -159,47 +433,139 @@ pub(crate) async fn currency_alert(message: &slack::Message, trimmed_text: &str)
                 || (comparison == "less" && value < to_amount)
             {
                 {
-                    return Some(format!(
+                    return Some(Ok(format!(
                         "Silly, {} {} is already worth {} than {} {} -- it's currently worth {} {}.",
-                        from_amount, from_currency, comparison, to_amount, to_currency, value, to_currency
-                    ));
+                        format_amount(from_amount),
+                        from_currency,
+                        comparison,
+                        format_amount(to_amount),
+                        to_currency,
+                        format_amount(value),
+                        to_currency
+                    )));
                 }
             }
         }
 
-        let user_id = if let Some(user) = message.user.as_ref() {
-            &user.id
-        } else {
-            ""
-        };
+        let user_id = message.user_id.as_deref().unwrap_or("");
+
+        // Don't store the same alert twice.
+        match alert_exists(&message.channel_id, user_id, &alert) {
+            Ok(true) => return Some(Ok("You already have that alert.".to_string())),
+            Ok(false) => (),
+            Err(e) => {
+                log::error!("failed to check for existing currency alert: {}", e);
+                return Some(Err("Sorry, I failed to save your alert.".to_string()));
+            }
+        }
 
         // Add alert to the database.
-        let db = DB.lock().unwrap_or_else(|_| panic!("DB mutex poisoned!"));
-        db.execute(
-            r#"INSERT INTO currency_alert (channel, user, from_currency, from_amount, comparison, to_currency, to_amount)  VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
-            params![message.channel.id, user_id, from_currency, from_amount, comparison, to_currency, to_amount],
-        )
-        .expect("failed to increment karma");
+        let thread_ts = message.thread_ts.as_deref().unwrap_or(&message.ts);
+        if let Err(e) = insert_alert(&message.channel_id, user_id, thread_ts, &alert) {
+            log::error!("failed to store currency alert: {}", e);
+            return Some(Err("Sorry, I failed to save your alert.".to_string()));
+        }
 
-        Some(format!(
-            "I will alert{} when {} {} is worth {} than {} {}.",
-            who, from_amount, from_currency, comparison, to_amount, to_currency
-        ))
+        Some(Ok(format!(
+            "I will alert {} when {} {} is worth {} than {} {}.",
+            notify_who(notify),
+            format_amount(from_amount),
+            from_currency,
+            comparison,
+            format_amount(to_amount),
+            to_currency
+        )))
     } else {
         None
     }
 }
 
+// Parse `alert me when # FOO is [greater|less] than # BAR`.
+fn parse_alert(trimmed_text: &str) -> Option<AlertSpec> {
+    let set =
+        RegexSet::new([REGEX_ALERT_GREATER, REGEX_ALERT_LESSER]).expect("failed to build RegexSet");
+    let set_match = set.matches(trimmed_text).into_iter().next()?;
+    let cap = if set_match == 0 {
+        let re = Regex::new(REGEX_ALERT_GREATER).expect("failed to compile REGEX_ALERT_GREATER");
+        re.captures(trimmed_text)?
+    } else {
+        let re = Regex::new(REGEX_ALERT_LESSER).expect("failed to compile REGEX_ALERT_LESSER");
+        re.captures(trimmed_text)?
+    };
+    let who = cap.get(1).map_or("", |m| m.as_str());
+    let from_amount = cap.get(2).map_or("", |m| m.as_str());
+    let from_currency = cap.get(3).map_or("", |m| m.as_str());
+    let to_amount = cap.get(4).map_or("", |m| m.as_str());
+    let to_currency = cap.get(5).map_or("", |m| m.as_str());
+
+    Some(AlertSpec {
+        notify: notify_target(who),
+        from_amount: from_amount.trim().parse::<f32>().unwrap_or(1.0),
+        from_currency: from_currency.to_uppercase(),
+        comparison: if set_match == 0 { "more" } else { "less" },
+        to_amount: to_amount.trim().parse::<f32>().unwrap_or(1.0),
+        to_currency: to_currency.to_uppercase(),
+    })
+}
+
+// Parse `alert me when FOO moves #% [up|down] [in BAR]`.
+fn parse_change_alert(trimmed_text: &str) -> Option<ChangeAlertSpec> {
+    let re = Regex::new(REGEX_ALERT_CHANGE).expect("failed to compile REGEX_ALERT_CHANGE");
+    let cap = re.captures(trimmed_text)?;
+    let who = cap.get(1).map_or("", |m| m.as_str());
+    let direction = cap.get(4).map(|m| m.as_str().to_lowercase());
+
+    Some(ChangeAlertSpec {
+        notify: notify_target(who),
+        from_currency: cap[2].to_uppercase(),
+        percent_change: cap[3].parse::<f32>().unwrap_or(0.0),
+        // The comparison is the direction of the move, or `moves` if either direction will do.
+        comparison: match direction.as_deref() {
+            Some("up") => "up",
+            Some("down") => "down",
+            _ => "moves",
+        },
+        // Changes are measured against USD unless another currency is given.
+        to_currency: cap
+            .get(5)
+            .map_or("USD".to_string(), |m| m.as_str().to_uppercase()),
+    })
+}
+
+// Who to notify when an alert fires: `everyone` in the channel for `alert all` or
+// `alert everyone`, otherwise `me`, the user that set the alert.
+fn notify_target(who: &str) -> &'static str {
+    if who.eq_ignore_ascii_case("all") || who.eq_ignore_ascii_case("everyone") {
+        "everyone"
+    } else {
+        "me"
+    }
+}
+
+// How the notification target is described when confirming an alert.
+fn notify_who(notify: &str) -> &'static str {
+    if notify == "everyone" {
+        "everyone"
+    } else {
+        "you"
+    }
+}
+
+// The Slack mention that notifies the target of an alert when it fires.
+fn alert_mention(alert: &CurrencyAlert) -> String {
+    if alert.notify.as_deref() == Some("everyone") {
+        "<!channel>".to_string()
+    } else {
+        format!("<@{}>", alert.user)
+    }
+}
+
 // Determine if this is a request to set a ccurrency conversion alert.
 pub(crate) async fn get_currency_range_24h(
     from_currency: &str,
     to_currency: &str,
     amount: f32,
 ) -> Result<String, String> {
-    // Get XE API secrets from the envinroment.
-    let id = env::var("XE_ACCOUNT_ID").unwrap_or_else(|_| panic!("XE_ACCOUNT_ID is not set."));
-    let key = env::var("XE_API_KEY").unwrap_or_else(|_| panic!("XE_API_KEY is not set."));
-
     // Groub hourly data for the past 24 hours.
     let gmt: DateTime<Utc> = Utc::now();
     let end_timestamp = gmt.format("%Y-%m-%dT%H:%M").to_string();
@@ -209,7 +575,7 @@ pub(crate) async fn get_currency_range_24h(
     let start_timestamp = day_ago_gmt.format("%Y-%m-%dT%H:%M").to_string();
 
     // Make the remote request.
-    let response = match match surf::get(format!(
+    let response = xe_request(format!(
         "{}?from={}&to={}&amount={}&start_timestamp={}&end_timestamp={}&interval=hourly&crypto=true",
         CURRENCY_API_RANGE,
         from_currency,
@@ -218,28 +584,7 @@ pub(crate) async fn get_currency_range_24h(
         start_timestamp,
         end_timestamp,
     ))
-    .header("Authorization", util::generate_basic_auth(&id, &key))
-    .await
-    {
-        Ok(r) => r,
-        Err(e) => {
-            return Err(format!(
-                "Sorry, my request to the ConversionAPI failed (`surf::get()`): {}",
-                e
-            ));
-        }
-    }
-    .body_string()
-    .await
-    {
-        Ok(s) => s,
-        Err(e) => {
-            return Err(format!(
-                "Sorry, my request to the ConversionAPI failed (`surf::body_string()`): {}",
-                e
-            ));
-        }
-    };
+    .await?;
 
     // Parse the CurrencyAPI response.
     let mut parsed_response = match json::parse(&response) {
@@ -291,23 +636,16 @@ pub(crate) async fn get_currency_range_24h(
     ))
 }
 
-// Determine if this is a request to set a ccurrency conversion alert.
-pub(crate) async fn get_currency_quote(
-    from_currency: &str,
-    to_currency: &str,
-    amount: f32,
-) -> Result<f32, String> {
+// Make a request to the XE API, returning the response body.
+#[cfg(not(test))]
+async fn xe_request(url: String) -> Result<String, String> {
     // Get XE API secrets from the envinroment.
     let id = env::var("XE_ACCOUNT_ID").unwrap_or_else(|_| panic!("XE_ACCOUNT_ID is not set."));
     let key = env::var("XE_API_KEY").unwrap_or_else(|_| panic!("XE_API_KEY is not set."));
 
-    // Make the remote request.
-    let response = match match surf::get(format!(
-        "{}?from={}&to={}&amount={}&crypto=true",
-        CURRENCY_API, from_currency, to_currency, amount,
-    ))
-    .header("Authorization", util::generate_basic_auth(&id, &key))
-    .await
+    let mut response = match surf::get(url)
+        .header("Authorization", util::generate_basic_auth(&id, &key))
+        .await
     {
         Ok(r) => r,
         Err(e) => {
@@ -316,18 +654,48 @@ pub(crate) async fn get_currency_quote(
                 e
             ));
         }
-    }
-    .body_string()
-    .await
-    {
-        Ok(s) => s,
-        Err(e) => {
-            return Err(format!(
-                "Sorry, my request to the ConversionAPI failed (`surf::body_string()`): {}",
-                e
-            ));
-        }
     };
+    match response.body_string().await {
+        Ok(s) => Ok(s),
+        Err(e) => Err(format!(
+            "Sorry, my request to the ConversionAPI failed (`surf::body_string()`): {}",
+            e
+        )),
+    }
+}
+
+// Tests never call the XE API, requests are answered with fixed rates.
+#[cfg(test)]
+async fn xe_request(url: String) -> Result<String, String> {
+    tests::xe_response(&url)
+}
+
+// Determine if this is a request to set a ccurrency conversion alert.
+pub(crate) async fn get_currency_quote(
+    from_currency: &str,
+    to_currency: &str,
+    amount: f32,
+) -> Result<f32, String> {
+    let values = get_currency_quotes(from_currency, &[to_currency.to_string()], amount).await?;
+    Ok(values[0])
+}
+
+// Convert `amount` of `from_currency` into each of `to_currencies` with a single API request.
+// Returns the converted values in the same order as `to_currencies`.
+pub(crate) async fn get_currency_quotes(
+    from_currency: &str,
+    to_currencies: &[String],
+    amount: f32,
+) -> Result<Vec<f32>, String> {
+    // Make the remote request.
+    let response = xe_request(format!(
+        "{}?from={}&to={}&amount={}&crypto=true",
+        CURRENCY_API,
+        from_currency,
+        to_currencies.join(","),
+        amount,
+    ))
+    .await?;
 
     // Parse the CurrencyAPI response.
     let parsed_response = match json::parse(&response) {
@@ -340,43 +708,188 @@ pub(crate) async fn get_currency_quote(
         }
     };
 
-    // Extract the conversion rate from the parsed JSON.
-    let converted_json = &parsed_response["to"][0]["mid"];
-    let converted: f32 = match converted_json.as_f32() {
-        Some(c) => c,
-        None => {
-            return Err(format!(
-                "{} and/or {} unknown, failed to convert.",
-                from_currency, to_currency
-            ))
+    // Extract the conversion rate for each target currency from the parsed JSON.
+    let mut values = Vec::new();
+    for to_currency in to_currencies {
+        let converted = parsed_response["to"]
+            .members()
+            .find(|quote| quote["quotecurrency"].as_str() == Some(to_currency.as_str()))
+            .and_then(|quote| quote["mid"].as_f32());
+        match converted {
+            Some(converted) => values.push(round_quote(converted)),
+            None => {
+                return Err(format!(
+                    "{} and/or {} unknown, failed to convert.",
+                    from_currency, to_currency
+                ))
+            }
         }
-    };
+    }
+
+    Ok(values)
+}
 
+// Round converted values based on their magnitude.
+fn round_quote(converted: f32) -> f32 {
     // For values greater than 100.0, round to two decimals.
     if converted > 100.0 {
         let to_round = converted * 100.0;
-        Ok(to_round.round() / 100.0)
+        to_round.round() / 100.0
     // For values greater than 0.1, round to three decimals.
     } else if converted > 0.1 {
         let to_round = converted * 1000.0;
-        Ok(to_round.round() / 1000.0)
+        to_round.round() / 1000.0
     // For values greater than 0.000001, round to six decimals.
     } else if converted > 0.000001 {
         let to_round = converted * 1000000.0;
-        Ok(to_round.round() / 1000000.0)
+        to_round.round() / 1000000.0
     // For very small values, don't round.
     } else {
-        Ok(converted)
+        converted
+    }
+}
+
+// Format an amount for display with thousands separators, ie `1,234,567.89`. Small amounts are
+// shown in full, ie `0.00001234`, never in scientific notation.
+fn format_amount(amount: f32) -> String {
+    let formatted = amount.to_string();
+    let (sign, formatted) = match formatted.strip_prefix('-') {
+        Some(formatted) => ("-", formatted),
+        None => ("", formatted.as_str()),
+    };
+    let (integer, fraction) = match formatted.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (formatted, None),
+    };
+
+    let mut grouped = String::new();
+    for (index, digit) in integer.chars().enumerate() {
+        if index > 0 && (integer.len() - index) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    match fraction {
+        Some(fraction) => format!("{}{}.{}", sign, grouped, fraction),
+        None => format!("{}{}", sign, grouped),
+    }
+}
+
+// Parse a watchlist of currency pairs, ie `BTC-USD,EUR-USD`, ignoring invalid pairs.
+pub(crate) fn parse_watchlist(watchlist: &str) -> Vec<(String, String)> {
+    watchlist
+        .split(',')
+        .filter_map(|pair| {
+            let (from_currency, to_currency) = pair.trim().split_once('-')?;
+            if from_currency.is_empty() || to_currency.is_empty() {
+                log::warn!("ignoring invalid watchlist pair: {}", pair);
+                return None;
+            }
+            Some((from_currency.to_uppercase(), to_currency.to_uppercase()))
+        })
+        .collect()
+}
+
+// Once a day, post how much each currency pair in the watchlist moved since the day before.
+pub(crate) async fn watchlist_thread(channel_id: String, watchlist: Vec<(String, String)>) {
+    loop {
+        // Post the summary at midnight UTC.
+        let sleep_seconds = 60 * 60 * 24 - util::timestamp_now() % (60 * 60 * 24);
+        log::info!(
+            "currency watchlist thread sleeping {} seconds",
+            sleep_seconds
+        );
+        tokio::time::sleep(Duration::from_secs(sleep_seconds)).await;
+        // Don't post during quiet hours.
+        util::wait_for_quiet_hours().await;
+
+        let mut movers = Vec::new();
+        for (from_currency, to_currency) in &watchlist {
+            let rate = match get_currency_quote(from_currency, to_currency, 1.0).await {
+                Ok(rate) => rate,
+                Err(e) => {
+                    log::error!("currency lookup error: {}", e);
+                    continue;
+                }
+            };
+            // Compare to the rate a day ago, then record the new one for tomorrow. The alert
+            // thread also records quotes, so the latest quote may only be an hour old. If there's
+            // no quote from the day before, ie the bot was down, no change is shown rather than a
+            // change over several days.
+            let day_ago = util::timestamp_now().saturating_sub(60 * 60 * 24);
+            let previous_rate = quote_before(from_currency, to_currency, day_ago)
+                .unwrap_or_else(|e| {
+                    log::error!("failed to load quote history: {}", e);
+                    None
+                })
+                .filter(|(_, ts)| *ts >= day_ago.saturating_sub(60 * 60 * 24))
+                .map(|(rate, _)| rate);
+            if let Err(e) = record_quote(from_currency, to_currency, rate) {
+                log::error!("failed to record quote history: {}", e);
+            }
+            let change = previous_rate
+                .filter(|previous_rate| *previous_rate > 0.0)
+                .map(|previous_rate| (rate - previous_rate) / previous_rate * 100.0);
+            movers.push((from_currency, to_currency, rate, change));
+        }
+
+        if movers.is_empty() {
+            continue;
+        }
+
+        // List the biggest movers first.
+        movers.sort_by(|a, b| {
+            let a_change = a.3.map_or(0.0, f32::abs);
+            let b_change = b.3.map_or(0.0, f32::abs);
+            b_change
+                .partial_cmp(&a_change)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let lines: Vec<String> = movers
+            .iter()
+            .map(|(from_currency, to_currency, rate, change)| match change {
+                Some(change) => format!(
+                    "• 1 *{}* is {} *{}* ({:+.2}%)",
+                    from_currency,
+                    format_amount(*rate),
+                    to_currency,
+                    change
+                ),
+                None => format!(
+                    "• 1 *{}* is {} *{}*",
+                    from_currency,
+                    format_amount(*rate),
+                    to_currency
+                ),
+            })
+            .collect();
+        let text = format!("Daily currency movers:\n{}", lines.join("\n"));
+        slack::post_text(&channel_id, &text).await;
     }
 }
 
 // Wake regularly and process alerts.
 pub(crate) async fn alert_thread() {
+    // Alerts that triggered during quiet hours, posted once quiet hours end.
+    let mut deferred: Vec<(CurrencyAlert, String)> = Vec::new();
     loop {
+        if !deferred.is_empty() && util::quiet_seconds_remaining() == 0 {
+            for (alert, text) in deferred.drain(..) {
+                post_alert(&alert, &text).await;
+            }
+        }
+
         // Rebuild currency_map each time around to work with the latest quotes.
         let mut currency_map = HashMap::new();
-        let alerts = load_alerts();
+        let alerts = load_alerts().unwrap_or_else(|e| {
+            log::error!("failed to load currency alerts: {}", e);
+            Vec::new()
+        });
         for alert in alerts {
+            // Already triggered, waiting for quiet hours to end.
+            if deferred.iter().any(|(deferred, _)| deferred.id == alert.id) {
+                continue;
+            }
             let conversion_pair = format!("{}-{}", alert.from_currency, alert.to_currency);
             if !currency_map.contains_key(&conversion_pair) {
                 // Look up the conversion of 1 from_currency to to_currency, using this to locally calculate all alerts for
@@ -387,6 +900,9 @@ pub(crate) async fn alert_thread() {
                     log::error!("currency lookup error: {}", e);
                 // Otherwise store the result to avoid duplicate API requests while processing alerts.
                 } else if let Ok(value) = value {
+                    if let Err(e) = record_quote(&alert.from_currency, &alert.to_currency, value) {
+                        log::error!("failed to record quote history: {}", e);
+                    }
                     currency_map.insert(conversion_pair.clone(), value);
                 }
             }
@@ -394,26 +910,78 @@ pub(crate) async fn alert_thread() {
             // This can fail if the lookup failed above.
             match currency_map.get(&conversion_pair) {
                 Some(rate) => {
-                    let value = rate * alert.from_amount;
-                    if (alert.comparison == "more" && value > alert.to_amount)
-                        || (alert.comparison == "less" && value < alert.to_amount)
+                    let text = if let (Some(baseline_rate), Some(percent_change)) =
+                        (alert.baseline_rate, alert.percent_change)
                     {
-                        let text = format!(
-                            "<@{}> CURRENCY ALERT: {} *{}* is now worth {} than {} *{}* -- it's currently worth <{}|{} {}>.",
-                            alert.user,
-                            alert.from_amount,
-                            alert.from_currency,
-                            alert.comparison,
-                            alert.to_amount,
-                            alert.to_currency,
-                            get_currency_range_24h(&alert.from_currency, &alert.to_currency, alert.from_amount)
-                                .await
-                                .unwrap(),
-                            value,
-                            alert.to_currency
-                        );
-                        slack::post_text(&alert.channel, &text).await;
-                        delete_alert(alert.id);
+                        // Percentage-change alerts compare against the rate when the alert was set.
+                        let change = (rate - baseline_rate) / baseline_rate * 100.0;
+                        let triggered = match alert.comparison.as_str() {
+                            "up" => change >= percent_change,
+                            "down" => change <= -percent_change,
+                            _ => change.abs() >= percent_change,
+                        };
+                        if triggered {
+                            Some(format!(
+                                "{} CURRENCY ALERT: 1 *{}* has moved {:+.2}% from {} to {} *{}*.",
+                                alert_mention(&alert),
+                                alert.from_currency,
+                                change,
+                                format_amount(baseline_rate),
+                                format_amount(*rate),
+                                alert.to_currency
+                            ))
+                        } else {
+                            None
+                        }
+                    } else {
+                        let value = rate * alert.from_amount;
+                        if (alert.comparison == "more" && value > alert.to_amount)
+                            || (alert.comparison == "less" && value < alert.to_amount)
+                        {
+                            // Link the value to a chart of the past 24 hours, if there is one.
+                            let worth = format!(
+                                "{} {}",
+                                format_amount(round_quote(value)),
+                                alert.to_currency
+                            );
+                            let worth = match get_currency_range_24h(
+                                &alert.from_currency,
+                                &alert.to_currency,
+                                alert.from_amount,
+                            )
+                            .await
+                            {
+                                Ok(chart) => format!("<{}|{}>", chart, worth),
+                                Err(e) => {
+                                    log::warn!(
+                                        "failed to chart {} to {}: {}",
+                                        alert.from_currency,
+                                        alert.to_currency,
+                                        e
+                                    );
+                                    worth
+                                }
+                            };
+                            Some(format!(
+                                "{} CURRENCY ALERT: {} *{}* is now worth {} than {} *{}* -- it's currently worth {}.",
+                                alert_mention(&alert),
+                                format_amount(alert.from_amount),
+                                alert.from_currency,
+                                alert.comparison,
+                                format_amount(alert.to_amount),
+                                alert.to_currency,
+                                worth
+                            ))
+                        } else {
+                            None
+                        }
+                    };
+                    if let Some(text) = text {
+                        if util::quiet_seconds_remaining() > 0 {
+                            deferred.push((alert, text));
+                        } else {
+                            post_alert(&alert, &text).await;
+                        }
                     }
                 }
                 None => log::error!("failed to process alert: {:#?}", alert),
@@ -439,47 +1007,505 @@ pub(crate) async fn alert_thread() {
             // Check daily if there more API calls to make, and hope we don't run out.
             60 * 60 * 24
         };
+        // Optionally override the adaptive interval, trading API quota for faster alerting.
+        let sleep_seconds = match env::var("XE_ALERT_MIN_INTERVAL_SECONDS") {
+            Ok(interval) => match interval.trim().parse::<u64>() {
+                Ok(interval) => interval.max(ALERT_INTERVAL_FLOOR),
+                Err(e) => {
+                    log::warn!("invalid XE_ALERT_MIN_INTERVAL_SECONDS {}: {}", interval, e);
+                    sleep_seconds
+                }
+            },
+            Err(_) => sleep_seconds,
+        };
+        // Wake up when quiet hours end to post any deferred alerts.
+        let sleep_seconds = if deferred.is_empty() {
+            sleep_seconds
+        } else {
+            sleep_seconds.min(util::quiet_seconds_remaining().max(1))
+        };
         log::info!("currency alert thread sleeping {} seconds", sleep_seconds);
         tokio::time::sleep(Duration::from_secs(sleep_seconds)).await;
     }
 }
 
-// Load all alerts from the database.
-fn load_alerts() -> Vec<CurrencyAlert> {
-    let db = DB.lock().unwrap_or_else(|_| panic!("DB mutex poisoned!"));
-    let mut statement = db
-        .prepare(
-            "SELECT id, channel, user, from_currency, from_amount, comparison, to_currency, to_amount FROM currency_alert",
+// Post a triggered alert, and delete it so it only triggers once. Alerts are posted in the thread
+// they were set in, and also shown in the channel for people not following the thread.
+async fn post_alert(alert: &CurrencyAlert, text: &str) {
+    let text = slack::truncate_reply(text, slack::max_reply_length("convert"));
+    match alert.thread_ts.as_ref() {
+        Some(thread_ts) => slack::post_in_thread(&alert.channel, thread_ts, &text, true).await,
+        None => slack::post_text(&alert.channel, &text).await,
+    }
+    if let Err(e) = delete_alert(alert.id) {
+        log::error!("failed to delete currency alert {}: {}", alert.id, e);
+    }
+}
+
+// Store a new alert in the database.
+fn insert_alert(
+    channel: &str,
+    user: &str,
+    thread_ts: &str,
+    alert: &AlertSpec,
+) -> Result<(), DbError> {
+    let db = db::get_conn()?;
+    db.execute(
+        r#"INSERT INTO currency_alert (channel, user, from_currency, from_amount, comparison, to_currency, to_amount, notify, thread_ts)  VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"#,
+        params![
+            channel,
+            user,
+            alert.from_currency,
+            alert.from_amount,
+            alert.comparison,
+            alert.to_currency,
+            alert.to_amount,
+            alert.notify,
+            thread_ts
+        ],
+    )?;
+    Ok(())
+}
+
+// Determine if an identical alert is already stored.
+fn alert_exists(channel: &str, user: &str, alert: &AlertSpec) -> Result<bool, DbError> {
+    let db = db::get_conn()?;
+    let count: u32 = db.query_row(
+        "SELECT COUNT(*) FROM currency_alert WHERE channel = ?1 AND user = ?2 AND from_currency = ?3 AND from_amount = ?4 AND comparison = ?5 AND to_currency = ?6 AND to_amount = ?7 AND percent_change IS NULL AND COALESCE(notify, 'me') = ?8",
+        params![
+            channel,
+            user,
+            alert.from_currency,
+            alert.from_amount,
+            alert.comparison,
+            alert.to_currency,
+            alert.to_amount,
+            alert.notify
+        ],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+// Determine if an identical percentage-change alert is already stored. The baseline rate isn't
+// compared, as it changes every time the alert is requested.
+fn change_alert_exists(
+    channel: &str,
+    user: &str,
+    alert: &ChangeAlertSpec,
+) -> Result<bool, DbError> {
+    let db = db::get_conn()?;
+    let count: u32 = db.query_row(
+        "SELECT COUNT(*) FROM currency_alert WHERE channel = ?1 AND user = ?2 AND from_currency = ?3 AND comparison = ?4 AND to_currency = ?5 AND percent_change = ?6 AND COALESCE(notify, 'me') = ?7",
+        params![
+            channel,
+            user,
+            alert.from_currency,
+            alert.comparison,
+            alert.to_currency,
+            alert.percent_change,
+            alert.notify
+        ],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+// Store a new percentage-change alert in the database.
+fn insert_change_alert(
+    channel: &str,
+    user: &str,
+    thread_ts: &str,
+    alert: &ChangeAlertSpec,
+    baseline_rate: f32,
+) -> Result<(), DbError> {
+    let db = db::get_conn()?;
+    db.execute(
+        r#"INSERT INTO currency_alert (channel, user, from_currency, from_amount, comparison, to_currency, to_amount, baseline_rate, percent_change, notify, thread_ts)  VALUES(?1, ?2, ?3, 1, ?4, ?5, ?6, ?6, ?7, ?8, ?9)"#,
+        params![
+            channel,
+            user,
+            alert.from_currency,
+            alert.comparison,
+            alert.to_currency,
+            baseline_rate,
+            alert.percent_change,
+            alert.notify,
+            thread_ts
+        ],
+    )?;
+    Ok(())
+}
+
+// Record a quote, the value of 1 `from_currency` in `to_currency`.
+fn record_quote(from_currency: &str, to_currency: &str, rate: f32) -> Result<(), DbError> {
+    let db = db::get_conn()?;
+    db.execute(
+        "INSERT INTO quote_history (from_currency, to_currency, rate, ts) VALUES(?1, ?2, ?3, ?4)",
+        params![from_currency, to_currency, rate, util::timestamp_now()],
+    )?;
+    Ok(())
+}
+
+// Load the most recent quote for a currency pair recorded at or before `before`, and when it
+// was recorded.
+fn quote_before(
+    from_currency: &str,
+    to_currency: &str,
+    before: u64,
+) -> Result<Option<(f32, u64)>, DbError> {
+    let db = db::get_conn()?;
+    let quote = db
+        .query_row(
+            "SELECT rate, ts FROM quote_history WHERE from_currency = ?1 AND to_currency = ?2 AND ts <= ?3 ORDER BY ts DESC LIMIT 1",
+            params![from_currency, to_currency, before],
+            |row| Ok((row.get(0)?, row.get(1)?)),
         )
-        .expect("failed to prepare SELECT");
-    let currency_alert_iterator = statement
-        .query_map([], |row| {
-            Ok(CurrencyAlert {
-                id: row.get(0).expect("failed to get id"),
-                channel: row.get(1).expect("failed to get channel"),
-                user: row.get(2).expect("failed to get user"),
-                from_currency: row.get(3).expect("failed to get user"),
-                from_amount: row.get(4).expect("failed to get user"),
-                comparison: row.get(5).expect("failed to get user"),
-                to_currency: row.get(6).expect("failed to get user"),
-                to_amount: row.get(7).expect("failed to get user"),
-            })
+        .optional()?;
+    Ok(quote)
+}
+
+// Load up to `limit` of the most recently recorded quotes for a currency pair, newest first.
+fn load_quote_history(
+    from_currency: &str,
+    to_currency: &str,
+    limit: u32,
+) -> Result<Vec<(f32, u64)>, DbError> {
+    let db = db::get_conn()?;
+    let mut statement = db.prepare(
+        "SELECT rate, ts FROM quote_history WHERE from_currency = ?1 AND to_currency = ?2 ORDER BY ts DESC LIMIT ?3",
+    )?;
+    let quote_iterator = statement
+        .query_map(params![from_currency, to_currency, limit], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+
+    let mut quotes = Vec::new();
+    for quote in quote_iterator {
+        quotes.push(quote?);
+    }
+    Ok(quotes)
+}
+
+// Load all alerts from the database.
+fn load_alerts() -> Result<Vec<CurrencyAlert>, DbError> {
+    let db = db::get_conn()?;
+    let mut statement = db.prepare(
+        "SELECT id, channel, user, from_currency, from_amount, comparison, to_currency, to_amount, baseline_rate, percent_change, notify, thread_ts FROM currency_alert",
+    )?;
+    let currency_alert_iterator = statement.query_map([], |row| {
+        Ok(CurrencyAlert {
+            id: row.get(0)?,
+            channel: row.get(1)?,
+            user: row.get(2)?,
+            from_currency: row.get(3)?,
+            from_amount: row.get(4)?,
+            comparison: row.get(5)?,
+            to_currency: row.get(6)?,
+            to_amount: row.get(7)?,
+            baseline_rate: row.get(8)?,
+            percent_change: row.get(9)?,
+            notify: row.get(10)?,
+            thread_ts: row.get(11)?,
         })
-        .expect("failed to select from seen table");
+    })?;
 
     let mut currency_alerts = Vec::new();
     for currency_alert in currency_alert_iterator {
-        currency_alerts.push(currency_alert.expect("failed to load row from currency_alert"));
+        currency_alerts.push(currency_alert?);
     }
-    currency_alerts
+    Ok(currency_alerts)
 }
 
 // Delete an alert once it has triggered.
-fn delete_alert(alert_id: u32) {
-    let db = DB.lock().unwrap_or_else(|_| panic!("DB mutex poisoned!"));
+fn delete_alert(alert_id: u32) -> Result<(), DbError> {
+    let db = db::get_conn()?;
     db.execute(
         r#"DELETE FROM currency_alert WHERE id = ?1"#,
         params![alert_id],
-    )
-    .expect("failed to delete currency alert");
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    // Fixed rates used instead of the XE API, the value of 1 of the first currency in the second.
+    const MOCK_RATES: &[(&str, &str, f32)] = &[
+        ("USD", "EUR", 0.92),
+        ("EUR", "USD", 1.087),
+        ("BTC", "USD", 100_000.0),
+    ];
+
+    // Answer a request to the XE API with MOCK_RATES. Charts aren't available.
+    pub(super) fn xe_response(url: &str) -> Result<String, String> {
+        let query = match url.strip_prefix(CURRENCY_API) {
+            Some(query) => query.trim_start_matches('?'),
+            None => return Err("not available in tests".to_string()),
+        };
+        let query: HashMap<&str, &str> = query
+            .split('&')
+            .filter_map(|parameter| parameter.split_once('='))
+            .collect();
+        let amount: f32 = query["amount"].parse().expect("invalid amount");
+        // Unknown currencies are left out of the response, as XE does.
+        let quotes: Vec<json::JsonValue> = query["to"]
+            .split(',')
+            .filter_map(|to_currency| {
+                MOCK_RATES
+                    .iter()
+                    .find(|(from, to, _)| *from == query["from"] && *to == to_currency)
+                    .map(|(_, _, rate)| {
+                        json::object! { "quotecurrency": to_currency, "mid": rate * amount }
+                    })
+            })
+            .collect();
+        Ok(json::object! { "to": quotes }.dump())
+    }
+
+    fn alert(
+        notify: &'static str,
+        from_amount: f32,
+        from_currency: &str,
+        comparison: &'static str,
+        to_amount: f32,
+        to_currency: &str,
+    ) -> Option<AlertSpec> {
+        Some(AlertSpec {
+            notify,
+            from_amount,
+            from_currency: from_currency.to_string(),
+            comparison,
+            to_amount,
+            to_currency: to_currency.to_string(),
+        })
+    }
+
+    #[test]
+    fn parse_convert_requests() {
+        assert_eq!(
+            parse_convert("convert 1 BTC to USD"),
+            Some((1.0, "BTC".to_string(), vec!["USD".to_string()]))
+        );
+        assert_eq!(
+            parse_convert("convert from 2.5 eur usd"),
+            Some((2.5, "EUR".to_string(), vec!["USD".to_string()]))
+        );
+        assert_eq!(
+            parse_convert("convert 100usd to eur"),
+            Some((100.0, "USD".to_string(), vec!["EUR".to_string()]))
+        );
+        // The amount defaults to 1.
+        assert_eq!(
+            parse_convert("convert USD to EUR"),
+            Some((1.0, "USD".to_string(), vec!["EUR".to_string()]))
+        );
+        assert_eq!(
+            parse_convert("convert 100 USD to EUR, GBP JPY"),
+            Some((
+                100.0,
+                "USD".to_string(),
+                vec!["EUR".to_string(), "GBP".to_string(), "JPY".to_string()]
+            ))
+        );
+        assert_eq!(parse_convert("convert 100 dollars to euros"), None);
+        assert_eq!(parse_convert("convert 100 to EUR"), None);
+        assert_eq!(parse_convert("convert USD"), None);
+        assert_eq!(parse_convert("please convert 1 USD to EUR"), None);
+    }
+
+    #[test]
+    fn parse_convert_loose_requests() {
+        assert_eq!(
+            parse_convert("$100 to eur"),
+            Some((100.0, "USD".to_string(), vec!["EUR".to_string()]))
+        );
+        assert_eq!(
+            parse_convert("100 USD in EUR"),
+            Some((100.0, "USD".to_string(), vec!["EUR".to_string()]))
+        );
+        assert_eq!(
+            parse_convert("£50 to usd"),
+            Some((50.0, "GBP".to_string(), vec!["USD".to_string()]))
+        );
+        assert_eq!(
+            parse_convert("convert €2.50 into JPY, GBP"),
+            Some((
+                2.5,
+                "EUR".to_string(),
+                vec!["JPY".to_string(), "GBP".to_string()]
+            ))
+        );
+        assert_eq!(
+            parse_convert("1000 JPY in $"),
+            Some((1000.0, "JPY".to_string(), vec!["USD".to_string()]))
+        );
+        assert_eq!(
+            parse_convert("convert ¥1000 in €"),
+            Some((1000.0, "JPY".to_string(), vec!["EUR".to_string()]))
+        );
+        assert_eq!(
+            parse_convert("from .5 BTC to USD"),
+            Some((0.5, "BTC".to_string(), vec!["USD".to_string()]))
+        );
+        // The amount is required without `convert`, and the currency to convert from is required.
+        assert_eq!(parse_convert("USD in EUR"), None);
+        assert_eq!(parse_convert("100 in EUR"), None);
+        assert_eq!(parse_convert("see you in 5 min"), None);
+        assert_eq!(parse_convert("5 cats in the house"), None);
+        // Without `convert`, both currencies must be known.
+        assert_eq!(parse_convert("2 cats to feed"), None);
+        assert_eq!(parse_convert("5 cats in the barn"), None);
+        assert_eq!(parse_convert("3 eggs to boil"), None);
+        assert_eq!(parse_convert("10 USD to feed"), None);
+        assert_eq!(parse_convert("$5 to spend"), None);
+        assert_eq!(
+            parse_convert("convert 2 cats to feed"),
+            Some((2.0, "CATS".to_string(), vec!["FEED".to_string()]))
+        );
+    }
+
+    #[test]
+    fn format_amounts() {
+        assert_eq!(format_amount(0.0), "0");
+        assert_eq!(format_amount(100.0), "100");
+        assert_eq!(format_amount(1000.0), "1,000");
+        assert_eq!(format_amount(1234567.9), "1,234,567.9");
+        assert_eq!(format_amount(round_quote(12345.678)), "12,345.68");
+        assert_eq!(format_amount(round_quote(0.95123)), "0.951");
+        assert_eq!(format_amount(0.00001234), "0.00001234");
+        assert_eq!(format_amount(-1234.5), "-1,234.5");
+    }
+
+    #[test]
+    fn parse_alert_greater() {
+        assert_eq!(
+            parse_alert("alert me when 1 USD is greater than .95 EUR"),
+            alert("me", 1.0, "USD", "more", 0.95, "EUR")
+        );
+        assert_eq!(
+            parse_alert("notify everyone if 1 BTC > 100000 USD"),
+            alert("everyone", 1.0, "BTC", "more", 100000.0, "USD")
+        );
+        assert_eq!(
+            parse_alert("tell me when 1 USD is more then 0.9 EUR"),
+            alert("me", 1.0, "USD", "more", 0.9, "EUR")
+        );
+        assert_eq!(
+            parse_alert("Ping ALL when 1.5 eur gt 2 usd"),
+            alert("everyone", 1.5, "EUR", "more", 2.0, "USD")
+        );
+    }
+
+    #[test]
+    fn parse_alert_less() {
+        assert_eq!(
+            parse_alert("alert when BTC is less than 20000 USD"),
+            alert("me", 1.0, "BTC", "less", 20000.0, "USD")
+        );
+        assert_eq!(
+            parse_alert("alert me when 10 EUR is lesser then 9.5 GBP"),
+            alert("me", 10.0, "EUR", "less", 9.5, "GBP")
+        );
+        assert_eq!(
+            parse_alert("alert me if 1 ETH < 1500.50 USD"),
+            alert("me", 1.0, "ETH", "less", 1500.5, "USD")
+        );
+        assert_eq!(
+            parse_alert("alert me when 1 ETH lt 1500 USD"),
+            alert("me", 1.0, "ETH", "less", 1500.0, "USD")
+        );
+    }
+
+    #[test]
+    fn parse_change_alert_requests() {
+        assert_eq!(
+            parse_change_alert("alert me when BTC moves 5%"),
+            Some(ChangeAlertSpec {
+                notify: "me",
+                from_currency: "BTC".to_string(),
+                percent_change: 5.0,
+                comparison: "moves",
+                to_currency: "USD".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_change_alert("notify everyone if eth changes 2.5% down vs eur"),
+            Some(ChangeAlertSpec {
+                notify: "everyone",
+                from_currency: "ETH".to_string(),
+                percent_change: 2.5,
+                comparison: "down",
+                to_currency: "EUR".to_string(),
+            })
+        );
+        assert_eq!(parse_change_alert("alert me when BTC moves"), None);
+    }
+
+    #[test]
+    fn alert_notifies_target() {
+        let mut alert = CurrencyAlert {
+            id: 1,
+            channel: "C1".to_string(),
+            user: "U1".to_string(),
+            from_currency: "BTC".to_string(),
+            from_amount: 1.0,
+            comparison: "more".to_string(),
+            to_currency: "USD".to_string(),
+            to_amount: 100000.0,
+            baseline_rate: None,
+            percent_change: None,
+            notify: None,
+            thread_ts: None,
+        };
+        assert_eq!(alert_mention(&alert), "<@U1>");
+        alert.notify = Some(notify_target("me").to_string());
+        assert_eq!(alert_mention(&alert), "<@U1>");
+        alert.notify = Some(notify_target("").to_string());
+        assert_eq!(alert_mention(&alert), "<@U1>");
+        alert.notify = Some(notify_target("Everyone").to_string());
+        assert_eq!(alert_mention(&alert), "<!channel>");
+        alert.notify = Some(notify_target("all").to_string());
+        assert_eq!(alert_mention(&alert), "<!channel>");
+    }
+
+    #[test]
+    fn parse_alert_rejects_other_requests() {
+        assert_eq!(parse_alert("alert me when BTC moves 5%"), None);
+        assert_eq!(parse_alert("alert me when 1 USD equals 1 EUR"), None);
+        assert_eq!(parse_alert("convert 1 USD to EUR"), None);
+        assert_eq!(parse_alert("remind me when 1 USD is less than 1 EUR"), None);
+    }
+
+    #[test]
+    fn watchlist_compares_to_a_day_ago() {
+        testing::setup();
+        let now = util::timestamp_now();
+        let day_ago = now - 60 * 60 * 24;
+        // A quote from the day before, then hourly quotes from the alert thread.
+        for (rate, ts) in [
+            (1.0, day_ago - 60 * 60),
+            (2.0, day_ago - 60),
+            (3.0, now - 60 * 60 * 2),
+            (4.0, now - 60 * 60),
+        ] {
+            let db = db::get_conn().unwrap();
+            db.execute(
+                "INSERT INTO quote_history (from_currency, to_currency, rate, ts) VALUES(?1, ?2, ?3, ?4)",
+                params!["WLA", "WLB", rate, ts],
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            quote_before("WLA", "WLB", day_ago).unwrap(),
+            Some((2.0, day_ago - 60))
+        );
+        assert_eq!(
+            quote_before("WLA", "WLB", now).unwrap(),
+            Some((4.0, now - 60 * 60))
+        );
+        assert_eq!(quote_before("WLB", "WLA", day_ago).unwrap(), None);
+    }
 }
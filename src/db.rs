@@ -1,81 +1,331 @@
 // Functions for creating, updating and sharing the sqlite database.
 
-use rusqlite::Connection;
-use std::sync::{Arc, Mutex};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection};
+use std::{env, path::PathBuf};
 
-// Write state database in the current working direcrtly.
+// By default, write state database in the current working direcrtly.
 const DATABASE_FILE: &str = "./state.sqlite3";
 
-// Open the database file once and share as needed.
+// A connection borrowed from the pool, returned to the pool when dropped.
+pub(crate) type DbConnection = PooledConnection<SqliteConnectionManager>;
+
+// Open a pool of connections to the database once and share as needed.
 lazy_static! {
-    pub(crate) static ref DB: Arc<Mutex<Connection>> = Arc::new(Mutex::new(
-        Connection::open(DATABASE_FILE)
-            .unwrap_or_else(|_| panic!("failed to open {}", DATABASE_FILE))
-    ));
+    static ref POOL: Pool<SqliteConnectionManager> = open_pool();
 }
 
-// Create all tables and indexes at startup.
-pub(crate) fn setup() {
-    let db = DB.lock().unwrap_or_else(|_| panic!("DB mutex poisoned!"));
+// Open a pool of connections to the database file.
+#[cfg(not(test))]
+fn open_pool() -> Pool<SqliteConnectionManager> {
+    let path = database_path();
+    // Create the directory holding the database, if needed.
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)
+                .unwrap_or_else(|e| panic!("failed to create {}: {}", parent.display(), e));
+        }
+    }
+    // Use write-ahead logging so reads and writes don't block each other.
+    let manager = SqliteConnectionManager::file(&path)
+        .with_init(|c| c.execute_batch("PRAGMA journal_mode = WAL;"));
+    Pool::new(manager).unwrap_or_else(|e| panic!("failed to open {}: {}", path.display(), e))
+}
 
-    // Create the karma table if it doesn't already exist.
-    db.execute(
-        "CREATE TABLE IF NOT EXISTS karma (
-        id              INTEGER PRIMARY KEY,
-        name            TEXT NOT NULL,
-        counter         INTEGER
-            )",
-        [],
-    )
-    .expect("failed to create karma table");
-    db.execute("CREATE INDEX IF NOT EXISTS i_name ON karma (name)", [])
-        .expect("failed to create index karma.i_name");
+// Tests never touch the real database, and leave nothing behind: each test run gets a database in
+// memory. Every in-memory connection is a separate database, so the pool holds a single
+// connection that is never closed.
+#[cfg(test)]
+fn open_pool() -> Pool<SqliteConnectionManager> {
+    Pool::builder()
+        .max_size(1)
+        .max_lifetime(None)
+        .idle_timeout(None)
+        .build(SqliteConnectionManager::memory())
+        .unwrap_or_else(|e| panic!("failed to open test database: {}", e))
+}
 
-    // Create the seen table if it doesn't already exist.
-    db.execute(
-        "CREATE TABLE IF NOT EXISTS seen (
-        id              INTEGER PRIMARY KEY,
-        channel         TEXT NOT NULL,
-        user            TEXT NOT NULL,
-        last_said       TEXT NOT NULL,
-        last_seen       INTEGER,
-        last_private    INTEGER
-            )",
-        [],
-    )
-    .expect("failed to create seen table");
-    db.execute("CREATE INDEX IF NOT EXISTS i_name ON seen (name)", [])
-        .expect("failed to create seen seen.i_name");
+// The database file can be moved with the `TAG1BOT_DB_PATH` environment variable.
+pub(crate) fn database_path() -> PathBuf {
+    PathBuf::from(env::var("TAG1BOT_DB_PATH").unwrap_or_else(|_| DATABASE_FILE.to_string()))
+}
 
-    // Create the currency_alert table if it doesn't already exist.
-    db.execute(
-        "CREATE TABLE IF NOT EXISTS currency_alert (
-        id              INTEGER PRIMARY KEY,
-        channel         TEXT NOT NULL,
-        user            TEXT NOT NULL,
-        from_currency   TEXT NOT NULL,
-        from_amount     REAL,
-        comparison      TEXT NOT NULL,
-        to_currency     TEXT NOT NULL,
-        to_amount       REAL
-            )",
-        [],
-    )
-    .expect("failed to create currency_alert table");
+// Errors returned by database helpers.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DbError {
+    // Failed to get a connection from the pool.
+    #[error("database pool error: {0}")]
+    Pool(#[from] r2d2::Error),
+    // A query failed.
+    #[error("database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+// Get a connection from the pool.
+pub(crate) fn get_conn() -> Result<DbConnection, DbError> {
+    Ok(POOL.get()?)
+}
+
+// A single schema change. The `schema_version` table records which migrations have been applied,
+// so each is applied once, in order. Each migration must also be idempotent, so it's safe to
+// apply to a database that already has the change, for example one created before migrations
+// were tracked.
+type Migration = fn(&Connection) -> rusqlite::Result<()>;
+
+// All schema changes in the order they are applied. Never edit or reorder a migration that has
+// already shipped, instead append a new one to the end of the list.
+const MIGRATIONS: &[Migration] = &[
+    // 1: Initial schema.
+    |db| {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS karma (
+                id              INTEGER PRIMARY KEY,
+                name            TEXT NOT NULL,
+                counter         INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS i_name ON karma (name);
 
-    // Create the chatgpt_threads table if it doesn't already exist.
+            CREATE TABLE IF NOT EXISTS seen (
+                id              INTEGER PRIMARY KEY,
+                channel         TEXT NOT NULL,
+                user            TEXT NOT NULL,
+                last_said       TEXT NOT NULL,
+                last_seen       INTEGER,
+                last_private    INTEGER
+            );
+
+            CREATE TABLE IF NOT EXISTS currency_alert (
+                id              INTEGER PRIMARY KEY,
+                channel         TEXT NOT NULL,
+                user            TEXT NOT NULL,
+                from_currency   TEXT NOT NULL,
+                from_amount     REAL,
+                comparison      TEXT NOT NULL,
+                to_currency     TEXT NOT NULL,
+                to_amount       REAL
+            );
+
+            CREATE TABLE IF NOT EXISTS chatgpt_context (
+                id              INTEGER PRIMARY KEY,
+                thread          TEXT NOT NULL,
+                context         TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS i_thread ON chatgpt_context (thread);",
+        )
+    },
+    // 2: Track seen per user and channel.
+    |db| {
+        db.execute_batch(
+            "DELETE FROM seen WHERE id NOT IN (SELECT MAX(id) FROM seen GROUP BY user, channel);
+            CREATE UNIQUE INDEX IF NOT EXISTS i_seen_user_channel ON seen (user, channel);",
+        )
+    },
+    // 3: Currency quote history.
+    |db| {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS quote_history (
+                id              INTEGER PRIMARY KEY,
+                from_currency   TEXT NOT NULL,
+                to_currency     TEXT NOT NULL,
+                rate            REAL,
+                ts              INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS i_quote_history_pair ON quote_history (from_currency, to_currency, ts);",
+        )
+    },
+    // 4: Percentage-change currency alerts.
+    |db| {
+        add_column_if_missing(db, "currency_alert", "baseline_rate", "REAL")?;
+        add_column_if_missing(db, "currency_alert", "percent_change", "REAL")
+    },
+    // 5: Per-channel feature configuration.
+    |db| {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS channel_config (
+                channel         TEXT NOT NULL,
+                feature         TEXT NOT NULL,
+                enabled         INTEGER NOT NULL,
+                PRIMARY KEY (channel, feature)
+            );",
+        )
+    },
+    // 6: Log who gave karma to what.
+    |db| {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS karma_log (
+                id              INTEGER PRIMARY KEY,
+                giver           TEXT NOT NULL,
+                name            TEXT NOT NULL,
+                adjustment      INTEGER NOT NULL,
+                ts              INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS i_karma_log_giver ON karma_log (giver);
+            CREATE INDEX IF NOT EXISTS i_karma_log_name ON karma_log (name);",
+        )
+    },
+    // 7: Record the timestamp of the message each user was last seen saying.
+    |db| add_column_if_missing(db, "seen", "last_ts", "TEXT"),
+    // 8: Record who to notify when a currency alert fires.
+    |db| add_column_if_missing(db, "currency_alert", "notify", "TEXT"),
+    // 9: Record the stable Slack user id with seen, as names can change.
+    |db| {
+        add_column_if_missing(db, "seen", "user_id", "TEXT")?;
+        db.execute_batch("CREATE INDEX IF NOT EXISTS i_seen_user_id ON seen (user_id);")
+    },
+    // 10: Users who opted out of seen tracking.
+    |db| {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS seen_optout (
+                user_id         TEXT PRIMARY KEY,
+                user            TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS i_seen_optout_user ON seen_optout (user);",
+        )
+    },
+    // 11: Audit log of commands run.
+    |db| {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id              INTEGER PRIMARY KEY,
+                ts              INTEGER NOT NULL,
+                user_id         TEXT,
+                channel_id      TEXT NOT NULL,
+                command         TEXT NOT NULL,
+                text            TEXT
+            );
+            CREATE INDEX IF NOT EXISTS i_audit_log_user_id ON audit_log (user_id);",
+        )
+    },
+    // 12: Record the thread a currency alert was set in, to reply there when it fires.
+    |db| add_column_if_missing(db, "currency_alert", "thread_ts", "TEXT"),
+    // 13: Count the messages each user has been seen posting, for `seen stats`.
+    |db| add_column_if_missing(db, "seen", "messages", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+// Add a column to a table, unless it already has it: SQLite has no `ADD COLUMN IF NOT EXISTS`.
+fn add_column_if_missing(
+    db: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> rusqlite::Result<()> {
+    let exists: bool = db.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        db.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {};",
+            table, column, decl
+        ))?;
+    }
+    Ok(())
+}
+
+// Create all tables and indexes at startup, applying any pending migrations.
+pub(crate) fn setup() {
+    let mut db = get_conn().unwrap_or_else(|e| panic!("failed to open database: {}", e));
+    migrate(&mut db).unwrap_or_else(|e| panic!("failed to migrate database: {}", e));
+}
+
+// Checkpoint the write-ahead log into the database file before exiting.
+pub(crate) fn shutdown() {
+    match get_conn() {
+        Ok(db) => {
+            if let Err(e) = db.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);") {
+                log::error!("failed to checkpoint database: {}", e);
+            }
+        }
+        Err(e) => log::error!("failed to open database for shutdown: {}", e),
+    }
+}
+
+// Apply all migrations newer than the version recorded in the `schema_version` table.
+pub(crate) fn migrate(db: &mut Connection) -> rusqlite::Result<()> {
     db.execute(
-        "CREATE TABLE IF NOT EXISTS chatgpt_context (
-        id              INTEGER PRIMARY KEY,
-        thread          TEXT NOT NULL,
-        context         TEXT NOT NULL
+        "CREATE TABLE IF NOT EXISTS schema_version (
+        version         INTEGER NOT NULL
             )",
         [],
-    )
-    .expect("failed to create chatgpt_threads table");
-    db.execute(
-        "CREATE INDEX IF NOT EXISTS i_thread ON chatgpt_context (thread)",
+    )?;
+    let version: u32 = db.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
         [],
-    )
-    .expect("failed to create index karma.i_name");
+        |row| row.get(0),
+    )?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        // Apply each migration in its own transaction, so a failure leaves the database at the
+        // last successfully applied version.
+        let tx = db.transaction()?;
+        migration(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            params![index as u32 + 1],
+        )?;
+        tx.commit()?;
+        log::info!("applied database migration {}", index + 1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_clean_database() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db).unwrap();
+        let version: u32 = db
+            .query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+
+        // Running again is a no-op.
+        migrate(&mut db).unwrap();
+        let versions: u32 = db
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(versions as usize, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn migrations_are_idempotent() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db).unwrap();
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            migration(&db).unwrap_or_else(|e| panic!("migration {}: {}", index + 1, e));
+        }
+    }
+
+    #[test]
+    fn seen_lookup_uses_index() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db).unwrap();
+        for user in ["alice", "bob", "carol"] {
+            db.execute(
+                "INSERT INTO seen (user, channel, last_said, last_seen) VALUES (?1, 'C1', 'hi', 1)",
+                params![user],
+            )
+            .unwrap();
+        }
+        let plan: String = db
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT last_said FROM seen WHERE user = 'bob'",
+                [],
+                |row| row.get(3),
+            )
+            .unwrap();
+        assert!(
+            plan.contains("USING INDEX i_seen_user_channel "),
+            "{}",
+            plan
+        );
+    }
 }
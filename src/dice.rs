@@ -0,0 +1,170 @@
+// Flips coins and rolls dice.
+// For example, `flip`, `roll` or `roll 3d20`.
+
+use rand::Rng;
+use regex::Regex;
+
+use crate::slack;
+use crate::stats;
+
+const REGEX_FLIP: &str = r"(?i)^flip(?: a coin)?$";
+const REGEX_ROLL: &str = r"(?i)^roll(?: (\d{1,10})?d(\d{1,10}))?$";
+
+// Limits on how many dice can be rolled at once, and how many sides they can have.
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+
+// Check if user is asking to flip a coin or roll dice.
+pub(crate) async fn process_message(message: &slack::Message) -> Option<(String, String)> {
+    let trimmed_text = message.text.trim();
+
+    let reply_message = if let Some(reply_message) = flip(trimmed_text) {
+        reply_message
+    } else if let Some(reply_message) = roll(trimmed_text) {
+        match reply_message {
+            Ok(reply_message) => reply_message,
+            // Errors are only of interest to the user that made the request.
+            Err(reply_message) => return slack::reply_ephemeral(message, reply_message).await,
+        }
+    } else {
+        return None;
+    };
+
+    stats::record(stats::Stat::Dice);
+
+    // Always reply in a thread: determine if reply is in a new thread or an existing thread.
+    let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
+        thread_ts.clone()
+    } else {
+        message.ts.clone()
+    };
+    Some((reply_thread_ts, reply_message))
+}
+
+// Determine if this is a request to flip a coin, and if so flip it.
+fn flip(trimmed_text: &str) -> Option<String> {
+    let re = Regex::new(REGEX_FLIP).expect("failed to compile REGEX_FLIP");
+    if !re.is_match(trimmed_text) {
+        return None;
+    }
+
+    let side = if rand::thread_rng().gen_bool(0.5) {
+        "heads"
+    } else {
+        "tails"
+    };
+    Some(format!("The coin landed on *{}*.", side))
+}
+
+// Determine if this is a request to roll dice, and if so roll them. A plain `roll` rolls 1d6.
+// Returns `Some(Err(message))` if the request is out of bounds.
+fn roll(trimmed_text: &str) -> Option<Result<String, String>> {
+    let re = Regex::new(REGEX_ROLL).expect("failed to compile REGEX_ROLL");
+    let cap = re.captures(trimmed_text)?;
+
+    // The number of dice defaults to 1, the number of sides to 6.
+    let dice = cap
+        .get(1)
+        .map_or(Some(1), |m| m.as_str().parse::<u32>().ok());
+    let sides = cap
+        .get(2)
+        .map_or(Some(6), |m| m.as_str().parse::<u32>().ok());
+    let (dice, sides) = match (dice, sides) {
+        (Some(dice), Some(sides))
+            if (1..=MAX_DICE).contains(&dice) && (2..=MAX_SIDES).contains(&sides) =>
+        {
+            (dice, sides)
+        }
+        _ => {
+            return Some(Err(format!(
+                "Sorry, I can only roll 1 to {} dice with 2 to {} sides.",
+                MAX_DICE, MAX_SIDES
+            )))
+        }
+    };
+
+    let mut rng = rand::thread_rng();
+    let rolls: Vec<u32> = (0..dice).map(|_| rng.gen_range(1..=sides)).collect();
+    let total: u32 = rolls.iter().sum();
+
+    if dice == 1 {
+        Some(Ok(format!("You rolled a *{}* (1d{}).", total, sides)))
+    } else {
+        Some(Ok(format!(
+            "You rolled *{}* ({}d{}: {}).",
+            total,
+            dice,
+            sides,
+            rolls
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The values rolled, from a reply like "You rolled *7* (2d6: 3, 4)."
+    fn rolled(reply_message: &str) -> Vec<u32> {
+        let rolls = reply_message
+            .split_once(": ")
+            .map_or("", |(_, rolls)| rolls.trim_end_matches(")."));
+        rolls.split(", ").map(|r| r.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn rolls_dice() {
+        for _ in 0..20 {
+            let reply_message = roll("roll").unwrap().unwrap();
+            assert!(reply_message.ends_with("(1d6)."), "{}", reply_message);
+            let total: u32 = reply_message.split('*').nth(1).unwrap().parse().unwrap();
+            assert!((1..=6).contains(&total), "{}", reply_message);
+
+            let reply_message = roll("roll 3d20").unwrap().unwrap();
+            let rolls = rolled(&reply_message);
+            assert_eq!(rolls.len(), 3, "{}", reply_message);
+            assert!(
+                rolls.iter().all(|r| (1..=20).contains(r)),
+                "{}",
+                reply_message
+            );
+        }
+        assert!(roll("Roll d20").unwrap().is_ok());
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_rolls() {
+        for text in [
+            "roll 0d6",
+            "roll 101d6",
+            "roll 1d1",
+            "roll 1d1001",
+            "roll 9999999999d6",
+            "roll 1d9999999999",
+        ] {
+            assert!(roll(text).unwrap().is_err(), "{}", text);
+        }
+        assert!(roll("rolling").is_none());
+        assert!(roll("roll 2d").is_none());
+    }
+
+    #[test]
+    fn flips_coins() {
+        for text in ["flip", "flip a coin", "Flip"] {
+            let reply_message = flip(text).unwrap();
+            assert!(
+                reply_message == "The coin landed on *heads*."
+                    || reply_message == "The coin landed on *tails*.",
+                "{}",
+                reply_message
+            );
+        }
+        for text in ["flipping", "flip the table", "a flip"] {
+            assert!(flip(text).is_none(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,670 @@
+// Routes each message received from Slack to the features that may respond to it.
+
+// Message subtypes that are never processed: edits and deletions would otherwise be processed a
+// second time, double counting karma, and other bots are ignored.
+const IGNORED_SUBTYPES: &[&str] = &["message_changed", "message_deleted", "bot_message"];
+
+// A mention at the start of a message, ie `<@U123> seen foo?` or `<@U123|tag1bot>: seen foo?`.
+const REGEX_LEADING_MENTION: &str = r"^\s*<@(\w+)(?:\|[^>]*)?>[\s:,]*";
+
+// Commands to suggest when someone makes a typo, with the feature each belongs to. Short commands
+// like `seen` and `roll` are left out, as they are too close to too many everyday words.
+const COMMAND_KEYWORDS: &[(&str, &str)] = &[
+    ("convert", "convert"),
+    ("weather", "weather"),
+    ("chatgpt", "chatgpt"),
+    ("history", "convert"),
+];
+
+// Commands that only work if configured, with the feature each belongs to, how the feature is
+// described, and the environment variables it needs.
+const CONFIGURED_COMMANDS: &[(&str, &str, &str, &[&str])] = &[
+    ("chatgpt", "chatgpt", "ChatGPT", &["CHATGPT_API_KEY"]),
+    ("detect", "chatgpt", "ChatGPT", &["CHATGPT_API_KEY"]),
+    ("tldr", "chatgpt", "ChatGPT", &["CHATGPT_API_KEY"]),
+    (
+        "convert",
+        "convert",
+        "Currency conversion",
+        &["XE_ACCOUNT_ID", "XE_API_KEY"],
+    ),
+    ("weather", "weather", "Weather", &["WEATHER_API_KEY"]),
+];
+
+// Real words that are close to a command, but are not typos of it.
+const NOT_TYPOS: &[&str] = &["whether", "content", "concert", "convent", "converts"];
+
+use regex::Regex;
+use std::env;
+
+use crate::audit;
+use crate::channel_config;
+use crate::channel_info;
+use crate::chatgpt;
+use crate::convert;
+use crate::dice;
+use crate::karma;
+use crate::seen;
+use crate::slack;
+use crate::stats;
+use crate::weather;
+
+// Process the message with each enabled feature. Returns a `(thread id, message)` for each reply
+// that should be posted.
+pub(crate) async fn process_message(message: &slack::Message) -> Vec<(String, String)> {
+    if is_ignored(message) {
+        return Vec::new();
+    }
+
+    if message.is_im() {
+        return process_direct_message(message).await;
+    }
+
+    // Slack also sends an app mention for any channel message mentioning the bot, which is
+    // processed instead, so karma and seen don't count the message twice.
+    if mentions_bot(&message.text) {
+        return Vec::new();
+    }
+
+    match env::var("TAG1BOT_PREFIX") {
+        Ok(prefix) if !prefix.is_empty() => process_prefixed(message, &prefix).await,
+        _ => process_command(message, false).await,
+    }
+}
+
+// If a command prefix is configured, only messages starting with it are commands. Karma and seen
+// still track every message.
+async fn process_prefixed(message: &slack::Message, prefix: &str) -> Vec<(String, String)> {
+    match message.text.trim_start().strip_prefix(prefix) {
+        Some(command) => process_command(&message.with_text(command.to_string()), true).await,
+        None => process_conversation(message).await,
+    }
+}
+
+// Process an app mention, which is always treated as a command.
+pub(crate) async fn process_mention(message: &slack::Message) -> Vec<(String, String)> {
+    if is_ignored(message) {
+        return Vec::new();
+    }
+    process_command(message, true).await
+}
+
+// Process a command run by reacting to a message, ie `chatgpt <message>`. Only the feature the
+// command belongs to processes it, so a reaction can't give karma or record the message as seen
+// again.
+pub(crate) async fn process_reaction(
+    message: &slack::Message,
+    command: &str,
+) -> Vec<(String, String)> {
+    if is_ignored(message) {
+        return Vec::new();
+    }
+    let feature = match command {
+        "chatgpt" | "detect" | "tldr" => "chatgpt",
+        "convert" => "convert",
+        "weather" => "weather",
+        _ => {
+            log::warn!("reactions can't run `{}`", command);
+            return Vec::new();
+        }
+    };
+    let channel_id = message.channel_id.as_str();
+    if !channel_config::is_enabled(channel_id, feature) {
+        return Vec::new();
+    }
+    if let Some((_, label, missing)) =
+        missing_config(&message.text, true, |name| env::var(name).is_ok())
+    {
+        log::warn!("{} requested, but {} not set", label, missing.join(" and "));
+        return Vec::new();
+    }
+    audit::record(message);
+
+    let reply = match feature {
+        "chatgpt" => chatgpt::process_message(message).await,
+        "convert" => convert::process_message(message).await,
+        _ => weather::process_message(message).await,
+    };
+    reply
+        .map(|reply| vec![limit_reply(feature, reply)])
+        .unwrap_or_default()
+}
+
+// Process a direct message to the bot. Every direct message is a command, with or without the
+// command prefix, and anything that isn't a command is sent to ChatGPT.
+async fn process_direct_message(message: &slack::Message) -> Vec<(String, String)> {
+    let command = match env::var("TAG1BOT_PREFIX") {
+        Ok(prefix) if !prefix.is_empty() => message.text.trim_start().strip_prefix(prefix.as_str()),
+        _ => None,
+    };
+    let message = message.with_text(command.unwrap_or(&message.text).to_string());
+
+    let mut replies = process_command(&message, true).await;
+    if replies.is_empty()
+        && env::var("CHATGPT_API_KEY").is_ok()
+        && channel_config::is_enabled(&message.channel_id, "chatgpt")
+    {
+        let chatgpt_message = message.with_text(format!("chatgpt {}", message.text.trim()));
+        if let Some(reply) = chatgpt::process_message(&chatgpt_message).await {
+            replies.push(limit_reply("chatgpt", reply));
+        }
+    }
+    replies
+}
+
+// Truncate a feature's reply to the longest it may post.
+fn limit_reply(feature: &str, reply: (String, String)) -> (String, String) {
+    let (reply_thread_ts, reply_message) = reply;
+    let max_length = slack::max_reply_length(feature);
+    (
+        reply_thread_ts,
+        slack::truncate_reply(&reply_message, max_length),
+    )
+}
+
+// Determine if the message should not be processed at all.
+fn is_ignored(message: &slack::Message) -> bool {
+    // Never process the bot's own messages, so it can't respond to itself.
+    if is_from_bot(message) {
+        return true;
+    }
+    match message.subtype.as_deref() {
+        Some(subtype) => IGNORED_SUBTYPES.contains(&subtype),
+        None => false,
+    }
+}
+
+// Process a message that isn't a command, only for karma and to record it as seen.
+async fn process_conversation(message: &slack::Message) -> Vec<(String, String)> {
+    let mut replies = Vec::new();
+    let channel_id = message.channel_id.as_str();
+
+    // Process the message for karma changes, `karma` commands need the prefix.
+    if channel_config::is_enabled(channel_id, "karma") {
+        if let Some(reply) = karma::process_operations(message).await {
+            replies.push(limit_reply("karma", reply));
+        }
+    }
+    // Record the message for seen.
+    if channel_config::is_enabled(channel_id, "seen") {
+        seen::record(message).await;
+    }
+
+    replies
+}
+
+// Process a command with each enabled feature. The message is `addressed` to the bot if it
+// mentioned the bot, started with the command prefix, or was sent directly to the bot.
+async fn process_command(message: &slack::Message, addressed: bool) -> Vec<(String, String)> {
+    let mut replies = Vec::new();
+    audit::record(message);
+
+    // Process the message for enabling and disabling features in this channel.
+    if let Some(reply) = channel_config::process_message(message).await {
+        replies.push(reply);
+    }
+    // Process the message for stats.
+    if let Some(reply) = stats::process_message(message).await {
+        replies.push(reply);
+    }
+    // Process the message for an admin reviewing the audit log.
+    if let Some(reply) = audit::process_message(message).await {
+        replies.push(reply);
+    }
+    // Process the message for details about the channel.
+    if let Some(reply) = channel_info::process_message(message).await {
+        replies.push(reply);
+    }
+    let channel_id = message.channel_id.as_str();
+
+    // Process the message for karma. Karma isn't given privately in direct messages.
+    if !message.is_im() && channel_config::is_enabled(channel_id, "karma") {
+        if let Some(reply) = karma::process_message(message).await {
+            replies.push(limit_reply("karma", reply));
+        }
+    }
+    // Process the message for seen.
+    if channel_config::is_enabled(channel_id, "seen") {
+        if let Some(reply) = seen::process_message(message).await {
+            replies.push(limit_reply("seen", reply));
+        }
+    }
+    // Process the message for coin flips and dice rolls.
+    if channel_config::is_enabled(channel_id, "dice") {
+        if let Some(reply) = dice::process_message(message).await {
+            replies.push(limit_reply("dice", reply));
+        }
+    }
+    // If enabled, process the message for convert.
+    if env::var("XE_ACCOUNT_ID").is_ok()
+        && env::var("XE_API_KEY").is_ok()
+        && channel_config::is_enabled(channel_id, "convert")
+    {
+        if let Some(reply) = convert::process_message(message).await {
+            replies.push(limit_reply("convert", reply));
+        }
+    }
+    // If enabled, process the message for weather.
+    if env::var("WEATHER_API_KEY").is_ok() && channel_config::is_enabled(channel_id, "weather") {
+        if let Some(reply) = weather::process_message(message).await {
+            replies.push(limit_reply("weather", reply));
+        }
+    }
+    // If enabled, process the message for ChatGPT.
+    if env::var("CHATGPT_API_KEY").is_ok() && channel_config::is_enabled(channel_id, "chatgpt") {
+        if let Some(reply) = chatgpt::process_message(message).await {
+            replies.push(limit_reply("chatgpt", reply));
+        }
+    }
+
+    // If nothing matched, check if this is a command for a feature that isn't configured, rather
+    // than silently ignoring it.
+    if replies.is_empty() {
+        if let Some((feature, label, missing)) =
+            missing_config(&message.text, addressed, |name| env::var(name).is_ok())
+        {
+            if channel_config::is_enabled(channel_id, feature) {
+                let missing = missing.join(" and ");
+                log::warn!("{} requested, but {} not set", label, missing);
+                let reply_message = format!("{} is unavailable: {} not set.", label, missing);
+                if let Some(reply) = slack::reply_ephemeral(message, reply_message).await {
+                    replies.push(reply);
+                }
+                return replies;
+            }
+        }
+    }
+
+    // If nothing matched, check if this looks like a misspelled command.
+    if replies.is_empty() {
+        if let Some(command) = did_you_mean(&message.text, addressed) {
+            if channel_config::is_enabled(channel_id, command.1) {
+                let reply_message = format!("Did you mean `{}`?", command.0);
+                if let Some(reply) = slack::reply_ephemeral(message, reply_message).await {
+                    replies.push(reply);
+                }
+            }
+        }
+    }
+
+    replies
+}
+
+// Determine if the message is a command, followed by arguments, for a feature that needs
+// configuration that `is_set` says is missing. Returns the feature, how it's described, and the
+// missing environment variables.
+fn missing_config(
+    text: &str,
+    addressed: bool,
+    is_set: impl Fn(&str) -> bool,
+) -> Option<(&'static str, &'static str, Vec<&'static str>)> {
+    let mut words = text.split_whitespace();
+    let first_word = words.next()?.to_lowercase();
+    // Commands always have arguments.
+    words.next()?;
+
+    let (command, feature, label, names) = CONFIGURED_COMMANDS
+        .iter()
+        .find(|(command, ..)| *command == first_word)?;
+    // Unless the bot was addressed, only answer messages that really are the command, not
+    // conversation like `convert the docs to markdown`. Anything can follow `weather`, so it's
+    // only answered when addressed.
+    if !addressed && (*feature == "weather" || !is_command(command, text.trim())) {
+        return None;
+    }
+    let missing: Vec<&'static str> = names.iter().copied().filter(|name| !is_set(name)).collect();
+    if missing.is_empty() {
+        None
+    } else {
+        Some((feature, label, missing))
+    }
+}
+
+// Determine if the text is a valid use of `command`, as the feature it belongs to would parse it.
+fn is_command(command: &str, trimmed_text: &str) -> bool {
+    match command {
+        "chatgpt" | "detect" | "tldr" => chatgpt::is_command(trimmed_text),
+        "convert" | "history" => convert::is_command(trimmed_text),
+        "weather" => weather::is_command(trimmed_text),
+        _ => false,
+    }
+}
+
+// Determine if the message starts with a misspelled command, alone or followed by arguments the
+// command accepts, for example `covnert 1 usd to eur`. Only messages addressed to the bot are
+// checked, as everyday words are often close to a command. Returns the command and the feature it
+// belongs to.
+fn did_you_mean(text: &str, addressed: bool) -> Option<(&'static str, &'static str)> {
+    if !addressed {
+        return None;
+    }
+    let text = text.trim();
+    let (first_word, arguments) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let first_word = first_word.to_lowercase();
+    if NOT_TYPOS.contains(&first_word.as_str()) {
+        return None;
+    }
+
+    // A typo is a single wrong, missing, extra or swapped letter, ie `wether` or `covnert`.
+    let (keyword, feature) = COMMAND_KEYWORDS.iter().copied().find(|(keyword, _)| {
+        edit_distance(&first_word, keyword) == 1
+            && first_word.chars().next() == keyword.chars().next()
+    })?;
+    let arguments = arguments.trim();
+    if arguments.is_empty() || is_command(keyword, &format!("{} {}", keyword, arguments)) {
+        Some((keyword, feature))
+    } else {
+        None
+    }
+}
+
+// The number of single character insertions, deletions, substitutions or swaps of adjacent
+// characters to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = distances[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = substitution
+                .min(distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+// Strip the bot's mention from the start of an app mention, leaving any command that follows it.
+pub(crate) fn strip_mention(text: &str) -> String {
+    let re = Regex::new(REGEX_LEADING_MENTION).expect("failed to compile REGEX_LEADING_MENTION");
+    match re.captures(text) {
+        // If we don't know who we are, assume a leading mention is of the bot.
+        Some(cap) if slack::bot_user_id().is_none_or(|bot_user_id| bot_user_id == &cap[1]) => {
+            text[cap[0].len()..].trim().to_string()
+        }
+        _ => text.trim().to_string(),
+    }
+}
+
+// Determine if the text mentions the bot, ie `<@U123> foo++` or `thanks <@U123|tag1bot>`.
+fn mentions_bot(text: &str) -> bool {
+    match slack::bot_user_id() {
+        Some(bot_user_id) => {
+            text.contains(&format!("<@{}>", bot_user_id))
+                || text.contains(&format!("<@{}|", bot_user_id))
+        }
+        None => false,
+    }
+}
+
+// Determine if the message was posted by the bot itself.
+fn is_from_bot(message: &slack::Message) -> bool {
+    match (message.user_id.as_deref(), slack::bot_user_id()) {
+        (Some(user_id), Some(bot_user_id)) => user_id == bot_user_id,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const BOT_USER_ID: &str = "UTAG1BOT";
+
+    #[tokio::test]
+    async fn ignores_own_messages() {
+        slack::set_bot_user_id(BOT_USER_ID);
+        for text in [
+            "foo++",
+            "seen foo?",
+            "karma rank foo",
+            "convert 1 usd to eur",
+        ] {
+            let message = slack::Message::new(
+                "C123".to_string(),
+                Some(BOT_USER_ID.to_string()),
+                text.to_string(),
+                None,
+                "1700000000.000100".to_string(),
+                None,
+            );
+            assert!(process_message(&message).await.is_empty(), "{}", text);
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_edits_and_deletions() {
+        for subtype in IGNORED_SUBTYPES {
+            let message = slack::Message::new(
+                "C123".to_string(),
+                Some("U123".to_string()),
+                "foo++".to_string(),
+                None,
+                "1700000000.000100".to_string(),
+                Some(subtype.to_string()),
+            );
+            assert!(process_message(&message).await.is_empty(), "{}", subtype);
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_messages_skip_karma() {
+        let message = slack::Message::new(
+            "D123".to_string(),
+            Some("U123".to_string()),
+            "foo++".to_string(),
+            None,
+            "1700000000.000100".to_string(),
+            None,
+        );
+        assert!(message.is_im());
+        assert!(process_message(&message).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pipeline_karma() {
+        let replies = testing::replies(&testing::message("pipeline++")).await;
+        assert_eq!(replies.len(), 1, "{:?}", replies);
+        let (reply_thread_ts, reply_message) = &replies[0];
+        assert_eq!(reply_thread_ts, "1700000000.000100");
+        assert!(reply_message.contains("pipeline"), "{}", reply_message);
+    }
+
+    #[tokio::test]
+    async fn pipeline_convert() {
+        let replies = testing::replies(&testing::message("convert 1 usd to eur")).await;
+        assert_eq!(
+            replies,
+            vec![(
+                "1700000000.000100".to_string(),
+                "1 USD is currently 0.92 EUR.".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn pipeline_mention_counts_karma_once() {
+        slack::set_bot_user_id(BOT_USER_ID);
+        testing::setup();
+        let before = karma::rank("mentioned")
+            .unwrap()
+            .map_or(0, |(_, karma, _)| karma);
+
+        // Slack sends both a message and an app mention for the same post.
+        let text = format!("<@{}> mentioned++", BOT_USER_ID);
+        assert!(testing::replies(&testing::message(&text)).await.is_empty());
+        let replies = testing::mention_replies(&testing::message(&text)).await;
+        assert_eq!(replies.len(), 1, "{:?}", replies);
+
+        let after = karma::rank("mentioned")
+            .unwrap()
+            .map_or(0, |(_, karma, _)| karma);
+        assert_eq!(after, before + 1);
+    }
+
+    #[tokio::test]
+    async fn pipeline_reaction_skips_karma() {
+        testing::setup();
+        let message = testing::message("chatgpt thanks reacted++");
+        assert!(process_reaction(&message, "chatgpt").await.is_empty());
+        assert!(process_reaction(&message, "karma").await.is_empty());
+        assert_eq!(karma::rank("reacted").unwrap(), None);
+
+        let message = testing::message("convert 1 usd to eur");
+        assert_eq!(
+            process_reaction(&message, "convert").await,
+            vec![(
+                "1700000000.000100".to_string(),
+                "1 USD is currently 0.92 EUR.".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn pipeline_prefix_only_allows_karma_changes() {
+        testing::setup();
+        for text in [
+            "karma rank prefixed",
+            "karma givers",
+            "karma chart prefixed",
+        ] {
+            let replies = process_prefixed(&testing::message(text), "!").await;
+            assert!(replies.is_empty(), "{}: {:?}", text, replies);
+        }
+        let replies = process_prefixed(&testing::message("prefixed++"), "!").await;
+        assert_eq!(replies.len(), 1, "{:?}", replies);
+        let replies = process_prefixed(&testing::message("!karma rank prefixed"), "!").await;
+        assert_eq!(replies.len(), 1, "{:?}", replies);
+    }
+
+    #[tokio::test]
+    async fn pipeline_ignores_conversation() {
+        let replies = testing::replies(&testing::message("nothing to see here")).await;
+        assert!(replies.is_empty(), "{:?}", replies);
+    }
+
+    #[test]
+    fn reports_missing_config() {
+        let nothing_set = |_: &str| false;
+        assert_eq!(
+            missing_config("chatgpt what is rust?", false, nothing_set),
+            Some(("chatgpt", "ChatGPT", vec!["CHATGPT_API_KEY"]))
+        );
+        assert_eq!(
+            missing_config("Convert 1 usd to eur", false, |name: &str| name
+                == "XE_API_KEY"),
+            Some(("convert", "Currency conversion", vec!["XE_ACCOUNT_ID"]))
+        );
+        assert_eq!(
+            missing_config("weather paris", true, nothing_set),
+            Some(("weather", "Weather", vec!["WEATHER_API_KEY"]))
+        );
+        // Configured features, commands without arguments and conversation are left alone.
+        assert_eq!(missing_config("weather paris", true, |_: &str| true), None);
+        assert_eq!(missing_config("weather", true, nothing_set), None);
+        assert_eq!(
+            missing_config("the weather is nice", true, nothing_set),
+            None
+        );
+        // Unless the bot was addressed, only real commands are answered.
+        assert_eq!(
+            missing_config("Convert the docs to markdown", false, nothing_set),
+            None
+        );
+        assert_eq!(
+            missing_config("weather is nice today", false, nothing_set),
+            None
+        );
+        assert_eq!(missing_config("weather paris", false, nothing_set), None);
+        assert_eq!(
+            missing_config("tldr of the meeting?", false, nothing_set),
+            None
+        );
+        assert_eq!(
+            missing_config("Convert the docs to markdown", true, nothing_set),
+            Some((
+                "convert",
+                "Currency conversion",
+                vec!["XE_ACCOUNT_ID", "XE_API_KEY"]
+            ))
+        );
+    }
+
+    #[test]
+    fn suggests_misspelled_commands() {
+        assert_eq!(
+            did_you_mean("covnert 1 usd to eur", true),
+            Some(("convert", "convert"))
+        );
+        assert_eq!(
+            did_you_mean("wether paris", true),
+            Some(("weather", "weather"))
+        );
+        assert_eq!(
+            did_you_mean("chatpgt hello", true),
+            Some(("chatgpt", "chatgpt"))
+        );
+        assert_eq!(did_you_mean("covnert", true), Some(("convert", "convert")));
+        assert_eq!(
+            did_you_mean("histroy btc usd", true),
+            Some(("history", "convert"))
+        );
+        // Correct commands, and everyday words, are left alone.
+        assert_eq!(did_you_mean("convert 1 usd to eur", true), None);
+        assert_eq!(did_you_mean("whether or not", true), None);
+        assert_eq!(did_you_mean("content is king", true), None);
+        assert_eq!(did_you_mean("hello there", true), None);
+        for text in [
+            "convey my thanks",
+            "weaker than expected",
+            "weathered the storm",
+            "historic day",
+            "convene at noon",
+            "convey",
+            "convene",
+        ] {
+            assert_eq!(did_you_mean(text, true), None, "{}", text);
+        }
+        // The arguments must be what the command accepts.
+        assert_eq!(did_you_mean("covnert the docs to markdown", true), None);
+        assert_eq!(did_you_mean("histroy of rome", true), None);
+        // Messages not addressed to the bot are never checked.
+        assert_eq!(did_you_mean("covnert 1 usd to eur", false), None);
+        assert_eq!(did_you_mean("covnert", false), None);
+    }
+
+    #[test]
+    fn strips_leading_bot_mention() {
+        slack::set_bot_user_id(BOT_USER_ID);
+        assert_eq!(strip_mention("<@UTAG1BOT> seen foo?"), "seen foo?");
+        assert_eq!(
+            strip_mention("<@UTAG1BOT>: convert 1 usd to eur"),
+            "convert 1 usd to eur"
+        );
+        assert_eq!(strip_mention("<@UTAG1BOT|tag1bot>, roll 2d6"), "roll 2d6");
+        assert_eq!(strip_mention("<@UTAG1BOT>"), "");
+        // Only the bot's own mention is stripped.
+        assert_eq!(strip_mention("<@U123> foo++"), "<@U123> foo++");
+    }
+
+    #[test]
+    fn detects_bot_mentions() {
+        slack::set_bot_user_id(BOT_USER_ID);
+        assert!(mentions_bot("<@UTAG1BOT> foo++"));
+        assert!(mentions_bot("thanks <@UTAG1BOT|tag1bot>"));
+        assert!(!mentions_bot("<@U123> foo++"));
+        assert!(!mentions_bot("<@UTAG1BOTX> foo++"));
+    }
+}
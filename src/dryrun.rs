@@ -0,0 +1,42 @@
+// Reads messages from stdin and prints the bot's replies, for trying out commands without
+// connecting to Slack. Enabled with `TAG1BOT_DRYRUN`.
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::dispatch;
+use crate::slack;
+use crate::util;
+
+// Messages are said by this user in this channel. Neither exists, so lookups of them fail.
+const DRYRUN_USER_ID: &str = "UDRYRUN";
+const DRYRUN_CHANNEL_ID: &str = "CDRYRUN";
+
+// Process each line read from stdin as a message, until stdin is closed.
+pub(crate) async fn run() {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                log::error!("failed to read from stdin: {}", e);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let message = slack::Message::new(
+            DRYRUN_CHANNEL_ID.to_string(),
+            Some(DRYRUN_USER_ID.to_string()),
+            slack::unescape_text(&line),
+            None,
+            format!("{}.000000", util::timestamp_now()),
+            None,
+        );
+        for (_, reply_message) in dispatch::process_message(&message).await {
+            println!("{}", reply_message);
+        }
+    }
+}
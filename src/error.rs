@@ -0,0 +1,34 @@
+// Errors returned by helpers that talk to Slack, other APIs and the database, so callers can tell
+// what went wrong, for example to only retry errors that may pass. Replies shown to users are
+// built separately by each feature, and never come from these errors.
+
+use crate::db::DbError;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    // Slack answered with an error, ie `channel_not_found`.
+    #[error("slack error: {0}")]
+    Slack(String),
+    // A request failed to send, or its response couldn't be read.
+    #[error("http error: {0}")]
+    Http(String),
+    // A database query failed.
+    #[error(transparent)]
+    Db(#[from] DbError),
+    // An AI provider failed.
+    #[error("provider error: {0}")]
+    Provider(String),
+    // A response or value couldn't be parsed.
+    #[error("parse error: {0}")]
+    Parse(String),
+    // Required configuration is missing or invalid.
+    #[error("configuration error: {0}")]
+    Config(String),
+}
+
+// surf's errors don't implement `std::error::Error`, so they're kept as text.
+impl From<surf::Error> for Error {
+    fn from(e: surf::Error) -> Self {
+        Error::Http(e.to_string())
+    }
+}
@@ -0,0 +1,105 @@
+// Serves a small health endpoint for liveness probes, reporting uptime, how many messages have
+// been processed, when the last Slack event was received, and whether the database is reachable.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::db;
+use crate::util;
+
+// Counters updated as events are received from Slack.
+static MESSAGES_PROCESSED: AtomicU64 = AtomicU64::new(0);
+static LAST_EVENT: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    // When the bot started.
+    static ref STARTED: u64 = util::timestamp_now();
+}
+
+// Record when the bot started.
+pub(crate) fn start() {
+    lazy_static::initialize(&STARTED);
+}
+
+// When the bot started.
+pub(crate) fn started() -> u64 {
+    *STARTED
+}
+
+// How many messages have been processed since the bot started.
+pub(crate) fn messages_processed() -> u64 {
+    MESSAGES_PROCESSED.load(Ordering::Relaxed)
+}
+
+// Record that an event was received from Slack.
+pub(crate) fn record_event() {
+    LAST_EVENT.store(util::timestamp_now(), Ordering::Relaxed);
+}
+
+// Record that a message was processed.
+pub(crate) fn record_message() {
+    MESSAGES_PROCESSED.fetch_add(1, Ordering::Relaxed);
+}
+
+// Listen for health checks on `port` until the bot exits.
+pub(crate) async fn serve(port: u16) {
+    let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("failed to bind health endpoint to port {}: {}", port, e);
+            return;
+        }
+    };
+    log::warn!("Serving health endpoint on port {}", port);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream).await {
+                        log::warn!("failed to respond to health check: {}", e);
+                    }
+                });
+            }
+            Err(e) => log::error!("failed to accept health check connection: {}", e),
+        }
+    }
+}
+
+// Respond to any request with the current health.
+async fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    // The request itself doesn't matter, but read it so the client sees a complete exchange.
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request).await?;
+
+    let body = health().to_string();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Build the health report.
+fn health() -> serde_json::Value {
+    let last_event = LAST_EVENT.load(Ordering::Relaxed);
+    serde_json::json!({
+        "uptime_seconds": util::timestamp_now().saturating_sub(started()),
+        "messages_processed": messages_processed(),
+        "last_event": if last_event == 0 { None } else { Some(last_event) },
+        "database_reachable": database_reachable(),
+    })
+}
+
+// Determine if the database can be queried.
+fn database_reachable() -> bool {
+    match db::get_conn() {
+        Ok(db) => db
+            .query_row("SELECT 1", [], |row| row.get::<_, i32>(0))
+            .is_ok(),
+        Err(_) => false,
+    }
+}
@@ -1,133 +1,1183 @@
 // Tracks keyword karma.
-// For example, `foo++` or `bar--`.
+// For example, `foo++` or `bar--`, or `karma rank foo` to see where a word stands and
+// `karma chart foo` to see how its karma has changed. Admins can
+// combine duplicates with `karma merge k8s kubernetes`, or moderate with `karma delete foo` and
+// `karma reset foo`.
 
 use regex::{Regex, RegexSet};
-use rusqlite::params;
+use rusqlite::{params, OptionalExtension};
+use std::env;
+use std::time::Duration;
 
-use crate::db::DB;
+use crate::db::{self, DbError};
 use crate::slack;
+use crate::stats;
+use crate::util;
 
 const REGEX_KARMA_WORD: &str = r#"^(?:@|#)??(\w{2,20})(?:\s)*(\+\+|\-\-)$"#;
 const REGEX_KARMA_MENTION: &str = r#"^<@(\w{5,15})>(?:\s)*(\+\+|\-\-)$"#;
+const REGEX_KARMA_SUBTEAM: &str =
+    r#"^<!subteam\^(\w{5,15})(?:\|@?([^>]{1,40}))?>(?:\s)*(\+\+|\-\-)$"#;
+const REGEX_KARMA_EMOJI: &str = r#"^(:[\w+\-]{1,40}:)(?:\s)*(\+\+|\-\-)$"#;
+// Code quoted in a message, ie `i++` or a block of code.
+const REGEX_CODE: &str = r#"```[\s\S]*?```|`[^`]*`"#;
+const REGEX_KARMA_RANK: &str = r#"(?i)^karma rank (?:@|#)?(\w{2,20}|:[\w+\-]{1,40}:)(?:\?)?$"#;
+const REGEX_KARMA_TOP: &str = r#"(?i)^karma (givers|takers)(?:\?)?$"#;
+const REGEX_KARMA_CHART: &str =
+    r#"(?i)^karma chart (?:@|#)?(\w{2,20}|:[\w+\-]{1,40}:)(?: (\d{1,3})(?: ?days?)?)?$"#;
+const REGEX_KARMA_MERGE: &str = r#"(?i)^karma merge (?:@|#)?(\w{2,20}|:[\w+\-]{1,40}:)(?:\s)+(?:into\s+)?(?:@|#)?(\w{2,20}|:[\w+\-]{1,40}:)$"#;
+const REGEX_KARMA_MODERATE: &str =
+    r#"(?i)^karma (delete|reset) (?:@|#)?(\w{2,20}|:[\w+\-]{1,40}:)$"#;
+
+// How many givers or takers to list.
+const KARMA_TOP_LIMIT: u32 = 10;
+
+// How many days `karma chart` covers, unless asked for more or fewer.
+const KARMA_CHART_DAYS: u64 = 14;
+const KARMA_CHART_MAX_DAYS: u64 = 90;
+
+// How many risers and fallers the weekly digest lists.
+const KARMA_DIGEST_LIMIT: u32 = 5;
+
+const WEEK_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+// From lowest to highest.
+const SPARKLINE_BLOCKS: &[char] = &['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
 
 // Determine if Karma is being modified in this message. Returns `Some(thread id, message)` if karma
 // is modified, returns `None` if not,
 pub(crate) async fn process_message(message: &slack::Message) -> Option<(String, String)> {
     let trimmed_text = message.text.trim();
+
+    // Check if someone is asking `karma rank <foo>`.
+    if let Some(reply_message) = karma_rank(trimmed_text) {
+        return Some((slack::reply_thread_ts(message), reply_message));
+    }
+
+    // Check if someone is asking `karma givers` or `karma takers`.
+    if let Some(reply_message) = karma_top(trimmed_text) {
+        return Some((slack::reply_thread_ts(message), reply_message));
+    }
+
+    // Check if someone is asking `karma chart <foo>`.
+    if let Some(reply_message) = karma_chart(trimmed_text) {
+        return Some((slack::reply_thread_ts(message), reply_message));
+    }
+
+    // Check if an admin is asking `karma merge <source> <target>`, `karma delete <foo>` or
+    // `karma reset <foo>`.
     let set =
-        RegexSet::new(&[REGEX_KARMA_MENTION, REGEX_KARMA_WORD]).expect("failed to build RegexSet");
+        RegexSet::new([REGEX_KARMA_MERGE, REGEX_KARMA_MODERATE]).expect("failed to build RegexSet");
     if set.is_match(trimmed_text) {
-        // Always reply in a thread: determine if reply is in a new thread or an existing thread.
-        let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
-            thread_ts.clone()
-        } else {
-            message.ts.clone()
+        // Only workspace admins can moderate karma.
+        if !slack::message_is_authorized(message, slack::AuthLevel::Admin).await {
+            return slack::reply_unauthorized(message, slack::AuthLevel::Admin).await;
+        }
+        let reply_message = karma_merge(trimmed_text).or_else(|| karma_moderate(trimmed_text))?;
+        return Some((slack::reply_thread_ts(message), reply_message));
+    }
+
+    process_operations(message).await
+}
+
+// Change karma for any `foo++` or `bar--` in this message, without answering any `karma`
+// commands. Returns `Some(thread id, message)` if karma is modified, returns `None` if not.
+pub(crate) async fn process_operations(message: &slack::Message) -> Option<(String, String)> {
+    // Any number of words can be changed at once, ie `foo++ bar++ baz--`.
+    let operations = parse_karma_operations(message.text.trim(), case_sensitive());
+    if operations.is_empty() {
+        return None;
+    }
+
+    let mut changed: Vec<String> = Vec::new();
+    let mut replies = Vec::new();
+    let mut errors = Vec::new();
+    let mut any_penalized = false;
+    let mut reactions = Vec::new();
+    for (target, adjustment) in operations {
+        let word = match resolve_target(target).await {
+            Some(word) => word,
+            None => continue,
         };
-        let matches: Vec<_> = set.matches(trimmed_text).into_iter().collect();
+        // Each word only changes once per message.
+        if changed.contains(&word) {
+            continue;
+        }
+        changed.push(word.clone());
+
+        match change_karma(message, &word, &adjustment).await {
+            Ok((reply_message, penalized)) => {
+                any_penalized |= penalized;
+                let emoji = if adjustment == "++" && !penalized {
+                    "thumbsup"
+                } else {
+                    "thumbsdown"
+                };
+                if !reactions.contains(&emoji) {
+                    reactions.push(emoji);
+                }
+                replies.push(reply_message);
+            }
+            Err(e) => {
+                log::error!("failed to update karma for {}: {}", word, e);
+                errors.push(format!(
+                    "Sorry, I failed to update karma for {}.",
+                    display(&word)
+                ));
+            }
+        }
+    }
+
+    // Errors are only of interest to the user that changed karma.
+    if !errors.is_empty() {
+        let error_reply = slack::reply_ephemeral(message, errors.join("\n")).await;
+        if replies.is_empty() {
+            return error_reply;
+        }
+    }
+    if replies.is_empty() {
+        return None;
+    }
+
+    // Optionally acknowledge karma changes with a reaction instead of a reply.
+    if !any_penalized && env::var("KARMA_REACT_ONLY").is_ok() {
+        for emoji in reactions {
+            slack::add_reaction(&message.channel_id, &message.ts, emoji).await;
+        }
+        return None;
+    }
+
+    Some((slack::reply_thread_ts(message), replies.join("\n")))
+}
+
+// Resolve what a karma change applies to into the word whose karma changes.
+async fn resolve_target(target: KarmaTarget) -> Option<String> {
+    match target {
+        KarmaTarget::Word(word) => Some(word),
         // Matched @MENTION, convert user_id to name (word).
-        let (word, adjustment) = if matches[0] == 0 {
-            let re =
-                Regex::new(REGEX_KARMA_MENTION).expect("failed to compile REGEX_KARMA_MENTION");
-            let cap = re
-                .captures(trimmed_text)
-                .expect("failed to capture REGEX_KARMA_MENTION");
-            let word = match slack::users_info(&cap[1]).await {
-                Ok(u) => u.name.to_lowercase(),
+        KarmaTarget::User(user_id) => match slack::users_info(&user_id).await {
+            Ok(u) => Some(u.name.to_lowercase()),
+            Err(e) => {
+                log::error!("failed to load mentioned user: {}", e);
+                None
+            }
+        },
+        // Matched user group, convert subteam id to the group's handle (word).
+        KarmaTarget::UserGroup(usergroup_id, handle) => {
+            match slack::usergroups_info(&usergroup_id).await {
+                Ok(usergroup) => Some(usergroup.handle.to_lowercase()),
                 Err(e) => {
-                    println!("unexpected error: {}", e);
-                    return None;
+                    log::error!("failed to load mentioned user group: {}", e);
+                    // Fall back to the handle included in the mention, if any.
+                    handle
                 }
-            };
-            let adjustment = cap[2].to_string();
-            (word, adjustment)
-        // Matched WORD.
+            }
+        }
+    }
+}
+
+// Change the karma of `word` by `adjustment`, `++` or `--`, returning the reply and whether the
+// user was penalized.
+async fn change_karma(
+    message: &slack::Message,
+    word: &str,
+    adjustment: &str,
+) -> Result<(String, bool), DbError> {
+    // Users are penalized for incrementing their own karma.
+    let penalized = if adjustment == "++" {
+        match message.user().await {
+            Some(user) => user.name.eq_ignore_ascii_case(word),
+            // Bot message.
+            None => false,
+        }
+    } else {
+        false
+    };
+
+    let reply_message = if adjustment == "++" && !penalized {
+        increment(word)
+            .map(|karma| format!("Karma for {} increased to {}.", display(word), karma))?
+    } else if penalized {
+        decrement(word).map(|karma| format!("Karma cannot be incremented for yourself, you have been penalized: Karma for {} decreased to {}.", display(word), karma))?
+    } else {
+        decrement(word)
+            .map(|karma| format!("Karma for {} decreased to {}.", display(word), karma))?
+    };
+    stats::record(stats::Stat::Karma);
+
+    // Log who gave the karma, if not a bot.
+    if let Some(user) = message.user().await {
+        let adjustment = if adjustment == "++" && !penalized {
+            1
         } else {
-            let re = Regex::new(REGEX_KARMA_WORD).expect("failed to compile REGEX_KARMA_WORD");
-            let cap = re
-                .captures(trimmed_text)
-                .expect("failed to capture REGEX_KARMA_WORD");
-            let word = cap[1].to_lowercase();
-            let adjustment = cap[2].to_string();
-            (word, adjustment)
+            -1
         };
+        if let Err(e) = log_karma(&user.name.to_lowercase(), word, adjustment) {
+            log::error!("failed to log karma for {}: {}", word, e);
+        }
+    }
+
+    Ok((reply_message, penalized))
+}
 
-        let reply_message = if adjustment == "++" {
-            // Normal user message.
-            if let Some(user) = message.user.as_ref() {
-                if user.name.to_lowercase() != word {
-                    let karma = increment(&word);
-                    format!("Karma for `{}` increased to {}.", word, karma)
+// What a karma change applies to, before mentions are resolved to names.
+#[derive(Debug, PartialEq)]
+enum KarmaTarget {
+    // A word, ie `foo++`.
+    Word(String),
+    // A user mention, ie `<@U123>++`.
+    User(String),
+    // A user group mention, with the handle if included, ie `<!subteam^S123|@frontend>++`.
+    UserGroup(String, Option<String>),
+}
+
+// Determine if karma is being modified, returning what is being modified and the adjustment,
+// `++` or `--`.
+fn parse_karma(trimmed_text: &str, case_sensitive: bool) -> Option<(KarmaTarget, String)> {
+    let set = RegexSet::new([
+        REGEX_KARMA_MENTION,
+        REGEX_KARMA_WORD,
+        REGEX_KARMA_SUBTEAM,
+        REGEX_KARMA_EMOJI,
+    ])
+    .expect("failed to build RegexSet");
+    let matched = set.matches(trimmed_text).into_iter().next()?;
+    if matched == 0 {
+        let re = Regex::new(REGEX_KARMA_MENTION).expect("failed to compile REGEX_KARMA_MENTION");
+        let cap = re.captures(trimmed_text)?;
+        Some((KarmaTarget::User(cap[1].to_string()), cap[2].to_string()))
+    } else if matched == 2 {
+        let re = Regex::new(REGEX_KARMA_SUBTEAM).expect("failed to compile REGEX_KARMA_SUBTEAM");
+        let cap = re.captures(trimmed_text)?;
+        let handle = cap.get(2).map(|handle| handle.as_str().to_lowercase());
+        Some((
+            KarmaTarget::UserGroup(cap[1].to_string(), handle),
+            cap[3].to_string(),
+        ))
+    } else if matched == 3 {
+        let re = Regex::new(REGEX_KARMA_EMOJI).expect("failed to compile REGEX_KARMA_EMOJI");
+        let cap = re.captures(trimmed_text)?;
+        Some((
+            KarmaTarget::Word(normalize(&cap[1], case_sensitive)),
+            cap[2].to_string(),
+        ))
+    } else {
+        let re = Regex::new(REGEX_KARMA_WORD).expect("failed to compile REGEX_KARMA_WORD");
+        let cap = re.captures(trimmed_text)?;
+        Some((
+            KarmaTarget::Word(normalize(&cap[1], case_sensitive)),
+            cap[2].to_string(),
+        ))
+    }
+}
+
+// Find all karma being modified in a message, ie `foo++ bar++ baz--` or `thanks nginx++ for
+// staying up`. Karma changes are whitespace separated words, optionally followed by punctuation.
+// Anything quoted as code is ignored.
+fn parse_karma_operations(trimmed_text: &str, case_sensitive: bool) -> Vec<(KarmaTarget, String)> {
+    if let Some(operation) = parse_karma(trimmed_text, case_sensitive) {
+        return vec![operation];
+    }
+
+    let re = Regex::new(REGEX_CODE).expect("failed to compile REGEX_CODE");
+    let text = re.replace_all(trimmed_text, " ");
+
+    // A message that only changes karma can put a space before each adjustment, ie `foo ++ bar --`.
+    if let Some(operations) = parse_karma_list(&text, case_sensitive) {
+        return operations;
+    }
+
+    // Within a sentence the adjustment must be attached to the word, ie `thanks foo++`, otherwise
+    // a dash like `we shipped it -- finally` would take karma from `it`.
+    let mut operations = Vec::new();
+    for token in text.split_whitespace() {
+        // Ignore punctuation around the change, ie `(foo++)` or `foo++!`.
+        let token = token
+            .trim_start_matches('(')
+            .trim_end_matches(|c| ".,!?;)".contains(c));
+        // Most words aren't karma changes, so skip them quickly.
+        if !token.ends_with("++") && !token.ends_with("--") {
+            continue;
+        }
+        if let Some(operation) = parse_karma(token, case_sensitive) {
+            operations.push(operation);
+        }
+    }
+    operations
+}
+
+// Parse a message made up entirely of karma changes, allowing a space before each adjustment.
+// Returns `None` if anything in the message isn't a karma change.
+fn parse_karma_list(text: &str, case_sensitive: bool) -> Option<Vec<(KarmaTarget, String)>> {
+    let mut operations = Vec::new();
+    let mut tokens = text.split_whitespace().peekable();
+    while let Some(token) = tokens.next() {
+        let token = match tokens.peek() {
+            Some(&next) if next == "++" || next == "--" => {
+                tokens.next();
+                format!("{}{}", token, next)
+            }
+            _ => token.to_string(),
+        };
+        operations.push(parse_karma(&token, case_sensitive)?);
+    }
+    Some(operations)
+}
+
+// Karma is case insensitive unless `KARMA_CASE_SENSITIVE` is set, for example to keep acronyms
+// apart from words.
+fn case_sensitive() -> bool {
+    env::var("KARMA_CASE_SENSITIVE").is_ok()
+}
+
+// Normalize a word before its karma is looked up or changed. Words are lower case unless karma
+// is case sensitive. Emoji, ie `:rocket:`, are always lower case as Slack emoji names are.
+fn normalize(word: &str, case_sensitive: bool) -> String {
+    if case_sensitive && !is_emoji(word) {
+        word.to_string()
+    } else {
+        word.to_lowercase()
+    }
+}
+
+// Determine if a word is an emoji, ie `:rocket:`.
+fn is_emoji(word: &str) -> bool {
+    word.len() > 2 && word.starts_with(':') && word.ends_with(':')
+}
+
+// Format a word for a reply: emoji are shown as the emoji, other words as code.
+fn display(word: &str) -> String {
+    if is_emoji(word) {
+        word.to_string()
+    } else {
+        format!("`{}`", word)
+    }
+}
+
+// Determine if this is a request for the rank of a word, and if so build the reply.
+fn karma_rank(trimmed_text: &str) -> Option<String> {
+    let re = Regex::new(REGEX_KARMA_RANK).expect("failed to compile REGEX_KARMA_RANK");
+    let cap = re.captures(trimmed_text)?;
+    let word = normalize(&cap[1], case_sensitive());
+
+    let reply_message = match rank(&word) {
+        Ok(Some((rank, karma, total))) => format!(
+            "{} is ranked #{} with {} karma (of {} tracked).",
+            display(&word),
+            rank,
+            karma,
+            total
+        ),
+        Ok(None) => format!("{} doesn't have any karma yet.", display(&word)),
+        Err(e) => {
+            log::error!("failed to rank karma for {}: {}", word, e);
+            format!("Sorry, I failed to look up karma for `{}`.", word)
+        }
+    };
+    Some(reply_message)
+}
+
+// Determine if this is a request for who gives or receives the most karma, and if so build the
+// reply.
+fn karma_top(trimmed_text: &str) -> Option<String> {
+    let re = Regex::new(REGEX_KARMA_TOP).expect("failed to compile REGEX_KARMA_TOP");
+    let cap = re.captures(trimmed_text)?;
+    let givers = cap[1].eq_ignore_ascii_case("givers");
+
+    let top = if givers {
+        top_givers(KARMA_TOP_LIMIT)
+    } else {
+        top_takers(KARMA_TOP_LIMIT)
+    };
+    let reply_message = match top {
+        Ok(top) if top.is_empty() => "Nobody has given any karma yet.".to_string(),
+        Ok(top) => {
+            let lines: Vec<String> = top
+                .iter()
+                .enumerate()
+                .map(|(index, (name, count))| {
+                    format!("{}. {} ({})", index + 1, display(name), count)
+                })
+                .collect();
+            format!(
+                "{}\n{}",
+                if givers {
+                    "Most karma given:"
                 } else {
-                    let karma = decrement(&word);
-                    format!("Karma cannot be incremented for yourself, you have been penalized: Karma for `{}` decreased to {}.", word, karma)
-                }
-            // Bot message.
-            } else {
-                let karma = increment(&word);
-                format!("Karma for `{}` increased to {}.", word, karma)
+                    "Most karma received:"
+                },
+                lines.join("\n")
+            )
+        }
+        Err(e) => {
+            log::error!("failed to load karma {}: {}", &cap[1], e);
+            "Sorry, I failed to look up karma.".to_string()
+        }
+    };
+    Some(reply_message)
+}
+
+// Determine if this is a request for a chart of a word's karma, and if so build the reply.
+fn karma_chart(trimmed_text: &str) -> Option<String> {
+    let re = Regex::new(REGEX_KARMA_CHART).expect("failed to compile REGEX_KARMA_CHART");
+    let cap = re.captures(trimmed_text)?;
+    let word = normalize(&cap[1], case_sensitive());
+    let days = cap
+        .get(2)
+        .and_then(|days| days.as_str().parse::<u64>().ok())
+        .unwrap_or(KARMA_CHART_DAYS);
+    // A chart needs at least two days to show a change.
+    if !(2..=KARMA_CHART_MAX_DAYS).contains(&days) {
+        return Some(format!(
+            "Sorry, a chart covers 2 to {} days.",
+            KARMA_CHART_MAX_DAYS
+        ));
+    }
+
+    // Days start at midnight UTC, the last day is today.
+    let now = util::timestamp_now();
+    let start = (now - now % 86400) - (days - 1) * 86400;
+
+    let history = rank(&word).and_then(|rank| Ok((rank, karma_changes(&word, start)?)));
+    let reply_message = match history {
+        Ok((_, changes)) if changes.is_empty() => match karma_changes(&word, 0) {
+            Ok(changes) if !changes.is_empty() => format!(
+                "No karma changes for {} in the last {} days.",
+                display(&word),
+                days
+            ),
+            _ => format!("No karma history for {} yet.", display(&word)),
+        },
+        Ok((rank, changes)) => {
+            let karma = rank.map_or(0, |(_, karma, _)| karma);
+            let totals = running_totals(karma, &daily_changes(&changes, start, days));
+            format!(
+                "Karma for {} over the last {} days: {} ({} → {})",
+                display(&word),
+                days,
+                sparkline(&totals),
+                totals[0],
+                karma
+            )
+        }
+        Err(e) => {
+            log::error!("failed to chart karma for {}: {}", word, e);
+            format!("Sorry, I failed to look up karma for `{}`.", word)
+        }
+    };
+    Some(reply_message)
+}
+
+// Once a week, at midnight UTC at the start of Monday, post the words whose karma rose and fell
+// the most in the past week to `channel_id`. Started if `KARMA_DIGEST_ENABLED` is set.
+pub(crate) async fn digest_thread(channel_id: String) {
+    loop {
+        let sleep_seconds = seconds_until_digest(util::timestamp_now());
+        log::info!("karma digest thread sleeping {} seconds", sleep_seconds);
+        tokio::time::sleep(Duration::from_secs(sleep_seconds)).await;
+        // Don't post during quiet hours.
+        util::wait_for_quiet_hours().await;
+
+        let since = util::timestamp_now().saturating_sub(WEEK_SECONDS);
+        let (risers, fallers) = match (
+            weekly_changes(since, true, KARMA_DIGEST_LIMIT),
+            weekly_changes(since, false, KARMA_DIGEST_LIMIT),
+        ) {
+            (Ok(risers), Ok(fallers)) => (risers, fallers),
+            (Err(e), _) | (_, Err(e)) => {
+                log::error!("failed to load weekly karma changes: {}", e);
+                continue;
             }
-        } else {
-            let karma = decrement(&word);
-            format!("Karma for `{}` decreased to {}.", word, karma)
         };
+        if risers.is_empty() && fallers.is_empty() {
+            continue;
+        }
+        slack::post_text(&channel_id, &format_digest(&risers, &fallers)).await;
+    }
+}
+
+// Seconds until the next Monday at midnight UTC. The epoch was a Thursday.
+fn seconds_until_digest(now: u64) -> u64 {
+    let since_monday = (now + 3 * 24 * 60 * 60) % WEEK_SECONDS;
+    WEEK_SECONDS - since_monday
+}
+
+fn format_digest(risers: &[(String, i32)], fallers: &[(String, i32)]) -> String {
+    let mut lines = vec!["*Karma this week*".to_string()];
+    if !risers.is_empty() {
+        lines.push("Biggest risers:".to_string());
+        for (index, (name, change)) in risers.iter().enumerate() {
+            lines.push(format!("{}. {} (+{})", index + 1, display(name), change));
+        }
+    }
+    if !fallers.is_empty() {
+        lines.push("Biggest fallers:".to_string());
+        for (index, (name, change)) in fallers.iter().enumerate() {
+            lines.push(format!("{}. {} ({})", index + 1, display(name), change));
+        }
+    }
+    lines.join("\n")
+}
+
+// Sum karma changes, `(timestamp, adjustment)`, per day for `days` days starting at `start`.
+fn daily_changes(changes: &[(u64, i32)], start: u64, days: u64) -> Vec<i32> {
+    let mut daily = vec![0; days as usize];
+    for (ts, adjustment) in changes {
+        if *ts < start {
+            continue;
+        }
+        let day = ((ts - start) / 86400).min(days - 1) as usize;
+        daily[day] += adjustment;
+    }
+    daily
+}
+
+// Work back from the current karma to what it was at the end of each day.
+fn running_totals(karma: i32, daily_changes: &[i32]) -> Vec<i32> {
+    let mut totals = vec![0; daily_changes.len()];
+    let mut total = karma;
+    for (day, change) in daily_changes.iter().enumerate().rev() {
+        totals[day] = total;
+        total -= change;
+    }
+    totals
+}
+
+// Draw values as a line of blocks, scaled from the lowest to the highest value.
+fn sparkline(values: &[i32]) -> String {
+    let min = values.iter().copied().min().unwrap_or(0);
+    let max = values.iter().copied().max().unwrap_or(0);
+    let range = (max - min) as usize;
+    values
+        .iter()
+        .map(|value| {
+            // All values are the same if there's no range, so draw them in the middle.
+            let block = ((value - min) as usize * (SPARKLINE_BLOCKS.len() - 1))
+                .checked_div(range)
+                .unwrap_or(SPARKLINE_BLOCKS.len() / 2);
+            SPARKLINE_BLOCKS[block]
+        })
+        .collect()
+}
+
+// Determine if this is a request to merge the karma of one word into another, and if so merge
+// them and build the reply.
+fn karma_merge(trimmed_text: &str) -> Option<String> {
+    let re = Regex::new(REGEX_KARMA_MERGE).expect("failed to compile REGEX_KARMA_MERGE");
+    let cap = re.captures(trimmed_text)?;
+    let source = normalize(&cap[1], case_sensitive());
+    let target = normalize(&cap[2], case_sensitive());
+
+    if source == target {
+        return Some(format!("{} can't be merged into itself.", display(&source)));
+    }
+    let reply_message = match merge(&source, &target) {
+        Ok(Some(karma)) => format!(
+            "Merged {} into {}, which now has {} karma.",
+            display(&source),
+            display(&target),
+            karma
+        ),
+        Ok(None) => format!("{} doesn't have any karma to merge.", display(&source)),
+        Err(e) => {
+            log::error!("failed to merge karma of {} into {}: {}", source, target, e);
+            format!(
+                "Sorry, I failed to merge {} into {}.",
+                display(&source),
+                display(&target)
+            )
+        }
+    };
+    Some(reply_message)
+}
+
+// Determine if this is a request to delete or reset the karma of a word, and if so do it and
+// build the reply.
+fn karma_moderate(trimmed_text: &str) -> Option<String> {
+    let re = Regex::new(REGEX_KARMA_MODERATE).expect("failed to compile REGEX_KARMA_MODERATE");
+    let cap = re.captures(trimmed_text)?;
+    let delete = cap[1].eq_ignore_ascii_case("delete");
+    let word = normalize(&cap[2], case_sensitive());
+
+    let result = if delete { remove(&word) } else { reset(&word) };
+    let reply_message = match result {
+        Ok(false) => format!("{} doesn't have any karma.", display(&word)),
+        Ok(true) if delete => format!("Deleted {} and all of its karma.", display(&word)),
+        Ok(true) => format!("Reset the karma of {} to 0.", display(&word)),
+        Err(e) => {
+            log::error!("failed to {} karma for {}: {}", &cap[1], word, e);
+            format!(
+                "Sorry, I failed to {} {}.",
+                cap[1].to_lowercase(),
+                display(&word)
+            )
+        }
+    };
+    Some(reply_message)
+}
+
+// Get the users who have given the most karma, with how many times they've given it.
+fn top_givers(limit: u32) -> Result<Vec<(String, u32)>, DbError> {
+    let db = db::get_conn()?;
+    let mut statement = db.prepare(
+        "SELECT giver, COUNT(*) AS given FROM karma_log WHERE adjustment > 0 GROUP BY giver ORDER BY given DESC, giver LIMIT ?1",
+    )?;
+    let giver_iterator =
+        statement.query_map(params![limit], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    let mut givers = Vec::new();
+    for giver in giver_iterator {
+        givers.push(giver?);
+    }
+    Ok(givers)
+}
+
+// Get the words that have received the most karma, with how many times they've received it.
+fn top_takers(limit: u32) -> Result<Vec<(String, u32)>, DbError> {
+    let db = db::get_conn()?;
+    let mut statement = db.prepare(
+        "SELECT name, COUNT(*) AS received FROM karma_log WHERE adjustment > 0 GROUP BY name ORDER BY received DESC, name LIMIT ?1",
+    )?;
+    let taker_iterator =
+        statement.query_map(params![limit], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    let mut takers = Vec::new();
+    for taker in taker_iterator {
+        takers.push(taker?);
+    }
+    Ok(takers)
+}
+
+// Get the words whose karma rose the most since `since` if `risers`, or otherwise fell the most,
+// with their net change.
+fn weekly_changes(since: u64, risers: bool, limit: u32) -> Result<Vec<(String, i32)>, DbError> {
+    let db = db::get_conn()?;
+    let query = if risers {
+        "SELECT name, SUM(adjustment) AS change FROM karma_log WHERE ts >= ?1 GROUP BY name HAVING change > 0 ORDER BY change DESC, name LIMIT ?2"
+    } else {
+        "SELECT name, SUM(adjustment) AS change FROM karma_log WHERE ts >= ?1 GROUP BY name HAVING change < 0 ORDER BY change ASC, name LIMIT ?2"
+    };
+    let mut statement = db.prepare(query)?;
+    let change_iterator =
+        statement.query_map(params![since, limit], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    let mut changes = Vec::new();
+    for change in change_iterator {
+        changes.push(change?);
+    }
+    Ok(changes)
+}
+
+// Get every logged change to the karma of `text` since `since`, as `(timestamp, adjustment)`.
+fn karma_changes(text: &str, since: u64) -> Result<Vec<(u64, i32)>, DbError> {
+    let db = db::get_conn()?;
+    let mut statement = db
+        .prepare("SELECT ts, adjustment FROM karma_log WHERE name = ?1 AND ts >= ?2 ORDER BY ts")?;
+    let change_iterator =
+        statement.query_map(params![text, since], |row| Ok((row.get(0)?, row.get(1)?)))?;
 
-        return Some((reply_thread_ts, reply_message));
+    let mut changes = Vec::new();
+    for change in change_iterator {
+        changes.push(change?);
     }
-    None
+    Ok(changes)
+}
+
+// Record who changed the karma of `text`.
+fn log_karma(giver: &str, text: &str, adjustment: i32) -> Result<(), DbError> {
+    let db = db::get_conn()?;
+    db.execute(
+        "INSERT INTO karma_log (giver, name, adjustment, ts) VALUES(?1, ?2, ?3, ?4)",
+        params![giver, text, adjustment, util::timestamp_now()],
+    )?;
+    Ok(())
+}
+
+// Get the 1-based rank of `text` by karma, along with its karma and the total number of words
+// tracked. Returns `None` if `text` has no karma.
+pub(crate) fn rank(text: &str) -> Result<Option<(u32, i32, u32)>, DbError> {
+    let db = db::get_conn()?;
+    let karma: Option<i32> = db
+        .query_row(
+            "SELECT counter FROM karma WHERE name = ?1",
+            params![text],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    if let Some(karma) = karma {
+        let rank = db.query_row(
+            "SELECT COUNT(*) + 1 FROM karma WHERE counter > ?1",
+            params![karma],
+            |row| row.get(0),
+        )?;
+        let total = db.query_row("SELECT COUNT(*) FROM karma", [], |row| row.get(0))?;
+        Ok(Some((rank, karma, total)))
+    } else {
+        Ok(None)
+    }
+}
+
+// Add the karma of `source` to `target` and delete `source`, crediting `target` with everything
+// logged for `source`. Returns the combined karma, or `None` if `source` has no karma.
+pub(crate) fn merge(source: &str, target: &str) -> Result<Option<i32>, DbError> {
+    let mut db = db::get_conn()?;
+    let tx = db.transaction()?;
+    let karma: Option<i32> = tx.query_row(
+        "SELECT SUM(counter) FROM karma WHERE name = ?1",
+        params![source],
+        |row| row.get(0),
+    )?;
+    let karma = match karma {
+        Some(karma) => karma,
+        None => return Ok(None),
+    };
+
+    tx.execute(
+        "UPDATE karma SET counter = counter + ?2 WHERE name = ?1",
+        params![target, karma],
+    )?;
+    tx.execute(
+        "INSERT INTO karma (name, counter) SELECT ?1, ?2 WHERE (Select Changes() = 0)",
+        params![target, karma],
+    )?;
+    tx.execute("DELETE FROM karma WHERE name = ?1", params![source])?;
+    tx.execute(
+        "UPDATE karma_log SET name = ?2 WHERE name = ?1",
+        params![source, target],
+    )?;
+    let counter = tx.query_row(
+        "SELECT counter FROM karma WHERE name = ?1",
+        params![target],
+        |row| row.get(0),
+    )?;
+    tx.commit()?;
+
+    Ok(Some(counter))
+}
+
+// Delete `text` and everything logged for it. Returns `false` if `text` has no karma.
+pub(crate) fn remove(text: &str) -> Result<bool, DbError> {
+    let mut db = db::get_conn()?;
+    let tx = db.transaction()?;
+    let deleted = tx.execute("DELETE FROM karma WHERE name = ?1", params![text])?;
+    tx.execute("DELETE FROM karma_log WHERE name = ?1", params![text])?;
+    tx.commit()?;
+
+    Ok(deleted > 0)
+}
+
+// Reset the karma of `text` to 0, forgetting everything logged for it so it starts over. Returns
+// `false` if `text` has no karma.
+pub(crate) fn reset(text: &str) -> Result<bool, DbError> {
+    let mut db = db::get_conn()?;
+    let tx = db.transaction()?;
+    let updated = tx.execute(
+        "UPDATE karma SET counter = 0 WHERE name = ?1",
+        params![text],
+    )?;
+    tx.execute("DELETE FROM karma_log WHERE name = ?1", params![text])?;
+    tx.commit()?;
+
+    Ok(updated > 0)
 }
 
 // Increment karma by 1 for given `text`.
-pub(crate) fn increment(text: &str) -> i32 {
-    let db = DB.lock().unwrap_or_else(|_| panic!("DB mutex poisoned!"));
+pub(crate) fn increment(text: &str) -> Result<i32, DbError> {
+    let db = db::get_conn()?;
     db.execute(
         "UPDATE karma SET counter = counter + 1 WHERE name = ?1",
         params![text],
-    )
-    .expect("failed to increment karma");
+    )?;
     db.execute(
         "INSERT INTO karma (name, counter) SELECT ?1, 1 WHERE (Select Changes() = 0)",
         params![text],
-    )
-    .expect("failed to increment karma");
-    let mut statement = db
-        .prepare("SELECT counter FROM karma WHERE name = :name")
-        .expect("failed to prepare SELECT");
-    let rows = statement
-        .query_map(&[(":name", text)], |row| row.get(0))
-        .expect("failed to SELECT");
-
-    let mut values: Vec<i32> = Vec::new();
-    for value_result in rows {
-        values.push(value_result.expect("failed to extract result"));
-    }
+    )?;
+    let counter = db.query_row(
+        "SELECT counter FROM karma WHERE name = ?1",
+        params![text],
+        |row| row.get(0),
+    )?;
 
-    values[0]
+    Ok(counter)
 }
 
 // Decrement karma by 1 for given `text`.
-pub(crate) fn decrement(text: &str) -> i32 {
-    let db = DB.lock().unwrap_or_else(|_| panic!("DB mutex poisoned!"));
+pub(crate) fn decrement(text: &str) -> Result<i32, DbError> {
+    let db = db::get_conn()?;
     db.execute(
         "UPDATE karma SET counter = counter - 1 WHERE name = ?1",
         params![text],
-    )
-    .expect("failed to decrement karma");
+    )?;
     db.execute(
         "INSERT INTO karma (name, counter) SELECT ?1, -1 WHERE (Select Changes() = 0)",
         params![text],
-    )
-    .expect("failed to decrement karma");
-    let mut statement = db
-        .prepare("SELECT counter FROM karma WHERE name = :name")
-        .expect("failed to prepare SELECT");
-    let rows = statement
-        .query_map(&[(":name", text)], |row| row.get(0))
-        .expect("failed to SELECT");
+    )?;
+    let counter = db.query_row(
+        "SELECT counter FROM karma WHERE name = ?1",
+        params![text],
+        |row| row.get(0),
+    )?;
 
-    let mut values: Vec<i32> = Vec::new();
-    for value_result in rows {
-        values.push(value_result.expect("failed to extract result"));
+    Ok(counter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn parse_karma_insensitive(trimmed_text: &str) -> Option<(KarmaTarget, String)> {
+        parse_karma(trimmed_text, false)
+    }
+
+    fn word(text: &str) -> Option<(KarmaTarget, String)> {
+        Some((KarmaTarget::Word(text.to_string()), "++".to_string()))
+    }
+
+    #[test]
+    fn parse_karma_words() {
+        assert_eq!(parse_karma_insensitive("foo++"), word("foo"));
+        assert_eq!(parse_karma_insensitive("foo ++"), word("foo"));
+        assert_eq!(parse_karma_insensitive("foo   ++"), word("foo"));
+        assert_eq!(parse_karma_insensitive("Foo++"), word("foo"));
+        assert_eq!(parse_karma_insensitive("@foo++"), word("foo"));
+        assert_eq!(parse_karma_insensitive("#channel++"), word("channel"));
+        assert_eq!(parse_karma_insensitive("ab++"), word("ab"));
+        assert_eq!(parse_karma_insensitive("foo_bar++"), word("foo_bar"));
+        assert_eq!(
+            parse_karma_insensitive("foo--"),
+            Some((KarmaTarget::Word("foo".to_string()), "--".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_karma_rejects_non_karma() {
+        // Too short.
+        assert_eq!(parse_karma_insensitive("a++"), None);
+        // Too long.
+        assert_eq!(
+            parse_karma_insensitive(&format!("{}++", "a".repeat(21))),
+            None
+        );
+        assert_eq!(parse_karma_insensitive("foo+++"), None);
+        assert_eq!(parse_karma_insensitive("foo+-"), None);
+        assert_eq!(parse_karma_insensitive("foo bar++"), None);
+        assert_eq!(parse_karma_insensitive("i++ in a loop"), None);
+        assert_eq!(parse_karma_insensitive("c++"), None);
+        assert_eq!(parse_karma_insensitive("foo"), None);
+    }
+
+    #[test]
+    fn parse_karma_mentions() {
+        assert_eq!(
+            parse_karma_insensitive("<@U12345>++"),
+            Some((KarmaTarget::User("U12345".to_string()), "++".to_string()))
+        );
+        assert_eq!(
+            parse_karma_insensitive("<@U12345> --"),
+            Some((KarmaTarget::User("U12345".to_string()), "--".to_string()))
+        );
+        assert_eq!(
+            parse_karma_insensitive("<!subteam^S12345|@frontend>++"),
+            Some((
+                KarmaTarget::UserGroup("S12345".to_string(), Some("frontend".to_string())),
+                "++".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_karma_insensitive("<!subteam^S12345>++"),
+            Some((
+                KarmaTarget::UserGroup("S12345".to_string(), None),
+                "++".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_karma_multiple_operations() {
+        let word = |text: &str, adjustment: &str| {
+            (KarmaTarget::Word(text.to_string()), adjustment.to_string())
+        };
+        assert_eq!(
+            parse_karma_operations("foo++ bar++ baz--", false),
+            vec![word("foo", "++"), word("bar", "++"), word("baz", "--")]
+        );
+        assert_eq!(
+            parse_karma_operations("foo++\nbar --\n<@U12345>++", false),
+            vec![
+                word("foo", "++"),
+                word("bar", "--"),
+                (KarmaTarget::User("U12345".to_string()), "++".to_string())
+            ]
+        );
+        assert_eq!(
+            parse_karma_operations("foo ++", false),
+            vec![word("foo", "++")]
+        );
+        assert_eq!(
+            parse_karma_operations("foo++ a++", false),
+            vec![word("foo", "++")]
+        );
+        assert_eq!(
+            parse_karma_operations("++ foo++", false),
+            vec![word("foo", "++")]
+        );
     }
 
-    values[0]
+    #[test]
+    fn parse_karma_inline() {
+        let word = |text: &str, adjustment: &str| {
+            (KarmaTarget::Word(text.to_string()), adjustment.to_string())
+        };
+        assert_eq!(
+            parse_karma_operations("thanks nginx++ for staying up", false),
+            vec![word("nginx", "++")]
+        );
+        assert_eq!(
+            parse_karma_operations("great work foo++!", false),
+            vec![word("foo", "++")]
+        );
+        assert_eq!(
+            parse_karma_operations("(foo++), bar--.", false),
+            vec![word("foo", "++"), word("bar", "--")]
+        );
+        assert_eq!(
+            parse_karma_operations("shoutout to <@U12345>++ today", false),
+            vec![(KarmaTarget::User("U12345".to_string()), "++".to_string())]
+        );
+        // The language, and single letters, are too short to be words.
+        assert_eq!(parse_karma_operations("I write C++ for fun", false), vec![]);
+        assert_eq!(parse_karma_operations("a-- b", false), vec![]);
+        assert_eq!(parse_karma_operations("x ++ y", false), vec![]);
+        // Operators and code aren't karma.
+        assert_eq!(parse_karma_operations("foo+++ bar+-", false), vec![]);
+        assert_eq!(parse_karma_operations("try `count++` here", false), vec![]);
+        assert_eq!(
+            parse_karma_operations("```\nfor i in 0..n { count++ }\n``` thanks foo++", false),
+            vec![word("foo", "++")]
+        );
+        assert_eq!(parse_karma_operations("foo ++bar", false), vec![]);
+    }
+
+    #[test]
+    fn parse_karma_ignores_prose() {
+        assert_eq!(parse_karma_operations("C++ is hard", false), vec![]);
+        assert_eq!(parse_karma_operations("it -- no", false), vec![]);
+        assert_eq!(parse_karma_operations("use `x++` in a loop", false), vec![]);
+        assert_eq!(
+            parse_karma_operations("use `count++` in a loop", false),
+            vec![]
+        );
+        assert_eq!(parse_karma_operations("i++ in a loop", false), vec![]);
+        assert_eq!(
+            parse_karma_operations("see the C++ and C-- docs", false),
+            vec![]
+        );
+        assert_eq!(
+            parse_karma_operations("foo ++ is an operator", false),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn parse_karma_ignores_dashes() {
+        // A spaced adjustment is only karma when the message is nothing but karma changes.
+        assert_eq!(
+            parse_karma_operations("we shipped it -- finally", false),
+            vec![]
+        );
+        assert_eq!(
+            parse_karma_operations("the build failed -- again", false),
+            vec![]
+        );
+        assert_eq!(parse_karma_operations("foo -- bar", false), vec![]);
+        assert_eq!(
+            parse_karma_operations("shoutout to <@U12345> ++ today", false),
+            vec![]
+        );
+        assert_eq!(parse_karma_operations("deploy is done --", false), vec![]);
+        assert_eq!(
+            parse_karma_operations("foo -- bar --", false),
+            vec![
+                (KarmaTarget::Word("foo".to_string()), "--".to_string()),
+                (KarmaTarget::Word("bar".to_string()), "--".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_karma_emoji() {
+        assert_eq!(parse_karma_insensitive(":rocket:++"), word(":rocket:"));
+        assert_eq!(parse_karma_insensitive(":Rocket: ++"), word(":rocket:"));
+        assert_eq!(parse_karma(":Rocket:++", true), word(":rocket:"));
+        assert_eq!(parse_karma_insensitive(":+1:++"), word(":+1:"));
+        assert_eq!(
+            parse_karma_insensitive(":thumbs-down:++"),
+            word(":thumbs-down:")
+        );
+        assert_eq!(parse_karma_insensitive("::++"), None);
+        assert_eq!(parse_karma_insensitive(":rocket++"), None);
+        assert_eq!(display(":rocket:"), ":rocket:");
+        assert_eq!(display("rocket"), "`rocket`");
+    }
+
+    #[test]
+    fn parse_karma_acronyms() {
+        assert_eq!(parse_karma_insensitive("API++"), word("api"));
+        assert_eq!(parse_karma("API++", true), word("API"));
+        assert_eq!(parse_karma("@API++", true), word("API"));
+        assert_eq!(normalize("API", false), "api");
+        assert_eq!(normalize("API", true), "API");
+    }
+
+    #[test]
+    fn karma_chart_totals() {
+        let start = 1_700_006_400;
+        let changes = [
+            (start - 1, 5),
+            (start + 10, 1),
+            (start + 20, 1),
+            (start + 86400 * 2, -1),
+            (start + 86400 * 3 + 5, 1),
+        ];
+        let daily = daily_changes(&changes, start, 4);
+        assert_eq!(daily, vec![2, 0, -1, 1]);
+        assert_eq!(running_totals(10, &daily), vec![10, 10, 9, 10]);
+    }
+
+    #[test]
+    fn karma_digest_on_mondays() {
+        // Monday, November 13, 2023 at midnight UTC.
+        let monday = 1_699_833_600;
+        assert_eq!(seconds_until_digest(monday), WEEK_SECONDS);
+        assert_eq!(seconds_until_digest(monday + 1), WEEK_SECONDS - 1);
+        assert_eq!(seconds_until_digest(monday - 60), 60);
+    }
+
+    #[test]
+    fn karma_digest_format() {
+        let risers = vec![("rust".to_string(), 12), (":rocket:".to_string(), 3)];
+        let fallers = vec![("meetings".to_string(), -4)];
+        assert_eq!(
+            format_digest(&risers, &fallers),
+            "*Karma this week*\nBiggest risers:\n1. `rust` (+12)\n2. :rocket: (+3)\nBiggest fallers:\n1. `meetings` (-4)"
+        );
+        assert_eq!(
+            format_digest(&[], &fallers),
+            "*Karma this week*\nBiggest fallers:\n1. `meetings` (-4)"
+        );
+    }
+
+    #[test]
+    fn karma_chart_days() {
+        for text in [
+            "karma chart foo 1",
+            "karma chart foo 0 days",
+            "karma chart foo 91",
+        ] {
+            assert_eq!(
+                karma_chart(text),
+                Some("Sorry, a chart covers 2 to 90 days.".to_string()),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn karma_chart_sparkline() {
+        assert_eq!(sparkline(&[0, 1, 2, 3, 4, 5, 6, 7]), "▁▂▃▄▅▆▇█");
+        assert_eq!(sparkline(&[-5, 5]), "▁█");
+        assert_eq!(sparkline(&[3, 3, 3]), "▅▅▅");
+        assert_eq!(sparkline(&[]), "");
+    }
+
+    // Give `text` karma `times` times, logged as given by `giver`.
+    fn give(giver: &str, text: &str, times: u32) {
+        for _ in 0..times {
+            increment(text).unwrap();
+            log_karma(giver, text, 1).unwrap();
+        }
+    }
+
+    fn karma(text: &str) -> Option<i32> {
+        rank(text).unwrap().map(|(_, karma, _)| karma)
+    }
+
+    #[test]
+    fn karma_merge_into_existing_target() {
+        testing::setup();
+        give("alice", "mergesource", 3);
+        give("bob", "mergetarget", 1);
+        assert_eq!(merge("mergesource", "mergetarget").unwrap(), Some(4));
+        assert_eq!(karma("mergesource"), None);
+        assert_eq!(karma("mergetarget"), Some(4));
+        // Everything logged for the source is credited to the target.
+        assert!(karma_changes("mergesource", 0).unwrap().is_empty());
+        assert_eq!(karma_changes("mergetarget", 0).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn karma_merge_into_missing_target() {
+        testing::setup();
+        give("alice", "mergetypo", 2);
+        assert_eq!(karma("mergefixed"), None);
+        assert_eq!(merge("mergetypo", "mergefixed").unwrap(), Some(2));
+        assert_eq!(karma("mergetypo"), None);
+        assert_eq!(karma("mergefixed"), Some(2));
+        assert_eq!(karma_changes("mergefixed", 0).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn karma_merge_without_karma() {
+        testing::setup();
+        assert_eq!(merge("mergenothing", "mergeanything").unwrap(), None);
+        assert_eq!(karma("mergeanything"), None);
+        assert_eq!(
+            karma_merge("karma merge mergenothing into mergeanything"),
+            Some("`mergenothing` doesn't have any karma to merge.".to_string())
+        );
+    }
+
+    #[test]
+    fn karma_delete() {
+        testing::setup();
+        give("alice", "deleteme", 2);
+        assert_eq!(
+            karma_moderate("karma delete deleteme"),
+            Some("Deleted `deleteme` and all of its karma.".to_string())
+        );
+        assert_eq!(karma("deleteme"), None);
+        assert!(karma_changes("deleteme", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn karma_reset() {
+        testing::setup();
+        give("alice", "resetme", 3);
+        assert_eq!(
+            karma_moderate("karma reset resetme"),
+            Some("Reset the karma of `resetme` to 0.".to_string())
+        );
+        assert_eq!(karma("resetme"), Some(0));
+        // The chart starts over, rather than working back from 0 through the old changes.
+        assert!(karma_changes("resetme", 0).unwrap().is_empty());
+        assert_eq!(
+            karma_chart("karma chart resetme"),
+            Some("No karma history for `resetme` yet.".to_string())
+        );
+    }
+
+    #[test]
+    fn karma_moderate_unknown_word() {
+        testing::setup();
+        for text in ["karma delete neverseen", "karma reset neverseen"] {
+            assert_eq!(
+                karma_moderate(text),
+                Some("`neverseen` doesn't have any karma.".to_string())
+            );
+        }
+        assert_eq!(karma("neverseen"), None);
+    }
 }
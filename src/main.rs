@@ -1,28 +1,92 @@
 use async_trait::async_trait;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use slack_rust::chat::post_message::{post_message, PostMessageRequest};
+use regex::Regex;
 use slack_rust::event_api::event::{Event, EventCallbackType};
 use slack_rust::http_client::{default_client, SlackWebAPIClient};
 use slack_rust::socket::event::{EventsAPI, HelloEvent};
 use slack_rust::socket::socket_mode::{ack, EventHandler, SocketMode, Stream};
-use std::env;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::RwLock;
 
+mod audit;
+mod channel_config;
+mod channel_info;
 mod chatgpt;
 mod convert;
 mod db;
+mod dice;
+mod dispatch;
+mod dryrun;
+mod error;
+mod health;
 mod karma;
+mod reaction;
 mod seen;
 mod slack;
+mod slash;
+mod stats;
+#[cfg(test)]
+mod testing;
 mod util;
+mod weather;
 
 #[macro_use]
 extern crate lazy_static;
 
+// Greetings, one per line, optionally prefixed with a language code.
+const HELLOS_FILE: &str = "./data/hellos.txt";
+const REGEX_HELLO_LANGUAGE: &str = r"^([a-zA-Z]{2,3}):\s*(.+)$";
+
+// Built-in greetings, used if HELLOS_FILE is missing.
+const DEFAULT_HELLOS: &str = "Hi.
+Hey.
+Hello.
+What's up?
+es: Hola.
+es: Que tal.
+fr: Salut.
+fr: Bonjour.
+fr: Eh oh.
+zh: Niihau.
+ru: Privet.
+it: Ciao.
+it: Buongiorno.";
+
+lazy_static! {
+    static ref HELLOS: Vec<(Option<String>, String)> = load_hellos();
+    // Each event holds a read lock while being processed, so shutdown can wait for them to finish.
+    static ref IN_FLIGHT: RwLock<()> = RwLock::new(());
+}
+
+// How long to wait before reconnecting to Slack, doubling after each failed connection.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(5);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+// Connections that last at least this long reset the backoff.
+const RECONNECT_STABLE: Duration = Duration::from_secs(60);
+
+// Set if greetings were loaded from HELLOS_FILE rather than the built-in list.
+static HELLOS_FROM_FILE: AtomicBool = AtomicBool::new(false);
+
+// Set once a shutdown signal is received, after which no new events are processed.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
+    health::start();
+
+    // Try out commands without Slack, reading them from stdin.
+    if slack::is_dry_run() {
+        db::setup();
+        dryrun::run().await;
+        seen::flush().await;
+        db::shutdown();
+        return;
+    }
 
     let slack_app_token = env::var("SLACK_APP_TOKEN")
         .unwrap_or_else(|_| panic!("slack app token is not set (starts with 'xapp')."));
@@ -43,56 +107,271 @@ async fn main() {
     // Be sure all required tables and indexes exist.
     db::setup();
 
+    // Load greetings now, rather than on the first mention.
+    lazy_static::initialize(&HELLOS);
+
+    // Learn who we are, so we can recognize our own messages.
+    if let Err(e) = slack::resolve_bot_user_id().await {
+        log::error!("failed to resolve bot user id: {}", e);
+    }
+
+    log_startup_summary(&slack_channel_id);
+
+    // If configured, serve a health endpoint for liveness probes.
+    if let Ok(port) = env::var("HEALTH_PORT") {
+        match port.parse::<u16>() {
+            Ok(port) => {
+                tokio::spawn(async move {
+                    health::serve(port).await;
+                });
+            }
+            Err(e) => log::error!("invalid HEALTH_PORT {}: {}", port, e),
+        }
+    }
+
+    // If configured, serve slash commands sent by Slack over HTTP.
+    if let Ok(port) = env::var("SLASH_COMMAND_PORT") {
+        match (port.parse::<u16>(), env::var("SLACK_SIGNING_SECRET")) {
+            (Ok(port), Ok(signing_secret)) => {
+                tokio::spawn(async move {
+                    slash::serve(port, signing_secret).await;
+                });
+            }
+            (Err(e), _) => log::error!("invalid SLASH_COMMAND_PORT {}: {}", port, e),
+            (_, Err(_)) => {
+                log::error!("SLACK_SIGNING_SECRET is not set, disabling slash commands.")
+            }
+        }
+    }
+
+    // Regularly write who was seen, unless every message is written as it's seen.
+    if seen::debounce_seconds() > 0 {
+        tokio::spawn(async {
+            seen::flush_thread().await;
+        });
+    }
+
+    // If enabled, post a weekly digest of karma changes.
+    if env::var("KARMA_DIGEST_ENABLED").is_ok() {
+        let channel_id = slack_channel_id.clone();
+        tokio::spawn(async move {
+            karma::digest_thread(channel_id).await;
+        });
+    }
+
     // If currency conversions is enabled, start the alert thread.
     if enable_currency {
         tokio::spawn(async {
             convert::alert_thread().await;
         });
+
+        // If a watchlist is configured, also start the daily summary thread.
+        if let Ok(watchlist) = env::var("CONVERT_WATCHLIST") {
+            let watchlist = convert::parse_watchlist(&watchlist);
+            let channel_id = slack_channel_id.clone();
+            tokio::spawn(async move {
+                convert::watchlist_thread(channel_id, watchlist).await;
+            });
+        }
     }
 
-    // Restart if the bot crashes.
+    // Resolves once a shutdown signal is received and all in-flight events are processed.
+    let shutdown = shutdown();
+    tokio::pin!(shutdown);
+
+    // Restart if the bot crashes, until intentionally shut down.
+    let mut backoff = RECONNECT_BACKOFF_MIN;
     loop {
-        match SocketMode::new(
+        let connected_at = Instant::now();
+        let socket_mode = SocketMode::new(
             api_client.clone(),
             slack_app_token.clone(),
             slack_bot_token.clone(),
         )
-        .option_parameter("SLACK_CHANNEL_ID".to_string(), slack_channel_id.clone())
-        .run(&mut Handler)
-        .await
-        {
-            Ok(_) => log::warn!("Socket mode completed"),
-            Err(e) => log::warn!("Socket mode run error: {}", e),
+        .option_parameter("SLACK_CHANNEL_ID".to_string(), slack_channel_id.clone());
+        let mut handler = Handler;
+        tokio::select! {
+            result = socket_mode.run(&mut handler) => match result {
+                Ok(_) => log::warn!("Socket mode completed"),
+                Err(e) => log::warn!("Socket mode run error: {}", e),
+            },
+            _ = &mut shutdown => break,
+        }
+
+        // A connection that stayed up for a while was healthy, so start backing off again from
+        // the minimum.
+        if connected_at.elapsed() >= RECONNECT_STABLE {
+            backoff = RECONNECT_BACKOFF_MIN;
+        }
+
+        // Wait before reconnecting, doubling the wait after each failed connection.
+        log::warn!("Reconnecting in {} seconds", backoff.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = &mut shutdown => break,
+        }
+        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+
+    seen::flush().await;
+    db::shutdown();
+    log::warn!("Shut down cleanly.");
+}
+
+// Log which features are enabled and how the bot is configured. Only the presence of secrets is
+// logged, never their values.
+fn log_startup_summary(slack_channel_id: &str) {
+    let enabled = |names: &[&str]| {
+        let presence: Vec<String> = names
+            .iter()
+            .map(|name| {
+                let state = if env::var(name).is_ok() {
+                    "set"
+                } else {
+                    "not set"
+                };
+                format!("{} {}", name, state)
+            })
+            .collect();
+        let state = if names.iter().all(|name| env::var(name).is_ok()) {
+            "enabled"
+        } else {
+            "disabled"
         };
+        format!("{} ({})", state, presence.join(", "))
+    };
 
-        // Wait a few seconds before reconnecting.
-        tokio::time::sleep(Duration::from_secs(5)).await;
+    log::warn!("Startup summary:");
+    log::warn!(
+        "  bot user: {}",
+        slack::bot_user_id().unwrap_or("unresolved")
+    );
+    log::warn!("  channel: {}", slack_channel_id);
+    log::warn!("  database: {}", db::database_path().display());
+    log::warn!(
+        "  greetings: {} {}",
+        HELLOS.len(),
+        if HELLOS_FROM_FILE.load(Ordering::SeqCst) {
+            format!("loaded from {}", HELLOS_FILE)
+        } else {
+            "built-in".to_string()
+        }
+    );
+    log::warn!("  convert: {}", enabled(&["XE_ACCOUNT_ID", "XE_API_KEY"]));
+    log::warn!(
+        "  convert watchlist: {}",
+        env::var("CONVERT_WATCHLIST").map_or("not set".to_string(), |watchlist| format!(
+            "{} pairs",
+            convert::parse_watchlist(&watchlist).len()
+        ))
+    );
+    log::warn!("  karma digest: {}", enabled(&["KARMA_DIGEST_ENABLED"]));
+    log::warn!("  weather: {}", enabled(&["WEATHER_API_KEY"]));
+    log::warn!("  chatgpt: {}", enabled(&["CHATGPT_API_KEY"]));
+    log::warn!(
+        "  chatgpt system prompt: {}",
+        if chatgpt::system_prompt().is_some() {
+            "loaded"
+        } else {
+            "none"
+        }
+    );
+    log::warn!(
+        "  slash commands: {}",
+        env::var("SLASH_COMMAND_PORT")
+            .map_or("disabled".to_string(), |port| format!("port {}", port))
+    );
+    log::warn!(
+        "  health endpoint: {}",
+        env::var("HEALTH_PORT").map_or("disabled".to_string(), |port| format!("port {}", port))
+    );
+}
+
+// Wait for SIGINT or SIGTERM, then stop processing new events and wait for in-flight events to
+// finish.
+async fn shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::warn!("Received SIGINT, shutting down..."),
+        _ = terminate.recv() => log::warn!("Received SIGTERM, shutting down..."),
     }
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    let _in_flight = IN_FLIGHT.write().await;
 }
 
 pub struct Handler;
 
-fn hello_text() -> String {
-    let hellos = [
-        "Hi.",
-        "Hey.",
-        "Hola.",
-        "Hello.",
-        "Salut.",
-        "Eh oh.",
-        "Niihau.",
-        "Privet.",
-        "Bonjour.",
-        "Que tal.",
-        "What's up?",
-        "Ciao.",
-        "Buongiorno.",
-    ];
+// Choose a random greeting. If the mention itself is a known greeting, ie `@tag1bot hola`, prefer
+// a greeting in the same language.
+fn hello_text(mention_text: &str) -> String {
+    let hint = normalize_greeting(
+        &mention_text
+            .split_whitespace()
+            .filter(|word| !word.starts_with("<@"))
+            .collect::<Vec<_>>()
+            .join(" "),
+    );
+    let language = HELLOS
+        .iter()
+        .find(|(_, hello)| !hint.is_empty() && normalize_greeting(hello) == hint)
+        .and_then(|(language, _)| language.as_deref());
+
+    let hellos: Vec<&String> = HELLOS
+        .iter()
+        .filter(|(hello_language, _)| language.is_none() || hello_language.as_deref() == language)
+        .map(|(_, hello)| hello)
+        .collect();
     let mut rng = thread_rng();
 
     hellos.choose(&mut rng).expect("random failure").to_string()
 }
 
+// Load greetings from HELLOS_FILE, falling back to the built-in greetings if it's missing or empty.
+fn load_hellos() -> Vec<(Option<String>, String)> {
+    let hellos = match fs::read_to_string(HELLOS_FILE) {
+        Ok(hellos) => parse_hellos(&hellos),
+        Err(e) => {
+            log::warn!(
+                "failed to read {}, using built-in greetings: {}",
+                HELLOS_FILE,
+                e
+            );
+            Vec::new()
+        }
+    };
+    if hellos.is_empty() {
+        parse_hellos(DEFAULT_HELLOS)
+    } else {
+        HELLOS_FROM_FILE.store(true, Ordering::SeqCst);
+        hellos
+    }
+}
+
+// Parse one greeting per line, each optionally prefixed with a language code, ie `es: Hola.`.
+// Blank lines and lines starting with `#` are ignored.
+fn parse_hellos(hellos: &str) -> Vec<(Option<String>, String)> {
+    let re = Regex::new(REGEX_HELLO_LANGUAGE).expect("failed to compile REGEX_HELLO_LANGUAGE");
+    hellos
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match re.captures(line) {
+            Some(cap) => (Some(cap[1].to_lowercase()), cap[2].trim().to_string()),
+            None => (None, line.to_string()),
+        })
+        .collect()
+}
+
+// Compare greetings ignoring case and punctuation.
+fn normalize_greeting(greeting: &str) -> String {
+    greeting
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect::<String>()
+        .trim()
+        .to_lowercase()
+}
+
 #[async_trait]
 impl<S> EventHandler<S> for Handler
 where
@@ -108,38 +387,55 @@ where
         log::warn!("Connected: {:?}", event);
     }
 
-    async fn on_events_api(&mut self, socket_mode: &SocketMode<S>, e: EventsAPI, s: &mut Stream) {
+    async fn on_events_api(&mut self, _socket_mode: &SocketMode<S>, e: EventsAPI, s: &mut Stream) {
         log::info!("event: {:?}", e);
         ack(&e.envelope_id, s)
             .await
             .expect("socket mode ack error.");
+        health::record_event();
+
+        // Don't start processing new events while shutting down.
+        if SHUTTING_DOWN.load(Ordering::SeqCst) {
+            return;
+        }
+        let _in_flight = IN_FLIGHT.read().await;
 
         match e.payload {
             Event::EventCallback(event_callback) => match event_callback.event {
                 EventCallbackType::AppMention {
-                    //text,
+                    text,
                     channel,
                     ts,
                     thread_ts,
+                    user,
                     ..
                 } => {
-                    let (reply_thread_ts, reply_text) = if let Some(thread_ts) = thread_ts {
-                        (thread_ts, hello_text())
-                    } else {
-                        (ts, hello_text())
-                    };
-
-                    let request = PostMessageRequest {
+                    // Process any command following the mention, ie `@tag1bot seen foo?`, the
+                    // same as a plain message.
+                    let message = slack::Message::new(
                         channel,
-                        thread_ts: Some(reply_thread_ts),
-                        text: Some(reply_text),
-                        ..Default::default()
-                    };
-                    let response =
-                        post_message(&socket_mode.api_client, &request, &socket_mode.bot_token)
-                            .await
-                            .expect("post message api error.");
-                    log::info!("post message api response: {:?}", response);
+                        Some(user),
+                        dispatch::strip_mention(&slack::unescape_text(&text)),
+                        thread_ts,
+                        ts,
+                        None,
+                    );
+                    let mut replies = dispatch::process_mention(&message).await;
+                    health::record_message();
+
+                    // If no command matched, just say hello.
+                    if replies.is_empty() {
+                        let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
+                            thread_ts.clone()
+                        } else {
+                            message.ts.clone()
+                        };
+                        replies.push((reply_thread_ts, hello_text(&text)));
+                    }
+
+                    for (reply_thread_ts, reply_message) in replies {
+                        slack::reply_in_thread(&message, reply_thread_ts, reply_message).await;
+                    }
                 }
                 EventCallbackType::Message {
                     //channel_type,
@@ -149,75 +445,44 @@ where
                     thread_ts,
                     ts,
                     user,
+                    subtype,
                     ..
                 } => {
-                    // Channel is required.
-                    if let Ok(channel_object) = slack::channels_info(&channel).await {
-                        let user_object = if let Some(unwrapped_user) = user {
-                            if let Ok(user_object) = slack::users_info(&unwrapped_user).await {
-                                Some(user_object)
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        };
-
-                        // The latest message received from Slack.
-                        let message =
-                            slack::Message::new(channel_object, user_object, text, thread_ts, ts);
-                        //println!("{:#?}", message);
-                        // Process the message for karma.
-                        if let Some((reply_thread_ts, reply_message)) =
-                            karma::process_message(&message).await
-                        {
-                            slack::reply_in_thread(
-                                socket_mode,
-                                &message,
-                                reply_thread_ts,
-                                reply_message,
-                            )
-                            .await;
-                        }
-                        // Process the message for seen.
-                        if let Some((reply_thread_ts, reply_message)) =
-                            seen::process_message(&message).await
-                        {
-                            slack::reply_in_thread(
-                                socket_mode,
-                                &message,
-                                reply_thread_ts,
-                                reply_message,
-                            )
-                            .await;
-                        }
-                        // If enabled, process the message for convert.
-                        if env::var("XE_ACCOUNT_ID").is_ok() && env::var("XE_API_KEY").is_ok() {
-                            if let Some((reply_thread_ts, reply_message)) =
-                                convert::process_message(&message).await
-                            {
-                                slack::reply_in_thread(
-                                    socket_mode,
-                                    &message,
-                                    reply_thread_ts,
-                                    reply_message,
-                                )
-                                .await;
-                            }
-                        }
-                        // If enabled, process the message for ChatGPT.
-                        if env::var("CHATGPT_API_KEY").is_ok() {
-                            if let Some((reply_thread_ts, reply_message)) =
-                                chatgpt::process_message(&message).await
-                            {
-                                slack::reply_in_thread(
-                                    socket_mode,
-                                    &message,
-                                    reply_thread_ts,
-                                    reply_message,
-                                )
-                                .await;
-                            }
+                    // The latest message received from Slack. User and channel details are
+                    // only looked up if a feature needs them, as most messages aren't commands.
+                    let message = slack::Message::new(
+                        channel,
+                        user,
+                        slack::unescape_text(&text),
+                        thread_ts,
+                        ts,
+                        subtype,
+                    );
+                    //log::debug!("{:#?}", message);
+                    let replies = dispatch::process_message(&message).await;
+                    health::record_message();
+                    for (reply_thread_ts, reply_message) in replies {
+                        slack::reply_in_thread(&message, reply_thread_ts, reply_message).await;
+                    }
+                }
+                EventCallbackType::ReactionAdded {
+                    user,
+                    reaction,
+                    item,
+                    ..
+                } => {
+                    // Only reactions to messages can run commands, not reactions to files.
+                    let (Some(channel), Some(ts)) = (item.channel, item.ts) else {
+                        return;
+                    };
+                    if let Some((command, message)) =
+                        reaction::command_message(user, &reaction, channel, &ts).await
+                    {
+                        // Only the configured command runs, not karma or anything else that
+                        // might match the message reacted to.
+                        let replies = dispatch::process_reaction(&message, &command).await;
+                        for (reply_thread_ts, reply_message) in replies {
+                            slack::reply_in_thread(&message, reply_thread_ts, reply_message).await;
                         }
                     }
                 }
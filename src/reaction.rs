@@ -0,0 +1,112 @@
+// Runs a command on a message when someone reacts to it with a configured emoji. For example,
+// reacting with :question: asks ChatGPT about the message, as if `chatgpt <message>` was said.
+
+use std::env;
+
+use crate::slack;
+
+// By default, which command each reaction runs, see `TAG1BOT_REACTIONS`.
+const DEFAULT_REACTIONS: &str = "question=chatgpt,globe_with_meridians=detect,page_facing_up=tldr";
+
+lazy_static! {
+    // The emoji, without colons, and the command each runs.
+    static ref REACTIONS: Vec<(String, String)> = parse_reactions(
+        &env::var("TAG1BOT_REACTIONS").unwrap_or_else(|_| DEFAULT_REACTIONS.to_string())
+    );
+}
+
+// Parse a comma separated list of `emoji=command`, ie `question=chatgpt,mag=detect`. Colons
+// around the emoji are optional.
+pub(crate) fn parse_reactions(reactions: &str) -> Vec<(String, String)> {
+    reactions
+        .split(',')
+        .filter_map(|reaction| {
+            let (emoji, command) = reaction.split_once('=')?;
+            let emoji = emoji.trim().trim_matches(':').to_lowercase();
+            let command = command.trim().to_lowercase();
+            if emoji.is_empty() || command.is_empty() {
+                log::warn!(
+                    "ignoring invalid reaction in TAG1BOT_REACTIONS: {}",
+                    reaction
+                );
+                return None;
+            }
+            Some((emoji, command))
+        })
+        .collect()
+}
+
+// The command run by a reaction, if any. Skin tones are ignored, so `+1::skin-tone-2` runs the
+// same command as `+1`.
+fn reaction_command(reactions: &[(String, String)], reaction: &str) -> Option<String> {
+    let emoji = reaction.split("::").next().unwrap_or(reaction);
+    reactions
+        .iter()
+        .find(|(configured, _)| configured == emoji)
+        .map(|(_, command)| command.clone())
+}
+
+// If `reaction` is configured, build the command it runs on the message reacted to. Returns the
+// command and the message to run it on. The command is from the user that reacted, and replies go
+// in the thread of the message.
+pub(crate) async fn command_message(
+    user_id: String,
+    reaction: &str,
+    channel_id: String,
+    ts: &str,
+) -> Option<(String, slack::Message)> {
+    let command = reaction_command(&REACTIONS, reaction)?;
+    let reacted_to = match slack::conversations_message(&channel_id, ts).await {
+        Ok(reacted_to) => reacted_to,
+        Err(e) => {
+            log::error!("failed to get message {} in {}: {}", ts, channel_id, e);
+            return None;
+        }
+    };
+    let text = slack::unescape_text(&reacted_to.text);
+    if text.trim().is_empty() {
+        return None;
+    }
+    log::info!("reaction :{}: runs `{}` on {}", reaction, command, ts);
+
+    let message = slack::Message::new(
+        channel_id,
+        Some(user_id),
+        format!("{} {}", command, text.trim()),
+        reacted_to.thread_ts,
+        reacted_to.ts,
+        None,
+    );
+    Some((command, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_configured_reactions() {
+        assert_eq!(
+            parse_reactions(":Question:=chatgpt, mag = Detect,,broken,=tldr"),
+            vec![
+                ("question".to_string(), "chatgpt".to_string()),
+                ("mag".to_string(), "detect".to_string()),
+            ]
+        );
+        assert!(parse_reactions("").is_empty());
+    }
+
+    #[test]
+    fn reactions_ignore_skin_tone() {
+        let reactions = parse_reactions(DEFAULT_REACTIONS);
+        assert_eq!(
+            reaction_command(&reactions, "question").as_deref(),
+            Some("chatgpt")
+        );
+        assert_eq!(
+            reaction_command(&reactions, "page_facing_up::skin-tone-3").as_deref(),
+            Some("tldr")
+        );
+        assert_eq!(reaction_command(&reactions, "thumbsup"), None);
+    }
+}
@@ -1,14 +1,62 @@
 // Tracks when each user was last seen.
-// For example, `seen nnewton?` or `seen peta`.
+// For example, `seen nnewton?`, `seen peta` or `seen peta in #general?`. Users can opt out of
+// being tracked with `seen optout`, and back in with `seen optin`. The most active users are
+// listed with `seen stats`.
+//
+// Bursts of messages would otherwise write to the database for every message, so each user is
+// written at most once per channel every `SEEN_DEBOUNCE_SECONDS`, holding the latest sighting in
+// memory until then. The user's name and whether the channel is private are only looked up when a
+// sighting is written.
 
 use regex::Regex;
-use rusqlite::params;
+use rusqlite::{params, OptionalExtension};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
 
-use crate::db::DB;
+use crate::db::{self, DbError};
 use crate::slack;
+use crate::stats;
 use crate::util;
 
 const REGEX_SEEN: &str = r"(?i)^seen (\w{1,42})(?:\?)?$";
+const REGEX_SEEN_IN: &str = r"(?i)^seen (\w{1,42}) in <#(\w+)(?:\|[^>]*)?>(?:\?)?$";
+const REGEX_SEEN_OPT: &str = r"(?i)^seen opt[ -]?(out|in)$";
+const REGEX_SEEN_STATS: &str = r"(?i)^seen stats(?:\?)?$";
+
+// The most similar users to suggest if there's no exact match for a seen request.
+const SEEN_SUGGESTIONS: usize = 5;
+
+// How many users `seen stats` lists.
+const SEEN_STATS_USERS: u32 = 10;
+
+// How often to write each user's latest sighting in a channel, 0 writes every message.
+const DEFAULT_SEEN_DEBOUNCE_SECONDS: u64 = 30;
+
+lazy_static! {
+    // Sightings per user id and channel id.
+    static ref SIGHTINGS: Mutex<HashMap<(String, String), Debounce>> = Mutex::new(HashMap::new());
+}
+
+// A user seen posting a message, to be written to the database.
+#[derive(Debug, Clone, PartialEq)]
+struct Sighting {
+    user_id: String,
+    channel: String,
+    last_said: String,
+    last_ts: String,
+    seen_at: u64,
+    // How many messages this sighting covers, more than one if a burst was debounced.
+    messages: u32,
+}
+
+// When a user's sightings in a channel were last written, and the latest sighting since.
+#[derive(Debug, Default)]
+struct Debounce {
+    last_written: u64,
+    pending: Option<Sighting>,
+}
 
 // When a user was last seen, and what they said (if in a non-private channel).
 #[derive(Debug)]
@@ -18,52 +66,192 @@ pub(crate) struct LastSeen {
     last_said: String,
     last_seen: u32,
     //last_private: u32,
+    // Not known for users last seen before this was recorded.
+    last_ts: Option<String>,
+    // Not known for users last seen before this was recorded.
+    user_id: Option<String>,
 }
 
 // Update last_seen for user posting message, reply if they're asking `seen displayname?`.
 pub(crate) async fn process_message(message: &slack::Message) -> Option<(String, String)> {
     let trimmed_text = message.text.trim();
 
-    // Check if someone is asking `seen <foo>?`.
+    // Check if someone is asking `seen optout` or `seen optin`.
+    let re_opt = Regex::new(REGEX_SEEN_OPT).expect("failed to compile REGEX_SEEN_OPT");
+    if let Some(cap) = re_opt.captures(trimmed_text) {
+        let user = message.user().await?;
+        let optout = cap[1].eq_ignore_ascii_case("out");
+        let reply_message = match set_optout(user, optout) {
+            Ok(()) if optout => {
+                "I've forgotten when I last saw you, and won't track you anymore. Say `seen optin` to change your mind.".to_string()
+            }
+            Ok(()) => "I'll track when I last saw you again.".to_string(),
+            Err(e) => {
+                log::error!("failed to update seen opt out for {}: {}", user.name, e);
+                let reply_message = "Sorry, I failed to update your preference.".to_string();
+                return slack::reply_ephemeral(message, reply_message).await;
+            }
+        };
+        let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
+            thread_ts.to_string()
+        } else {
+            message.ts.to_string()
+        };
+        return Some((reply_thread_ts, reply_message));
+    }
+
+    // Check if someone is asking `seen stats`.
+    let re_stats = Regex::new(REGEX_SEEN_STATS).expect("failed to compile REGEX_SEEN_STATS");
+    if re_stats.is_match(trimmed_text) {
+        flush().await;
+        let reply_message = match most_active(SEEN_STATS_USERS) {
+            Ok(users) if users.is_empty() => "I haven't seen anyone yet.".to_string(),
+            Ok(users) => format_most_active(&users),
+            Err(e) => {
+                log::error!("failed to load most active users: {}", e);
+                let reply_message = "Sorry, I failed to look up seen stats.".to_string();
+                return slack::reply_ephemeral(message, reply_message).await;
+            }
+        };
+        let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
+            thread_ts.to_string()
+        } else {
+            message.ts.to_string()
+        };
+        return Some((reply_thread_ts, reply_message));
+    }
+
+    // Check if someone is asking `seen <foo> in #<channel>?` or `seen <foo>?`.
+    let re_in = Regex::new(REGEX_SEEN_IN).expect("failed to compile REGEX_SEEN_IN");
     let re = Regex::new(REGEX_SEEN).expect("failed to compile REGEX_SEEN");
-    let seen_request = if re.is_match(trimmed_text) {
-        let cap = re
-            .captures(trimmed_text)
-            .expect("failed to capture REGEX_KARMA_PLUS");
-        cap.get(1).map_or("", |m| m.as_str())
+    let (seen_request, seen_channel) = if let Some(cap) = re_in.captures(trimmed_text) {
+        (
+            cap.get(1).map_or("", |m| m.as_str()),
+            cap.get(2).map(|m| m.as_str()),
+        )
+    } else if let Some(cap) = re.captures(trimmed_text) {
+        (cap.get(1).map_or("", |m| m.as_str()), None)
     } else {
-        ""
+        ("", None)
+    };
+
+    // Someone asking about themselves, either by name or as `seen me?`, is obviously here.
+    let seen_self = if seen_request.is_empty() {
+        false
+    } else if seen_request.eq_ignore_ascii_case("me") {
+        message.user().await.is_some()
+    } else if let Some(user) = message.user().await {
+        seen_request.eq_ignore_ascii_case(&user.name)
+    } else {
+        false
+    };
+
+    // Users who opted out are never looked up.
+    let seen_optout = if seen_request.is_empty() || seen_self {
+        false
+    } else {
+        match is_opted_out(seen_request) {
+            Ok(seen_optout) => seen_optout,
+            Err(e) => {
+                log::error!("failed to check seen opt out for {}: {}", seen_request, e);
+                false
+            }
+        }
     };
 
-    // And if so, get the answer.
-    let requested_user_last_seen = if seen_request.is_empty() {
+    // And if not, get the answer, including sightings not yet written.
+    let requested_user_last_seen = if seen_request.is_empty() || seen_self || seen_optout {
         None
     } else {
-        last_seen(seen_request)
+        flush().await;
+        Some(lookup(seen_request, seen_channel))
     };
 
-    // Either way, record that we're seeing a user message now (if not a bot).
-    if let Some(user) = message.user.as_ref() {
-        let current_user_last_seen = last_seen(&user.name);
-        record_seen(
-            message,
-            message.channel.is_private,
-            current_user_last_seen.is_some(),
-        );
-    }
+    // If there's no exact match, fall back to users with similar names: answer for a single
+    // match, or suggest a few.
+    let mut suggestions = Vec::new();
+    let requested_user_last_seen = match requested_user_last_seen {
+        Some(Ok(None)) => match similar_users(seen_request) {
+            Ok(users) if users.len() == 1 => Some(lookup(&users[0], seen_channel)),
+            Ok(users) => {
+                suggestions = users;
+                Some(Ok(None))
+            }
+            Err(e) => Some(Err(e)),
+        },
+        requested_user_last_seen => requested_user_last_seen,
+    };
+
+    // Either way, record that we're seeing a user message now.
+    record(message).await;
 
     // Prepare a reply, if someone asked `seen <foo>?`.
     let reply_message = if seen_request.is_empty() {
         // Do not send a reply.
         return None;
-    } else if let Some(last_seen) = requested_user_last_seen {
+    } else if seen_self {
+        "That's you! You're right here.".to_string()
+    } else if seen_optout {
+        "That user has opted out of seen tracking.".to_string()
+    } else if let Some(Ok(Some(last_seen))) = requested_user_last_seen {
+        stats::record(stats::Stat::Seen);
+        // Show the user's current name, in case they've renamed themselves since.
+        let name = match last_seen.user_id.as_ref() {
+            Some(user_id) => match slack::users_info(user_id).await {
+                Ok(user) => user.name,
+                Err(e) => {
+                    log::warn!("failed to load seen user {}: {}", user_id, e);
+                    last_seen.user.clone()
+                }
+            },
+            None => last_seen.user.clone(),
+        };
+        // Also show when in the requester's timezone, if known.
+        let tz_offset = message.user().await.and_then(|u| u.tz_offset);
+        // Link to the message, if possible.
+        let permalink = match last_seen.last_ts.as_ref() {
+            Some(last_ts) => match slack::get_permalink(&last_seen.channel, last_ts).await {
+                Ok(permalink) => format!(" <{}|View message>", permalink),
+                Err(e) => {
+                    log::warn!("failed to get permalink: {}", e);
+                    String::new()
+                }
+            },
+            None => String::new(),
+        };
         format!(
-            "`{}` last seen in <#{}> saying `{}` {}.",
-            last_seen.user,
+            "`{}` last seen in <#{}> saying `{}` {} ({} {}).{}",
+            name,
             last_seen.channel,
             last_seen.last_said,
-            util::time_ago(last_seen.last_seen as u64, false)
+            util::time_ago(last_seen.last_seen as u64, false),
+            util::format_local(last_seen.last_seen as u64, tz_offset),
+            if tz_offset.is_some() {
+                "your time"
+            } else {
+                "UTC"
+            },
+            permalink,
         )
+    } else if let Some(Err(e)) = requested_user_last_seen {
+        log::error!("failed to look up seen for {}: {}", seen_request, e);
+        let reply_message = format!("Sorry, I failed to look up `{}`.", seen_request);
+        return slack::reply_ephemeral(message, reply_message).await;
+    } else if !suggestions.is_empty() {
+        let more = if suggestions.len() > SEEN_SUGGESTIONS {
+            suggestions.truncate(SEEN_SUGGESTIONS);
+            ", ..."
+        } else {
+            ""
+        };
+        format!(
+            "I've never seen `{}`. Did you mean: {}{}?",
+            seen_request,
+            suggestions.join(", "),
+            more
+        )
+    } else if let Some(seen_channel) = seen_channel {
+        format!("I've never seen `{}` in <#{}>.", seen_request, seen_channel)
     } else {
         format!("I've never seen `{}`.", seen_request)
     };
@@ -78,89 +266,457 @@ pub(crate) async fn process_message(message: &slack::Message) -> Option<(String,
     Some((reply_thread_ts, reply_message))
 }
 
-// Determine when a given user was last seen.
-fn last_seen(user: &str) -> Option<LastSeen> {
-    let db = DB.lock().unwrap_or_else(|_| panic!("DB mutex poisoned!"));
-    let mut statement = db
-        .prepare(
-            //"SELECT user, channel, last_said, last_seen, last_private FROM seen WHERE user = :user",
-            "SELECT user, channel, last_said, last_seen FROM seen WHERE user = :user",
-        )
-        .expect("failed to prepare SELECT");
-    let mut seen_iter = statement
-        .query_map(&[(":user", &user.to_lowercase())], |row| {
-            Ok(LastSeen {
-                user: row.get(0).expect("failed to get user"),
-                channel: row.get(1).expect("failed to get channel"),
-                last_said: row.get(2).expect("failed to get last_said"),
-                last_seen: row.get(3).expect("failed to get last_seen"),
-                //last_private: row.get(4).expect("failed to get last_private"),
+// Record that we're seeing a user message now (if not a bot). Direct messages to the bot aren't
+// recorded, only messages in channels.
+pub(crate) async fn record(message: &slack::Message) {
+    if message.is_im() {
+        return;
+    }
+    let user_id = match message.user_id.as_ref() {
+        Some(user_id) => user_id,
+        None => return,
+    };
+    let sighting = Sighting {
+        user_id: user_id.clone(),
+        channel: message.channel_id.clone(),
+        last_said: message.text.clone(),
+        last_ts: message.ts.clone(),
+        seen_at: util::timestamp_now(),
+        messages: 1,
+    };
+    // Debounce before looking anything up, so a burst of messages doesn't call Slack for each.
+    let sighting = {
+        let mut sightings = SIGHTINGS.lock().unwrap();
+        let debounce = sightings
+            .entry((user_id.clone(), message.channel_id.clone()))
+            .or_default();
+        debounce_sighting(debounce, sighting, debounce_seconds())
+    };
+    if let Some(sighting) = sighting {
+        write_sighting(&sighting).await;
+    }
+}
+
+// How often to write each user's latest sighting in a channel, configurable with
+// `SEEN_DEBOUNCE_SECONDS`.
+pub(crate) fn debounce_seconds() -> u64 {
+    match env::var("SEEN_DEBOUNCE_SECONDS") {
+        Ok(seconds) => seconds.trim().parse().unwrap_or_else(|e| {
+            log::warn!("invalid SEEN_DEBOUNCE_SECONDS {}: {}", seconds, e);
+            DEFAULT_SEEN_DEBOUNCE_SECONDS
+        }),
+        Err(_) => DEFAULT_SEEN_DEBOUNCE_SECONDS,
+    }
+}
+
+// Return the sighting if it should be written now, otherwise hold on to it until the next flush.
+fn debounce_sighting(
+    debounce: &mut Debounce,
+    mut sighting: Sighting,
+    debounce_seconds: u64,
+) -> Option<Sighting> {
+    // Keep counting messages from sightings that weren't written.
+    if let Some(pending) = debounce.pending.take() {
+        sighting.messages += pending.messages;
+    }
+    if sighting.seen_at >= debounce.last_written + debounce_seconds {
+        debounce.last_written = sighting.seen_at;
+        Some(sighting)
+    } else {
+        debounce.pending = Some(sighting);
+        None
+    }
+}
+
+// Write all sightings held in memory, and forget sightings that are no longer recent.
+pub(crate) async fn flush() {
+    let now = util::timestamp_now();
+    let debounce_seconds = debounce_seconds();
+    let pending: Vec<Sighting> = {
+        let mut sightings = SIGHTINGS.lock().unwrap();
+        sightings.retain(|_, debounce| {
+            debounce.pending.is_some() || debounce.last_written + debounce_seconds > now
+        });
+        sightings
+            .values_mut()
+            .filter_map(|debounce| {
+                let pending = debounce.pending.take()?;
+                debounce.last_written = now;
+                Some(pending)
             })
-        })
-        .expect("failed to select from seen table");
-
-    // Return last_seen if exists.
-    if let Some(seen) = seen_iter.next() {
-        return Some(seen.unwrap());
-    }
-    None
-}
-
-// Create/update record for last_seen for current user.
-fn record_seen(seen_message: &slack::Message, is_private: bool, previously_seen: bool) {
-    if let Some(user) = seen_message.user.as_ref() {
-        let db = DB.lock().unwrap_or_else(|_| panic!("DB mutex poisoned!"));
-
-        match previously_seen {
-            // The user has previously been seen, update their record with their latest message.
-            true => {
-                if is_private {
-                    // Only record timestamp if seeing user in a private channel.
-                    db.execute(
-                        "UPDATE seen SET last_private = ?1 WHERE user = ?2",
-                        params![util::timestamp_now(), user.name.to_lowercase()],
-                    )
-                    .expect("failed to update seen");
+            .collect()
+    };
+    for sighting in pending {
+        write_sighting(&sighting).await;
+    }
+}
+
+// Regularly write sightings held in memory. Started unless `SEEN_DEBOUNCE_SECONDS` is 0.
+pub(crate) async fn flush_thread() {
+    let debounce_seconds = debounce_seconds().max(1);
+    loop {
+        tokio::time::sleep(Duration::from_secs(debounce_seconds)).await;
+        flush().await;
+    }
+}
+
+// Look up the user's name and whether the channel is private, then write the sighting.
+async fn write_sighting(sighting: &Sighting) {
+    let user = match slack::users_info(&sighting.user_id).await {
+        Ok(user) => user,
+        Err(e) => {
+            log::error!("failed to load user {}: {}", sighting.user_id, e);
+            return;
+        }
+    };
+    let channel = match slack::channels_info(&sighting.channel).await {
+        Ok(channel) => channel,
+        Err(e) => {
+            log::error!("failed to load channel {}: {}", sighting.channel, e);
+            return;
+        }
+    };
+    if let Err(e) = record_seen(sighting, &user.name.to_lowercase(), channel.is_private) {
+        log::error!("failed to record seen for {}: {}", user.name, e);
+    }
+}
+
+// Determine if the user with a given name opted out of seen tracking.
+fn is_opted_out(user: &str) -> Result<bool, DbError> {
+    let db = db::get_conn()?;
+    let count: u32 = db.query_row(
+        "SELECT COUNT(*) FROM seen_optout WHERE user = ?1",
+        params![user.to_lowercase()],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+// Opt a user out of seen tracking, forgetting when they were last seen, or back in.
+fn set_optout(user: &slack::User, optout: bool) -> Result<(), DbError> {
+    let mut db = db::get_conn()?;
+    let tx = db.transaction()?;
+    if optout {
+        tx.execute(
+            "INSERT INTO seen_optout (user_id, user) VALUES(?1, ?2)
+            ON CONFLICT (user_id) DO UPDATE SET user = excluded.user",
+            params![user.id, user.name.to_lowercase()],
+        )?;
+        tx.execute(
+            "DELETE FROM seen WHERE user_id = ?1 OR user = ?2",
+            params![user.id, user.name.to_lowercase()],
+        )?;
+    } else {
+        tx.execute(
+            "DELETE FROM seen_optout WHERE user_id = ?1",
+            params![user.id],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Determine when a given user was last seen, in a specific channel if given.
+fn lookup(user: &str, channel: Option<&str>) -> Result<Option<LastSeen>, DbError> {
+    match channel {
+        Some(channel) => last_seen_in(user, channel),
+        None => last_seen(user),
+    }
+}
+
+// Find users whose names contain `partial`, returning at most one more than SEEN_SUGGESTIONS so
+// the caller can tell if there are more.
+fn similar_users(partial: &str) -> Result<Vec<String>, DbError> {
+    let pattern = format!("%{}%", escape_like(&partial.to_lowercase()));
+    let db = db::get_conn()?;
+    let mut statement = db.prepare(
+        r"SELECT DISTINCT user FROM seen WHERE user LIKE ?1 ESCAPE '\' AND channel != '' ORDER BY user LIMIT ?2",
+    )?;
+    let user_iterator =
+        statement.query_map(params![pattern, SEEN_SUGGESTIONS + 1], |row| row.get(0))?;
+
+    let mut users = Vec::new();
+    for user in user_iterator {
+        users.push(user?);
+    }
+    Ok(users)
+}
+
+// Escape `%` and `_` so they're matched literally by LIKE, not as wildcards.
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// Find the users seen posting the most messages, with how many and when they were last seen.
+fn most_active(limit: u32) -> Result<Vec<(String, u32, u64)>, DbError> {
+    let db = db::get_conn()?;
+    let mut statement = db.prepare(
+        "SELECT user, SUM(messages) AS total, MAX(MAX(COALESCE(last_seen, 0)), MAX(COALESCE(last_private, 0))) AS latest FROM seen GROUP BY user ORDER BY total DESC, latest DESC LIMIT ?1",
+    )?;
+    let user_iterator = statement.query_map(params![limit], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })?;
+
+    let mut users = Vec::new();
+    for user in user_iterator {
+        users.push(user?);
+    }
+    Ok(users)
+}
+
+// List users with how many messages they've been seen posting, and when they were last seen.
+fn format_most_active(users: &[(String, u32, u64)]) -> String {
+    let lines: Vec<String> = users
+        .iter()
+        .enumerate()
+        .map(|(index, (user, messages, latest))| {
+            format!(
+                "{}. `{}`: {} {}, last seen {}",
+                index + 1,
+                user,
+                messages,
+                if *messages == 1 {
+                    "message"
                 } else {
-                    // Record full information if seeing user in a public channel.
-                    db.execute(
-                    "UPDATE seen SET channel = ?1, last_said = ?2, last_seen = ?3 WHERE user = ?4",
-                    params![
-                        seen_message.channel.id,
-                        seen_message.text,
-                        util::timestamp_now(),
-                        user.name.to_lowercase()
-                    ],
-                )
-                .expect("failed to update seen");
-                }
-            }
-            // The user has not been previously seen, create a new record with their first message.
-            false => {
-                if is_private {
-                    // Only record name and timestamp if seeing user in a private channel.
-                    db.execute(
-                r#"INSERT INTO seen (user, last_said, channel, last_seen, last_private) VALUES(?1, "", "", 0, ?2)"#,
-                params![
-                    user.name.to_lowercase(),
-                    util::timestamp_now(),
-                ],
+                    "messages"
+                },
+                util::time_ago(*latest, false)
             )
-            .expect("failed to insert into seen");
-                } else {
-                    // Record full information if seeing user in a public channel.
-                    db.execute(
-                    "INSERT INTO seen (user, last_said, channel, last_seen) VALUES(?1, ?2, ?3, ?4)",
-                    params![
-                        user.name.to_lowercase(),
-                        seen_message.text,
-                        seen_message.channel.id,
-                        util::timestamp_now(),
-                    ],
-                )
-                .expect("failed to insert into seen");
-                }
-            }
+        })
+        .collect();
+    format!("Most active users:\n{}", lines.join("\n"))
+}
+
+// Determine when and where a given user was last seen in any public channel.
+fn last_seen(user: &str) -> Result<Option<LastSeen>, DbError> {
+    let db = db::get_conn()?;
+    let last_seen = db
+        .query_row(
+            //"SELECT user, channel, last_said, last_seen, last_private FROM seen WHERE user = ?1",
+            "SELECT user, channel, last_said, last_seen, last_ts, user_id FROM seen WHERE user = ?1 AND channel != '' ORDER BY last_seen DESC LIMIT 1",
+            params![user.to_lowercase()],
+            |row| {
+                Ok(LastSeen {
+                    user: row.get(0)?,
+                    channel: row.get(1)?,
+                    last_said: row.get(2)?,
+                    last_seen: row.get(3)?,
+                    //last_private: row.get(4)?,
+                    last_ts: row.get(4)?,
+                    user_id: row.get(5)?,
+                })
+            },
+        )
+        .optional()?;
+
+    Ok(last_seen)
+}
+
+// Determine when a given user was last seen in a specific channel.
+fn last_seen_in(user: &str, channel: &str) -> Result<Option<LastSeen>, DbError> {
+    let db = db::get_conn()?;
+    let last_seen = db
+        .query_row(
+            "SELECT user, channel, last_said, last_seen, last_ts, user_id FROM seen WHERE user = ?1 AND channel = ?2",
+            params![user.to_lowercase(), channel],
+            |row| {
+                Ok(LastSeen {
+                    user: row.get(0)?,
+                    channel: row.get(1)?,
+                    last_said: row.get(2)?,
+                    last_seen: row.get(3)?,
+                    last_ts: row.get(4)?,
+                    user_id: row.get(5)?,
+                })
+            },
+        )
+        .optional()?;
+
+    Ok(last_seen)
+}
+
+// Create/update record for last_seen for current user. Each user has a record per public channel
+// they've been seen in, and a single record with an empty channel for when they were last seen
+// in any private channel.
+fn record_seen(sighting: &Sighting, name: &str, is_private: bool) -> Result<(), DbError> {
+    let mut db = db::get_conn()?;
+    let tx = db.transaction()?;
+
+    // Never track users who opted out.
+    let optout: u32 = tx.query_row(
+        "SELECT COUNT(*) FROM seen_optout WHERE user_id = ?1",
+        params![sighting.user_id],
+        |row| row.get(0),
+    )?;
+    if optout > 0 {
+        return Ok(());
+    }
+
+    // Records are keyed by name, so if the user has been renamed move their records to the new
+    // name, replacing any stale records already stored under it.
+    tx.execute(
+        "UPDATE OR REPLACE seen SET user = ?1 WHERE user_id = ?2 AND user != ?1",
+        params![name, sighting.user_id],
+    )?;
+
+    if is_private {
+        // Only record timestamp if seeing user in a private channel.
+        tx.execute(
+            r#"INSERT INTO seen (user, last_said, channel, last_seen, last_private, user_id, messages) VALUES(?1, "", "", 0, ?2, ?3, ?4)
+            ON CONFLICT (user, channel) DO UPDATE SET last_private = excluded.last_private, user_id = excluded.user_id, messages = messages + excluded.messages"#,
+            params![name, sighting.seen_at, sighting.user_id, sighting.messages],
+        )?;
+    } else {
+        // Record full information if seeing user in a public channel.
+        tx.execute(
+            "INSERT INTO seen (user, last_said, channel, last_seen, last_ts, user_id, messages) VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT (user, channel) DO UPDATE SET last_said = excluded.last_said, last_seen = excluded.last_seen, last_ts = excluded.last_ts, user_id = excluded.user_id, messages = messages + excluded.messages",
+            params![
+                name,
+                sighting.last_said,
+                sighting.channel,
+                sighting.seen_at,
+                sighting.last_ts,
+                sighting.user_id,
+                sighting.messages,
+            ],
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn sighting(seen_at: u64, last_said: &str) -> Sighting {
+        Sighting {
+            user_id: "U1".to_string(),
+            channel: "C1".to_string(),
+            last_said: last_said.to_string(),
+            last_ts: format!("{}.000100", seen_at),
+            seen_at,
+            messages: 1,
+        }
+    }
+
+    // Write a sighting of `name` saying hi in CSEEN.
+    fn record_hi(user_id: &str, name: &str) {
+        let sighting = Sighting {
+            user_id: user_id.to_string(),
+            channel: "CSEEN".to_string(),
+            ..sighting(util::timestamp_now(), "hi")
+        };
+        record_seen(&sighting, name, false).unwrap();
+    }
+
+    #[tokio::test]
+    async fn seen_skips_opted_out_users() {
+        testing::setup();
+        let user = slack::User {
+            id: "UOPTOUT".to_string(),
+            name: "hermit".to_string(),
+            ..Default::default()
+        };
+        record_hi(&user.id, &user.name);
+        assert!(lookup("hermit", None).unwrap().is_some());
+
+        // Opting out forgets the user, and they're not recorded again.
+        set_optout(&user, true).unwrap();
+        assert!(lookup("hermit", None).unwrap().is_none());
+        record_hi(&user.id, &user.name);
+        assert!(lookup("hermit", None).unwrap().is_none());
+        assert_eq!(
+            testing::replies(&testing::message("seen hermit?")).await,
+            vec![(
+                "1700000000.000100".to_string(),
+                "That user has opted out of seen tracking.".to_string()
+            )]
+        );
+
+        // Until they opt back in.
+        set_optout(&user, false).unwrap();
+        record_hi(&user.id, &user.name);
+        assert!(lookup("hermit", None).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn seen_matches_partial_names() {
+        testing::setup();
+        record_hi("UPARTIAL", "bartholomew");
+        assert_eq!(similar_users("tholo").unwrap(), vec!["bartholomew"]);
+        let replies = testing::replies(&testing::message("seen bartholo")).await;
+        assert_eq!(replies.len(), 1);
+        assert!(
+            replies[0]
+                .1
+                .starts_with("`bartholomew` last seen in <#CSEEN> saying `hi` just now"),
+            "{}",
+            replies[0].1
+        );
+    }
+
+    #[test]
+    fn seen_escapes_wildcards() {
+        testing::setup();
+        assert_eq!(escape_like(r"100%_a\b"), r"100\%\_a\\b");
+        record_hi("UWILD1", "wild_card");
+        record_hi("UWILD2", "wildxcard");
+        record_hi("UWILD3", "wild%card");
+        assert_eq!(similar_users("wild_").unwrap(), vec!["wild_card"]);
+        assert_eq!(similar_users("wild%").unwrap(), vec!["wild%card"]);
+        assert_eq!(
+            similar_users("card").unwrap(),
+            vec!["wild%card", "wild_card", "wildxcard"]
+        );
+    }
+
+    #[test]
+    fn seen_debounces_bursts() {
+        let mut debounce = Debounce::default();
+        assert_eq!(
+            debounce_sighting(&mut debounce, sighting(1000, "one"), 30),
+            Some(sighting(1000, "one"))
+        );
+        // Later messages in the burst are held, keeping only the latest.
+        assert_eq!(
+            debounce_sighting(&mut debounce, sighting(1005, "two"), 30),
+            None
+        );
+        assert_eq!(
+            debounce_sighting(&mut debounce, sighting(1010, "three"), 30),
+            None
+        );
+        let pending = debounce.pending.as_ref().unwrap();
+        assert_eq!(pending.last_said, "three");
+        assert_eq!(pending.messages, 2);
+        // Once the interval has passed, the next message is written, counting the held messages.
+        let written = debounce_sighting(&mut debounce, sighting(1030, "four"), 30).unwrap();
+        assert_eq!(written.last_said, "four");
+        assert_eq!(written.messages, 3);
+        assert_eq!(debounce.pending, None);
+    }
+
+    #[test]
+    fn seen_stats_format() {
+        let now = util::timestamp_now();
+        let users = vec![("peta".to_string(), 42, now), ("jon".to_string(), 1, now)];
+        assert_eq!(
+            format_most_active(&users),
+            "Most active users:\n1. `peta`: 42 messages, last seen just now\n2. `jon`: 1 message, last seen just now"
+        );
+    }
+
+    #[test]
+    fn seen_without_debounce() {
+        let mut debounce = Debounce::default();
+        for seen_at in [1000, 1000, 1001] {
+            assert!(debounce_sighting(&mut debounce, sighting(seen_at, "hi"), 0).is_some());
         }
     }
 }
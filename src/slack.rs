@@ -1,10 +1,19 @@
 // Additional Slack functionality beyond what is provided by the slack_rust crate.
 
+use hmac::{Hmac, Mac, NewMac};
+use regex::{Captures, Regex};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use slack_rust::chat::post_message::{post_message, PostMessageRequest};
-use slack_rust::http_client::SlackWebAPIClient;
-use slack_rust::socket::socket_mode::SocketMode;
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
 use std::env;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+use crate::error::Error;
+use crate::util;
 
 // Calls to users_info return the following.
 #[derive(Deserialize, Serialize, Debug)]
@@ -14,32 +23,185 @@ pub(crate) struct UserWrapper {
     error: Option<String>,
 }
 
+// Reject requests signed longer ago than this, so captured requests can't be replayed.
+const MAX_REQUEST_AGE_SECONDS: u64 = 5 * 60;
+
+// How many times to retry a Slack API call that was rate limited, before giving up.
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+
+// The longest to wait before retrying a rate limited call, whatever Slack asks for.
+const MAX_RETRY_AFTER_SECONDS: u64 = 60;
+
+// How long to wait before each retry of a message that failed to post, before giving up.
+const POST_RETRY_BACKOFF_SECONDS: &[u64] = &[5, 30, 120];
+
+// Errors from Slack that may pass, so posting the message again may work.
+const RETRYABLE_ERRORS: &[&str] = &[
+    "internal_error",
+    "fatal_error",
+    "service_unavailable",
+    "request_timeout",
+    "ratelimited",
+];
+
+// How long to reuse user and channel details, so a busy channel doesn't look up the same user
+// and channel for every message.
+const INFO_CACHE_SECONDS: u64 = 5 * 60;
+
+// The most pages of results to fetch from a Slack list method.
+const MAX_PAGES: usize = 50;
+
+// Split longer messages, see `SLACK_MAX_MESSAGE_LENGTH`.
+const DEFAULT_MAX_MESSAGE_LENGTH: usize = 3500;
+
+// Opens and closes a block of code.
+const CODE_FENCE: &str = "```";
+
+// The longest reply each feature posts, see `MAX_REPLY_LENGTH_<FEATURE>`. Longer replies are
+// truncated before being split into messages.
+const DEFAULT_MAX_REPLY_LENGTHS: &[(&str, usize)] = &[
+    ("karma", 1_000),
+    ("seen", 2_000),
+    ("dice", 2_000),
+    ("convert", 4_000),
+    ("weather", 4_000),
+    ("chatgpt", 20_000),
+];
+
+// Appended to truncated replies.
+const TRUNCATED: &str = "…";
+
+// Slack wraps links in markup, ie `<https://example.com|label>` or `<mailto:a@b.com|a@b.com>`.
+const REGEX_SLACK_LINK: &str = r"<((?:https?|mailto|ftp|tel):[^|>]*)(?:\|([^>]*))?>";
+
+// Calls to auth_test return the following.
+#[derive(Deserialize, Serialize, Debug)]
+struct AuthTestWrapper {
+    ok: bool,
+    user_id: Option<String>,
+    error: Option<String>,
+}
+
+// The bot's own user id, resolved once at startup.
+lazy_static! {
+    static ref BOT_USER_ID: OnceCell<String> = OnceCell::new();
+}
+
+// Recently looked up users and channels by id, with when each was looked up.
+lazy_static! {
+    static ref USERS: Mutex<HashMap<String, (u64, User)>> = Mutex::new(HashMap::new());
+    static ref CHANNELS: Mutex<HashMap<String, (u64, Channel)>> = Mutex::new(HashMap::new());
+}
+
 // Details needed to track when a user was last seen.
 #[derive(Debug)]
 pub(crate) struct Message {
-    pub(crate) user: Option<User>,
-    pub(crate) channel: Channel,
+    pub(crate) user_id: Option<String>,
+    pub(crate) channel_id: String,
     pub(crate) text: String,
     pub(crate) thread_ts: Option<String>,
     pub(crate) ts: String,
+    // Set for edits, deletions and other special messages, see https://api.slack.com/events/message.
+    pub(crate) subtype: Option<String>,
+    // Full user and channel details are only fetched from Slack if a feature needs them.
+    user: OnceCell<Option<User>>,
+    channel: OnceCell<Option<Channel>>,
+    // Set for slash commands, which answer privately themselves: private replies are collected
+    // here instead of being posted.
+    ephemeral_replies: Option<Arc<Mutex<Vec<String>>>>,
 }
 
 impl Message {
     pub(crate) fn new(
-        channel: Channel,
-        user: Option<User>,
+        channel_id: String,
+        user_id: Option<String>,
         text: String,
         thread_ts: Option<String>,
         ts: String,
+        subtype: Option<String>,
     ) -> Message {
         Message {
-            channel,
-            user,
+            channel_id,
+            user_id,
             text,
             thread_ts,
             ts,
+            subtype,
+            user: OnceCell::new(),
+            channel: OnceCell::new(),
+            ephemeral_replies: None,
         }
     }
+
+    // A copy of the message with different text, for example with a command prefix removed.
+    pub(crate) fn with_text(&self, text: String) -> Message {
+        let mut message = Message::new(
+            self.channel_id.clone(),
+            self.user_id.clone(),
+            text,
+            self.thread_ts.clone(),
+            self.ts.clone(),
+            self.subtype.clone(),
+        );
+        message.ephemeral_replies = self.ephemeral_replies.clone();
+        message
+    }
+
+    // The message, collecting private replies to it instead of posting them, see
+    // `take_ephemeral_replies`.
+    pub(crate) fn collecting_ephemeral_replies(mut self) -> Message {
+        self.ephemeral_replies = Some(Arc::new(Mutex::new(Vec::new())));
+        self
+    }
+
+    // Take the private replies collected for the message, and for copies made with `with_text`.
+    pub(crate) fn take_ephemeral_replies(&self) -> Vec<String> {
+        match self.ephemeral_replies.as_ref() {
+            Some(replies) => std::mem::take(&mut *replies.lock().unwrap()),
+            None => Vec::new(),
+        }
+    }
+
+    // Determine if the message is a direct message to the bot. Slack's direct message channel ids
+    // start with `D`, so this doesn't need to look up the channel.
+    pub(crate) fn is_im(&self) -> bool {
+        self.channel_id.starts_with('D')
+    }
+
+    // Get full details about the user that posted the message, calling users_info on first use.
+    // Returns `None` for bot messages, or if the lookup fails.
+    pub(crate) async fn user(&self) -> Option<&User> {
+        self.user
+            .get_or_init(|| async {
+                let user_id = self.user_id.as_ref()?;
+                match users_info(user_id).await {
+                    Ok(user) => Some(user),
+                    Err(e) => {
+                        log::error!("failed to load user {}: {}", user_id, e);
+                        None
+                    }
+                }
+            })
+            .await
+            .as_ref()
+    }
+
+    // Get full details about the channel the message was posted in, calling channels_info on
+    // first use. Returns `None` if the lookup fails.
+    pub(crate) async fn channel(&self) -> Option<&Channel> {
+        self.channel
+            .get_or_init(|| async {
+                match channels_info(&self.channel_id).await {
+                    Ok(channel) => Some(channel),
+                    Err(e) => {
+                        log::error!("failed to load channel {}: {}", self.channel_id, e);
+                        None
+                    }
+                }
+            })
+            .await
+            .as_ref()
+    }
 }
 
 // Used to post message directly into channel, using serde to handle serialization.
@@ -48,10 +210,52 @@ struct JsonMessage {
     channel: String,
     text: String,
     mrkdwn: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_ts: Option<String>,
+    // Also show a threaded reply in the channel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_broadcast: Option<bool>,
 }
 
-// All available user info, see https://api.slack.com/methods/users.info.
+impl JsonMessage {
+    fn new(channel_id: &str, text: &str) -> JsonMessage {
+        JsonMessage {
+            channel: channel_id.to_string(),
+            text: text.to_string(),
+            mrkdwn: true,
+            thread_ts: None,
+            reply_broadcast: None,
+        }
+    }
+}
+
+// Used to post a message only visible to one user, see https://api.slack.com/methods/chat.postEphemeral.
 #[derive(Deserialize, Serialize, Debug)]
+struct JsonEphemeral {
+    channel: String,
+    user: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_ts: Option<String>,
+}
+
+// Used to react to a message with an emoji, see https://api.slack.com/methods/reactions.add.
+#[derive(Deserialize, Serialize, Debug)]
+struct JsonReaction {
+    channel: String,
+    timestamp: String,
+    name: String,
+}
+
+// Calls to most write methods return the following.
+#[derive(Deserialize, Serialize, Debug)]
+struct OkWrapper {
+    ok: bool,
+    error: Option<String>,
+}
+
+// All available user info, see https://api.slack.com/methods/users.info.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub(crate) struct User {
     pub(crate) id: String,
     pub(crate) team_id: String,
@@ -72,7 +276,7 @@ pub(crate) struct User {
 }
 
 // Profile information included about user.
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub(crate) struct Profile {
     pub(crate) status_text: String,
     pub(crate) status_emoji: String,
@@ -92,8 +296,10 @@ pub(crate) struct ChannelWrapper {
     error: Option<String>,
 }
 
-// All available user info, see https://api.slack.com/methods/conversations.info
-#[derive(Deserialize, Serialize, Debug)]
+// All available user info, see https://api.slack.com/methods/conversations.info. Direct messages
+// have no name, topic, purpose and so on, so missing fields are left empty.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[serde(default)]
 pub(crate) struct Channel {
     pub(crate) id: String,
     pub(crate) name: String,
@@ -119,34 +325,149 @@ pub(crate) struct Channel {
     pub(crate) previous_names: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
 pub(crate) struct ChannelDetail {
-    value: String,
+    pub(crate) value: String,
     creator: String,
     last_set: u32,
 }
 
-// Get full details about a user by id.
-pub(crate) async fn users_info(user_id: &str) -> Result<User, String> {
-    let slack_bot_token = env::var("SLACK_BOT_TOKEN")
-        .unwrap_or_else(|_| panic!("slack bot token is not set (starts with 'xoxb')."));
+// Convert the markup Slack adds to message text back to plain text: links are replaced by their
+// label, or the bare link if there's no label, and escaped `&`, `<` and `>` are decoded. User,
+// channel and group mentions are left as is, as commands depend on them.
+pub(crate) fn unescape_text(text: &str) -> String {
+    let re = Regex::new(REGEX_SLACK_LINK).expect("failed to compile REGEX_SLACK_LINK");
+    let text = re.replace_all(text, |cap: &Captures| match cap.get(2) {
+        Some(label) => label.as_str().to_string(),
+        None => cap[1].trim_start_matches("mailto:").to_string(),
+    });
+    // Decode `&amp;` last, so `&amp;lt;` becomes `&lt;` and not `<`.
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
 
-    let user_wrapper: UserWrapper =
-        match surf::post(format!("https://slack.com/api/users.info?user={}", user_id))
+// In dry run mode, see `TAG1BOT_DRYRUN`, nothing is sent to Slack: posts are printed instead, and
+// lookups fail.
+#[cfg(not(test))]
+pub(crate) fn is_dry_run() -> bool {
+    env::var("TAG1BOT_DRYRUN").is_ok()
+}
+
+// Tests always run in dry run mode.
+#[cfg(test)]
+pub(crate) fn is_dry_run() -> bool {
+    true
+}
+
+// Call the Slack API with the bot token, returning the JSON response. `request` builds the
+// request, so it can be sent again if Slack rate limits it: the call is retried after waiting as
+// long as Slack asks, up to MAX_RATE_LIMIT_RETRIES times.
+async fn call_api<T, F>(request: F) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+    F: Fn() -> surf::RequestBuilder,
+{
+    let slack_bot_token = env::var("SLACK_BOT_TOKEN").map_err(|_| {
+        Error::Config("slack bot token is not set (starts with 'xoxb').".to_string())
+    })?;
+
+    let mut retries = 0;
+    loop {
+        let request: surf::Request = request()
             .header("Authorization", format!("Bearer {}", slack_bot_token))
-            .recv_json()
-            .await
+            .into();
+        let method = request.url().path().trim_start_matches("/api/").to_string();
+        let mut response = surf::client().send(request).await?;
+
+        if response.status() == surf::StatusCode::TooManyRequests
+            && retries < MAX_RATE_LIMIT_RETRIES
         {
-            Ok(user_wrapper) => user_wrapper,
-            Err(e) => return Err(e.to_string()),
-        };
+            retries += 1;
+            let retry_after = retry_after(response.header("Retry-After").map(|v| v.as_str()));
+            log::warn!(
+                "rate limited calling {}, retrying in {}s ({}/{})",
+                method,
+                retry_after,
+                retries,
+                MAX_RATE_LIMIT_RETRIES
+            );
+            tokio::time::sleep(Duration::from_secs(retry_after)).await;
+            continue;
+        }
+        // Slack's errors come back as JSON, anything else means Slack isn't answering properly.
+        if response.status().is_server_error() {
+            return Err(Error::Http(format!(
+                "{} returned {}",
+                method,
+                response.status()
+            )));
+        }
+        return response
+            .body_json()
+            .await
+            .map_err(|e| Error::Parse(format!("invalid response from {}: {}", method, e)));
+    }
+}
+
+// How many seconds to wait before retrying a rate limited call, from the `Retry-After` header.
+fn retry_after(header: Option<&str>) -> u64 {
+    header
+        .and_then(|seconds| seconds.trim().parse::<u64>().ok())
+        .unwrap_or(1)
+        .clamp(1, MAX_RETRY_AFTER_SECONDS)
+}
+
+// Look up the bot's own user id with auth.test, and remember it for `bot_user_id()`.
+pub(crate) async fn resolve_bot_user_id() -> Result<String, Error> {
+    if is_dry_run() {
+        return Err(Error::Config("not available in dry run".to_string()));
+    }
+    let auth_test_wrapper: AuthTestWrapper =
+        call_api(|| surf::post("https://slack.com/api/auth.test")).await?;
+
+    if let Some(user_id) = auth_test_wrapper.user_id {
+        set_bot_user_id(&user_id);
+        Ok(user_id)
+    } else {
+        Err(Error::Slack(
+            auth_test_wrapper
+                .error
+                .unwrap_or_else(|| "no user_id returned".to_string()),
+        ))
+    }
+}
+
+// Remember the bot's own user id. It doesn't change, so only the first call has an effect.
+pub(crate) fn set_bot_user_id(user_id: &str) {
+    let _ = BOT_USER_ID.set(user_id.to_string());
+}
+
+// The bot's own user id, if it was resolved at startup.
+pub(crate) fn bot_user_id() -> Option<&'static str> {
+    BOT_USER_ID.get().map(|id| id.as_str())
+}
+
+// Get full details about a user by id, reusing details looked up in the last
+// `INFO_CACHE_SECONDS`.
+pub(crate) async fn users_info(user_id: &str) -> Result<User, Error> {
+    if let Some(user) = cached(&USERS, user_id, util::timestamp_now()) {
+        return Ok(user);
+    }
+    if is_dry_run() {
+        return Err(Error::Config("not available in dry run".to_string()));
+    }
+    let user_wrapper: UserWrapper =
+        call_api(|| surf::post(format!("https://slack.com/api/users.info?user={}", user_id)))
+            .await?;
 
     // No need to check `ok`, just check if the user exists.
     if let Some(user) = user_wrapper.user {
+        cache(&USERS, user_id, &user, util::timestamp_now());
         Ok(user)
     // Otherwise we got an error.
     } else if let Some(error) = user_wrapper.error {
-        Err(error)
+        Err(Error::Slack(error))
     } else {
         // Debug output if somehow this happened:
         log::error!("user_wrapper: {:#?}", user_wrapper);
@@ -154,29 +475,30 @@ pub(crate) async fn users_info(user_id: &str) -> Result<User, String> {
     }
 }
 
-// Get full details about a channel by id.
-pub(crate) async fn channels_info(channel_id: &str) -> Result<Channel, String> {
-    let slack_bot_token = env::var("SLACK_BOT_TOKEN")
-        .unwrap_or_else(|_| panic!("slack bot token is not set (starts with 'xoxb')."));
-
-    let channel_wrapper: ChannelWrapper = match surf::post(format!(
-        "https://slack.com/api/conversations.info?channel={}",
-        channel_id
-    ))
-    .header("Authorization", format!("Bearer {}", slack_bot_token))
-    .recv_json()
-    .await
-    {
-        Ok(channel_wrapper) => channel_wrapper,
-        Err(e) => return Err(e.to_string()),
-    };
+// Get full details about a channel by id, reusing details looked up in the last
+// `INFO_CACHE_SECONDS`.
+pub(crate) async fn channels_info(channel_id: &str) -> Result<Channel, Error> {
+    if is_dry_run() {
+        return Err(Error::Config("not available in dry run".to_string()));
+    }
+    if let Some(channel) = cached(&CHANNELS, channel_id, util::timestamp_now()) {
+        return Ok(channel);
+    }
+    let channel_wrapper: ChannelWrapper = call_api(|| {
+        surf::post(format!(
+            "https://slack.com/api/conversations.info?channel={}",
+            channel_id
+        ))
+    })
+    .await?;
 
     // No need to check `ok`, just check if the channel exists.
     if let Some(channel) = channel_wrapper.channel {
+        cache(&CHANNELS, channel_id, &channel, util::timestamp_now());
         Ok(channel)
     // Otherwise we got an error.
     } else if let Some(error) = channel_wrapper.error {
-        Err(error)
+        Err(Error::Slack(error))
     } else {
         // Debug output if somehow this happened:
         log::error!("channel_wrapper: {:#?}", channel_wrapper);
@@ -184,45 +506,1082 @@ pub(crate) async fn channels_info(channel_id: &str) -> Result<Channel, String> {
     }
 }
 
+// Remember a user as if they were just looked up, for tests that need a user's details.
+#[cfg(test)]
+pub(crate) fn cache_user(user: User) {
+    cache(&USERS, &user.id, &user, util::timestamp_now());
+}
+
+// Get details looked up less than `INFO_CACHE_SECONDS` ago.
+fn cached<T: Clone>(cache: &Mutex<HashMap<String, (u64, T)>>, id: &str, now: u64) -> Option<T> {
+    let cache = cache.lock().unwrap();
+    let (looked_up, value) = cache.get(id)?;
+    if now < looked_up + INFO_CACHE_SECONDS {
+        Some(value.clone())
+    } else {
+        None
+    }
+}
+
+// Remember details that were just looked up, forgetting any that are no longer fresh.
+fn cache<T: Clone>(cache: &Mutex<HashMap<String, (u64, T)>>, id: &str, value: &T, now: u64) {
+    let mut cache = cache.lock().unwrap();
+    cache.retain(|_, (looked_up, _)| now < *looked_up + INFO_CACHE_SECONDS);
+    cache.insert(id.to_string(), (now, value.clone()));
+}
+
+// Calls to chat.getPermalink return the following.
+#[derive(Deserialize, Serialize, Debug)]
+struct PermalinkWrapper {
+    ok: bool,
+    permalink: Option<String>,
+    error: Option<String>,
+}
+
+// Get a link to a message.
+pub(crate) async fn get_permalink(channel_id: &str, ts: &str) -> Result<String, Error> {
+    if is_dry_run() {
+        return Err(Error::Config("not available in dry run".to_string()));
+    }
+    let permalink_wrapper: PermalinkWrapper = call_api(|| {
+        surf::get(format!(
+            "https://slack.com/api/chat.getPermalink?channel={}&message_ts={}",
+            channel_id, ts
+        ))
+    })
+    .await?;
+
+    if let Some(permalink) = permalink_wrapper.permalink {
+        Ok(permalink)
+    } else {
+        Err(Error::Slack(
+            permalink_wrapper
+                .error
+                .unwrap_or_else(|| "no permalink returned".to_string()),
+        ))
+    }
+}
+
+// Calls to conversations.replies return the following.
+#[derive(Deserialize, Serialize, Debug)]
+struct RepliesWrapper {
+    ok: bool,
+    messages: Option<Vec<HistoryMessage>>,
+    error: Option<String>,
+    response_metadata: Option<ResponseMetadata>,
+}
+
+// Calls to methods returning lists include the following, see
+// https://api.slack.com/docs/pagination.
+#[derive(Deserialize, Serialize, Debug)]
+struct ResponseMetadata {
+    next_cursor: Option<String>,
+}
+
+impl ResponseMetadata {
+    // The cursor to fetch the next page with, or `None` if this is the last page.
+    fn next_cursor(metadata: Option<ResponseMetadata>) -> Option<String> {
+        metadata
+            .and_then(|metadata| metadata.next_cursor)
+            .filter(|cursor| !cursor.is_empty())
+    }
+}
+
+// Query string sent to conversations.replies.
+#[derive(Serialize)]
+struct RepliesQuery<'a> {
+    channel: &'a str,
+    ts: &'a str,
+    limit: u32,
+    cursor: Option<&'a str>,
+}
+
+// A message previously posted to a channel.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct HistoryMessage {
+    pub(crate) ts: String,
+    pub(crate) text: String,
+    pub(crate) user: Option<String>,
+    pub(crate) thread_ts: Option<String>,
+}
+
+// Get a message previously posted to a channel. conversations.replies finds both messages in the
+// channel and replies in threads, which conversations.history doesn't.
+pub(crate) async fn conversations_message(
+    channel_id: &str,
+    ts: &str,
+) -> Result<HistoryMessage, Error> {
+    if is_dry_run() {
+        return Err(Error::Config("not available in dry run".to_string()));
+    }
+    let messages = paginate(|cursor| async move {
+        let query = RepliesQuery {
+            channel: channel_id,
+            ts,
+            limit: 200,
+            cursor: cursor.as_deref(),
+        };
+        let replies_wrapper: RepliesWrapper = call_api(|| {
+            surf::get("https://slack.com/api/conversations.replies")
+                .query(&query)
+                .expect("failed to serialize query")
+        })
+        .await?;
+
+        if let Some(messages) = replies_wrapper.messages {
+            Ok((
+                messages,
+                ResponseMetadata::next_cursor(replies_wrapper.response_metadata),
+            ))
+        } else {
+            Err(Error::Slack(
+                replies_wrapper
+                    .error
+                    .unwrap_or_else(|| "no messages returned".to_string()),
+            ))
+        }
+    })
+    .await?;
+
+    messages
+        .into_iter()
+        .find(|message| message.ts == ts)
+        .ok_or_else(|| Error::Slack(format!("message {} not found", ts)))
+}
+
+// Collect every page of a Slack list method. `fetch_page` is called with the cursor of the page to
+// fetch, `None` for the first page, and returns the page's items and the next page's cursor, if
+// there is one. Stops after MAX_PAGES, in case Slack keeps returning cursors.
+pub(crate) async fn paginate<T, E, F, Fut>(mut fetch_page: F) -> Result<Vec<T>, E>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = Result<(Vec<T>, Option<String>), E>>,
+{
+    let mut items = Vec::new();
+    let mut cursor = None;
+    for _ in 0..MAX_PAGES {
+        let (page, next_cursor) = fetch_page(cursor).await?;
+        items.extend(page);
+        match next_cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => return Ok(items),
+        }
+    }
+    log::warn!("stopped after {} pages of results", MAX_PAGES);
+    Ok(items)
+}
+
+// Calls to usergroups.list return the following.
+#[derive(Deserialize, Serialize, Debug)]
+struct UserGroupsWrapper {
+    ok: bool,
+    usergroups: Option<Vec<UserGroup>>,
+    error: Option<String>,
+}
+
+// Details about a user group, for example `@frontend`.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct UserGroup {
+    pub(crate) id: String,
+    pub(crate) handle: String,
+    pub(crate) name: String,
+}
+
+// Look up a user group. Slack has no `usergroups.info`, so find it in `usergroups.list`.
+pub(crate) async fn usergroups_info(usergroup_id: &str) -> Result<UserGroup, Error> {
+    if is_dry_run() {
+        return Err(Error::Config("not available in dry run".to_string()));
+    }
+    let usergroups_wrapper: UserGroupsWrapper =
+        call_api(|| surf::post("https://slack.com/api/usergroups.list")).await?;
+
+    if let Some(usergroups) = usergroups_wrapper.usergroups {
+        usergroups
+            .into_iter()
+            .find(|usergroup| usergroup.id == usergroup_id)
+            .ok_or_else(|| Error::Slack(format!("user group {} not found", usergroup_id)))
+    } else {
+        Err(Error::Slack(
+            usergroups_wrapper
+                .error
+                .unwrap_or_else(|| "no user groups returned".to_string()),
+        ))
+    }
+}
+
 // Post a message into the specified channel.
 pub(crate) async fn post_text(channel_id: &str, text: &str) {
-    let slack_bot_token = env::var("SLACK_BOT_TOKEN")
-        .unwrap_or_else(|_| panic!("slack bot token is not set (starts with 'xoxb')."));
+    if is_dry_run() {
+        println!("[{}] {}", channel_id, text);
+        return;
+    }
+    // Send the text in a JSON body rather than the query string, so it needs no URL encoding.
+    post_messages(VecDeque::from([JsonMessage::new(channel_id, text)])).await;
+}
+
+// Post messages in order. If a message fails to post for a reason that may pass, such as a
+// network error, it's retried in the background along with the messages after it, so they stay
+// in order and nobody waits on the retries.
+async fn post_messages(messages: VecDeque<JsonMessage>) {
+    if let Some(messages) = try_post_messages(messages).await {
+        tokio::spawn(retry_messages(messages));
+    }
+}
 
-    let message = JsonMessage {
+// Retry posting messages with backoff, giving up after the last retry.
+async fn retry_messages(mut messages: VecDeque<JsonMessage>) {
+    for (retry, backoff) in POST_RETRY_BACKOFF_SECONDS.iter().enumerate() {
+        log::warn!(
+            "retrying {} message(s) to {} in {}s ({}/{})",
+            messages.len(),
+            messages[0].channel,
+            backoff,
+            retry + 1,
+            POST_RETRY_BACKOFF_SECONDS.len()
+        );
+        tokio::time::sleep(Duration::from_secs(*backoff)).await;
+        messages = match try_post_messages(messages).await {
+            Some(messages) => messages,
+            None => return,
+        };
+    }
+    log::error!(
+        "gave up posting {} message(s) to {}",
+        messages.len(),
+        messages[0].channel
+    );
+}
+
+// Post messages in order, stopping at the first that fails. Returns the failed message and those
+// after it if posting them again may work.
+async fn try_post_messages(mut messages: VecDeque<JsonMessage>) -> Option<VecDeque<JsonMessage>> {
+    while let Some(message) = messages.front() {
+        match post_message(message).await {
+            Ok(()) => {
+                log::info!("posted message to {}", message.channel);
+                messages.pop_front();
+            }
+            Err(e) if is_retryable(&e) => {
+                log::warn!("failed to post message to {}: {}", message.channel, e);
+                return Some(messages);
+            }
+            Err(e) => {
+                log::error!("failed to post message to {}: {}", message.channel, e);
+                return None;
+            }
+        }
+    }
+    None
+}
+
+// Post a message with chat.postMessage.
+async fn post_message(message: &JsonMessage) -> Result<(), Error> {
+    let response: OkWrapper = call_api(|| {
+        surf::post("https://slack.com/api/chat.postMessage")
+            .body_json(message)
+            .expect("failed to serialize json")
+    })
+    .await?;
+
+    if response.ok {
+        Ok(())
+    } else {
+        Err(Error::Slack(response.error.unwrap_or_default()))
+    }
+}
+
+// Determine if an error posting a message may pass: network errors, and some errors from Slack.
+fn is_retryable(error: &Error) -> bool {
+    match error {
+        Error::Http(_) => true,
+        Error::Slack(error) => RETRYABLE_ERRORS.contains(&error.as_str()),
+        _ => false,
+    }
+}
+
+// Post a message into the specified channel that is only visible to the specified user.
+pub(crate) async fn post_ephemeral(
+    channel_id: &str,
+    user_id: &str,
+    thread_ts: Option<&str>,
+    text: &str,
+) {
+    if is_dry_run() {
+        println!("(only visible to you) {}", text);
+        return;
+    }
+    let message = JsonEphemeral {
         channel: channel_id.to_string(),
+        user: user_id.to_string(),
         text: text.to_string(),
-        mrkdwn: true,
+        thread_ts: thread_ts.map(|t| t.to_string()),
+    };
+
+    let response: OkWrapper = match call_api(|| {
+        surf::post("https://slack.com/api/chat.postEphemeral")
+            .body_json(&message)
+            .expect("failed to serialize json")
+    })
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            log::error!("failed to post ephemeral message: {}", e);
+            return;
+        }
     };
 
-    let res = surf::post("https://slack.com/api/chat.postMessage")
-        .header("Authorization", format!("Bearer {}", slack_bot_token))
-        .body_json(&message)
-        .expect("failed to serialize json")
+    if !response.ok {
+        log::error!(
+            "failed to post ephemeral message: {}",
+            response.error.unwrap_or_default()
+        );
+    }
+}
+
+// Answer a slash command through its `response_url`, which needs no token and works for 30 minutes
+// after the command, see https://api.slack.com/interactivity/handling#message_responses.
+pub(crate) async fn post_response(
+    response_url: &str,
+    response: &serde_json::Value,
+) -> Result<(), Error> {
+    if is_dry_run() {
+        println!("{}", response["text"].as_str().unwrap_or_default());
+        return Ok(());
+    }
+    let response = surf::post(response_url).body_json(response)?.await?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(Error::Http(format!(
+            "response_url returned {}",
+            response.status()
+        )))
+    }
+}
+
+// Reply privately to whoever sent the message, for errors and help that only matter to them.
+// Bot messages have no user to reply to privately, so the reply is returned to be posted in the
+// thread instead.
+pub(crate) async fn reply_ephemeral(
+    message: &Message,
+    reply_message: String,
+) -> Option<(String, String)> {
+    if let Some(replies) = message.ephemeral_replies.as_ref() {
+        replies.lock().unwrap().push(reply_message);
+        None
+    } else if let Some(user_id) = message.user_id.as_ref() {
+        post_ephemeral(
+            &message.channel_id,
+            user_id,
+            message.thread_ts.as_deref(),
+            &reply_message,
+        )
         .await;
+        None
+    } else {
+        Some((reply_thread_ts(message), reply_message))
+    }
+}
+
+// Always reply in a thread: determine if reply is in a new thread or an existing thread.
+pub(crate) fn reply_thread_ts(message: &Message) -> String {
+    if let Some(thread_ts) = message.thread_ts.as_ref() {
+        thread_ts.clone()
+    } else {
+        message.ts.clone()
+    }
+}
 
-    println!("{:?}", res);
+// Who is allowed to run a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AuthLevel {
+    Anyone,
+    Admin,
+    // Not yet required by any command.
+    #[allow(dead_code)]
+    Owner,
+}
+
+// Determine if a user is allowed to run commands requiring `level`. Owners can run admin
+// commands.
+pub(crate) fn is_authorized(user: &User, level: AuthLevel) -> bool {
+    match level {
+        AuthLevel::Anyone => true,
+        AuthLevel::Admin => user.is_admin || user.is_owner,
+        AuthLevel::Owner => user.is_owner,
+    }
+}
+
+// Determine if whoever sent the message is allowed to run commands requiring `level`. Bot
+// messages are only allowed to run commands anyone can run.
+pub(crate) async fn message_is_authorized(message: &Message, level: AuthLevel) -> bool {
+    match message.user().await {
+        Some(user) => is_authorized(user, level),
+        None => level == AuthLevel::Anyone,
+    }
+}
+
+// Privately tell whoever sent the message that the command requires `level`.
+pub(crate) async fn reply_unauthorized(
+    message: &Message,
+    level: AuthLevel,
+) -> Option<(String, String)> {
+    let reply_message = match level {
+        AuthLevel::Owner => "Sorry, that's owner-only.",
+        _ => "Sorry, that's admin-only.",
+    };
+    reply_ephemeral(message, reply_message.to_string()).await
+}
+
+// React to a message with an emoji, for example `thumbsup`.
+pub(crate) async fn add_reaction(channel_id: &str, ts: &str, emoji: &str) {
+    if is_dry_run() {
+        println!(":{}:", emoji);
+        return;
+    }
+    let reaction = JsonReaction {
+        channel: channel_id.to_string(),
+        timestamp: ts.to_string(),
+        name: emoji.to_string(),
+    };
+
+    let response: OkWrapper = match call_api(|| {
+        surf::post("https://slack.com/api/reactions.add")
+            .body_json(&reaction)
+            .expect("failed to serialize json")
+    })
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            log::error!("failed to add reaction {}: {}", emoji, e);
+            return;
+        }
+    };
+
+    if !response.ok {
+        log::error!(
+            "failed to add reaction {}: {}",
+            emoji,
+            response.error.unwrap_or_default()
+        );
+    }
 }
 
 // Reply to a specific message in a thread.
-pub(crate) async fn reply_in_thread<S>(
-    socket_mode: &SocketMode<S>,
+pub(crate) async fn reply_in_thread(
     message: &Message,
     reply_thread_ts: String,
     reply_message: String,
-) where
-    S: SlackWebAPIClient,
-{
-    let request = PostMessageRequest {
-        channel: message.channel.id.clone(),
-        thread_ts: Some(reply_thread_ts),
-        text: Some(reply_message),
-        mrkdwn: Some(true),
-        ..Default::default()
+) {
+    post_in_thread(&message.channel_id, &reply_thread_ts, &reply_message, false).await;
+}
+
+// Post a reply in a thread, if `reply_broadcast` also showing it in the channel for people not
+// following the thread.
+pub(crate) async fn post_in_thread(
+    channel_id: &str,
+    reply_thread_ts: &str,
+    reply_message: &str,
+    reply_broadcast: bool,
+) {
+    if is_dry_run() {
+        println!("{}", reply_message);
+        return;
+    }
+
+    // Long replies are posted as several messages in the same thread.
+    let mut replies = VecDeque::new();
+    for (index, text) in split_message(reply_message, max_message_length())
+        .into_iter()
+        .enumerate()
+    {
+        let mut reply = JsonMessage::new(channel_id, &text);
+        reply.thread_ts = Some(reply_thread_ts.to_string());
+        // Only broadcast the start of a long reply, rather than flooding the channel.
+        if reply_broadcast && index == 0 {
+            reply.reply_broadcast = Some(true);
+        }
+        replies.push_back(reply);
+    }
+    post_messages(replies).await;
+}
+
+// The longest message to post, configurable with `SLACK_MAX_MESSAGE_LENGTH`. Slack rejects
+// messages over 40,000 characters, and renders much shorter messages badly.
+fn max_message_length() -> usize {
+    env::var("SLACK_MAX_MESSAGE_LENGTH")
+        .ok()
+        .and_then(|max_length| max_length.parse::<usize>().ok())
+        .filter(|max_length| *max_length > 0)
+        .unwrap_or(DEFAULT_MAX_MESSAGE_LENGTH)
+        .min(40_000)
+}
+
+// The longest reply a feature posts, configurable with `MAX_REPLY_LENGTH_<FEATURE>`, for example
+// `MAX_REPLY_LENGTH_CHATGPT`.
+pub(crate) fn max_reply_length(feature: &str) -> usize {
+    let default = DEFAULT_MAX_REPLY_LENGTHS
+        .iter()
+        .find(|(name, _)| *name == feature)
+        .map(|(_, max_length)| *max_length)
+        .unwrap_or(40_000);
+    let name = format!("MAX_REPLY_LENGTH_{}", feature.to_uppercase());
+    match env::var(&name) {
+        Ok(max_length) => match max_length.trim().parse::<usize>() {
+            Ok(max_length) if max_length > 0 => max_length,
+            _ => {
+                log::warn!("invalid {} {}", name, max_length);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+// Truncate text to at most `max_length` characters, breaking between words where possible and
+// closing an open code block.
+pub(crate) fn truncate_reply(text: &str, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_string();
+    }
+    // Leave room to close a code block and mark the text as truncated.
+    let room = max_length.saturating_sub(CODE_FENCE.len() + 2);
+    let cut: String = text.chars().take(room).collect();
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(index) if index > room / 2 => &cut[..index],
+        _ => cut.as_str(),
+    };
+    let mut truncated = cut.trim_end().to_string();
+    truncated.push_str(TRUNCATED);
+    if !truncated.matches(CODE_FENCE).count().is_multiple_of(2) {
+        truncated.push('\n');
+        truncated.push_str(CODE_FENCE);
+    }
+    truncated
+}
+
+// Split text into messages of at most `max_length` characters. Splits between paragraphs where
+// possible, and a fenced code block is only split if it doesn't fit in a message on its own.
+pub(crate) fn split_message(text: &str, max_length: usize) -> Vec<String> {
+    if text.chars().count() <= max_length {
+        return vec![text.to_string()];
+    }
+    pack(paragraphs(text), "\n\n", max_length, split_lines)
+}
+
+// Split text into paragraphs separated by blank lines, keeping each fenced code block in a single
+// paragraph even if it contains blank lines.
+fn paragraphs(text: &str) -> Vec<String> {
+    let mut paragraphs = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut in_fence = false;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+        }
+        if !in_fence && line.trim().is_empty() {
+            if !paragraph.is_empty() {
+                paragraphs.push(paragraph.join("\n"));
+                paragraph.clear();
+            }
+        } else {
+            paragraph.push(line);
+        }
+    }
+    if !paragraph.is_empty() {
+        paragraphs.push(paragraph.join("\n"));
+    }
+    paragraphs
+}
+
+// Split a paragraph that's too long for one message between lines. If a fenced code block has
+// to be split, it's closed at the end of one message and reopened at the start of the next, so
+// each part is still formatted as code.
+fn split_lines(paragraph: &str, max_length: usize) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut message = String::new();
+    // The line that opened the fenced code block being split, if any.
+    let mut fence: Option<&str> = None;
+    for line in paragraph.lines() {
+        let is_fence = line.trim_start().starts_with(CODE_FENCE);
+        // Room needed to reopen the fence before this line and close it after.
+        let overhead = fence.map_or(0, |open| open.chars().count() + CODE_FENCE.len() + 2);
+        let pieces = if line.chars().count() + overhead > max_length {
+            split_words(line, max_length.saturating_sub(overhead).max(1))
+        } else {
+            vec![line.to_string()]
+        };
+        for piece in pieces {
+            // Leave room to close the fence, unless this line closes it.
+            let closing = if fence.is_some() && !is_fence {
+                CODE_FENCE.len() + 1
+            } else {
+                0
+            };
+            if message.is_empty() {
+                message = piece;
+            } else if message.chars().count() + 1 + piece.chars().count() + closing <= max_length {
+                message.push('\n');
+                message.push_str(&piece);
+            } else if let Some(open) = fence {
+                message.push('\n');
+                message.push_str(CODE_FENCE);
+                messages.push(std::mem::replace(
+                    &mut message,
+                    format!("{}\n{}", open, piece),
+                ));
+            } else {
+                messages.push(std::mem::replace(&mut message, piece));
+            }
+        }
+        if is_fence {
+            fence = if fence.is_some() { None } else { Some(line) };
+        }
+    }
+    if !message.is_empty() {
+        messages.push(message);
+    }
+    messages
+}
+
+// Split a line that's too long for one message between words.
+fn split_words(line: &str, max_length: usize) -> Vec<String> {
+    pack(
+        line.split(' ').map(str::to_string).collect(),
+        " ",
+        max_length,
+        split_chars,
+    )
+}
+
+// Split a word that's too long for one message, as a last resort.
+fn split_chars(line: &str, max_length: usize) -> Vec<String> {
+    line.chars()
+        .collect::<Vec<_>>()
+        .chunks(max_length)
+        .map(|chunk| chunk.iter().collect())
+        .collect()
+}
+
+// Combine pieces into as few messages of at most `max_length` characters as possible, using
+// `split` to break up any piece that is too long on its own.
+fn pack(
+    pieces: Vec<String>,
+    separator: &str,
+    max_length: usize,
+    split: fn(&str, usize) -> Vec<String>,
+) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut message = String::new();
+    for piece in pieces {
+        let pieces = if piece.chars().count() > max_length {
+            split(&piece, max_length)
+        } else {
+            vec![piece]
+        };
+        for piece in pieces {
+            if message.is_empty() {
+                message = piece;
+            } else if message.chars().count() + separator.len() + piece.chars().count()
+                <= max_length
+            {
+                message.push_str(separator);
+                message.push_str(&piece);
+            } else {
+                messages.push(std::mem::replace(&mut message, piece));
+            }
+        }
+    }
+    if !message.is_empty() {
+        messages.push(message);
+    }
+    messages
+}
+
+// Determine if an HTTP request was sent by Slack: `signature` is the `X-Slack-Signature` header,
+// made with the app's signing secret from the `X-Slack-Request-Timestamp` header and the body, as
+// described at https://api.slack.com/authentication/verifying-requests-from-slack. Requests signed
+// more than 5 minutes ago are rejected, so captured requests can't be replayed.
+pub(crate) fn verify_signature(
+    signing_secret: &str,
+    timestamp: &str,
+    body: &[u8],
+    signature: &str,
+) -> bool {
+    verify_signature_at(
+        signing_secret,
+        timestamp,
+        body,
+        signature,
+        util::timestamp_now(),
+    )
+}
+
+// Verify a signature as if it's `now`.
+fn verify_signature_at(
+    signing_secret: &str,
+    timestamp: &str,
+    body: &[u8],
+    signature: &str,
+    now: u64,
+) -> bool {
+    let timestamp_seconds = match timestamp.parse::<u64>() {
+        Ok(timestamp_seconds) => timestamp_seconds,
+        Err(_) => return false,
+    };
+    if now.abs_diff(timestamp_seconds) > MAX_REQUEST_AGE_SECONDS {
+        return false;
+    }
+    let signature = match signature.strip_prefix("v0=").and_then(decode_hex) {
+        Some(signature) => signature,
+        None => return false,
     };
 
-    let response = post_message(&socket_mode.api_client, &request, &socket_mode.bot_token)
-        .await
-        .expect("post message api error.");
-    log::info!("post message api response: {:?}", response);
+    let mut mac = Hmac::<Sha256>::new_varkey(signing_secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("v0:{}:", timestamp).as_bytes());
+    mac.update(body);
+    // Compares in constant time.
+    mac.verify(&signature).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example from https://api.slack.com/authentication/verifying-requests-from-slack.
+    const SIGNING_SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const TIMESTAMP: &str = "1531420618";
+    const BODY: &str = "token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&command=%2Fwebhook-collect&text=&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN&trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+    const SIGNATURE: &str = "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503";
+
+    #[test]
+    fn verifies_slack_signatures() {
+        let now = 1531420618 + 60;
+        assert!(verify_signature_at(
+            SIGNING_SECRET,
+            TIMESTAMP,
+            BODY.as_bytes(),
+            SIGNATURE,
+            now
+        ));
+        // A different secret, body, timestamp or signature fails.
+        assert!(!verify_signature_at(
+            "wrong",
+            TIMESTAMP,
+            BODY.as_bytes(),
+            SIGNATURE,
+            now
+        ));
+        assert!(!verify_signature_at(
+            SIGNING_SECRET,
+            TIMESTAMP,
+            b"token=forged",
+            SIGNATURE,
+            now
+        ));
+        assert!(!verify_signature_at(
+            SIGNING_SECRET,
+            "1531420619",
+            BODY.as_bytes(),
+            SIGNATURE,
+            now
+        ));
+        assert!(!verify_signature_at(
+            SIGNING_SECRET,
+            TIMESTAMP,
+            BODY.as_bytes(),
+            "v0=a2114d57",
+            now
+        ));
+        assert!(!verify_signature_at(
+            SIGNING_SECRET,
+            TIMESTAMP,
+            BODY.as_bytes(),
+            "a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503",
+            now
+        ));
+    }
+
+    #[test]
+    fn rejects_old_signatures() {
+        let now = 1531420618 + MAX_REQUEST_AGE_SECONDS + 1;
+        assert!(!verify_signature_at(
+            SIGNING_SECRET,
+            TIMESTAMP,
+            BODY.as_bytes(),
+            SIGNATURE,
+            now
+        ));
+    }
+
+    #[test]
+    fn rejects_replayed_signatures() {
+        // Slack's example was signed long ago.
+        assert!(!verify_signature(
+            SIGNING_SECRET,
+            TIMESTAMP,
+            BODY.as_bytes(),
+            SIGNATURE
+        ));
+    }
+
+    #[tokio::test]
+    async fn paginate_follows_cursors() {
+        let mut cursors = Vec::new();
+        let items = paginate(|cursor| {
+            cursors.push(cursor.clone());
+            async move {
+                match cursor.as_deref() {
+                    None => Ok((vec![1, 2], Some("page2".to_string()))),
+                    Some("page2") => Ok((vec![3], None)),
+                    Some(cursor) => Err(format!("unexpected cursor {}", cursor)),
+                }
+            }
+        })
+        .await;
+        assert_eq!(items, Ok(vec![1, 2, 3]));
+        assert_eq!(cursors, vec![None, Some("page2".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn paginate_stops_on_errors_and_endless_cursors() {
+        let items: Result<Vec<u32>, String> =
+            paginate(|_| async { Err("ratelimited".to_string()) }).await;
+        assert_eq!(items, Err("ratelimited".to_string()));
+
+        let items: Result<Vec<u32>, String> =
+            paginate(|_| async { Ok((vec![1], Some("again".to_string()))) }).await;
+        assert_eq!(items.unwrap().len(), MAX_PAGES);
+    }
+
+    #[test]
+    fn next_cursor_ignores_empty_cursors() {
+        let last_page: ResponseMetadata = serde_json::from_str(r#"{"next_cursor": ""}"#).unwrap();
+        assert_eq!(ResponseMetadata::next_cursor(Some(last_page)), None);
+        let next_page: ResponseMetadata =
+            serde_json::from_str(r#"{"next_cursor": "dXNlcjpVMDYxTkZUVDI="}"#).unwrap();
+        assert_eq!(
+            ResponseMetadata::next_cursor(Some(next_page)).as_deref(),
+            Some("dXNlcjpVMDYxTkZUVDI=")
+        );
+        assert_eq!(ResponseMetadata::next_cursor(None), None);
+    }
+
+    #[test]
+    fn retry_after_is_bounded() {
+        assert_eq!(retry_after(Some("30")), 30);
+        assert_eq!(retry_after(Some(" 5 ")), 5);
+        // Missing or invalid headers wait a second, and long waits are capped.
+        assert_eq!(retry_after(None), 1);
+        assert_eq!(retry_after(Some("soon")), 1);
+        assert_eq!(retry_after(Some("0")), 1);
+        assert_eq!(retry_after(Some("3600")), MAX_RETRY_AFTER_SECONDS);
+    }
+
+    #[test]
+    fn post_text_body_preserves_special_characters() {
+        let text = "1 BTC & 2 ETH #alert?\n<https://example.com|chart>";
+        let body = serde_json::to_string(&JsonMessage::new("C123", text)).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["channel"], "C123");
+        assert_eq!(value["text"], text);
+        assert_eq!(value["mrkdwn"], true);
+        assert!(value.get("thread_ts").is_none());
+        assert!(value.get("reply_broadcast").is_none());
+    }
+
+    #[test]
+    fn thread_reply_body_broadcasts() {
+        let mut message = JsonMessage::new("C123", "CURRENCY ALERT");
+        message.thread_ts = Some("1700000000.000100".to_string());
+        message.reply_broadcast = Some(true);
+        let body = serde_json::to_string(&message).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["thread_ts"], "1700000000.000100");
+        assert_eq!(value["reply_broadcast"], true);
+    }
+
+    #[test]
+    fn unescape_text_links() {
+        assert_eq!(
+            unescape_text("see <https://example.com|the docs>"),
+            "see the docs"
+        );
+        assert_eq!(
+            unescape_text("see <https://example.com/a?b=1&amp;c=2>"),
+            "see https://example.com/a?b=1&c=2"
+        );
+        assert_eq!(
+            unescape_text("email <mailto:a@b.com|a@b.com> or <mailto:c@d.com>"),
+            "email a@b.com or c@d.com"
+        );
+    }
+
+    #[test]
+    fn unescape_text_keeps_mentions() {
+        assert_eq!(unescape_text("<@U12345>++"), "<@U12345>++");
+        assert_eq!(
+            unescape_text("seen foo in <#C12345|general>?"),
+            "seen foo in <#C12345|general>?"
+        );
+        assert_eq!(
+            unescape_text("<!subteam^S12345|@frontend>++"),
+            "<!subteam^S12345|@frontend>++"
+        );
+    }
+
+    #[test]
+    fn unescape_text_entities() {
+        assert_eq!(
+            unescape_text("alert me when 1 BTC &gt; 100000 USD"),
+            "alert me when 1 BTC > 100000 USD"
+        );
+        assert_eq!(unescape_text("a &lt; b &amp;&amp; c"), "a < b && c");
+        assert_eq!(unescape_text("&amp;lt;"), "&lt;");
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let slack_error = |error: &str| Error::Slack(error.to_string());
+        assert!(is_retryable(&Error::Http("connection reset".to_string())));
+        assert!(is_retryable(&slack_error("internal_error")));
+        assert!(is_retryable(&slack_error("service_unavailable")));
+        assert!(!is_retryable(&slack_error("channel_not_found")));
+        assert!(!is_retryable(&slack_error("not_in_channel")));
+        assert!(!is_retryable(&slack_error("msg_too_long")));
+        assert!(!is_retryable(&Error::Config(
+            "not available in dry run".to_string()
+        )));
+    }
+
+    #[test]
+    fn truncate_reply_leaves_short_replies_alone() {
+        assert_eq!(
+            truncate_reply("1 USD is 0.92 EUR.", 100),
+            "1 USD is 0.92 EUR."
+        );
+    }
+
+    #[test]
+    fn truncate_reply_breaks_between_words() {
+        let text = vec!["word"; 100].join(" ");
+        let truncated = truncate_reply(&text, 50);
+        assert!(truncated.chars().count() <= 50, "{}", truncated);
+        assert!(truncated.ends_with("word…"), "{}", truncated);
+    }
+
+    #[test]
+    fn truncate_reply_closes_code_blocks() {
+        let text = format!("Try this:\n```\n{}\n```", "x".repeat(100));
+        let truncated = truncate_reply(&text, 40);
+        assert!(truncated.chars().count() <= 40, "{}", truncated);
+        assert!(truncated.ends_with("…\n```"), "{}", truncated);
+    }
+
+    #[test]
+    fn max_reply_length_defaults() {
+        assert_eq!(max_reply_length("karma"), 1_000);
+        assert_eq!(max_reply_length("chatgpt"), 20_000);
+        assert_eq!(max_reply_length("stats"), 40_000);
+    }
+
+    #[test]
+    fn split_message_leaves_short_messages_alone() {
+        assert_eq!(split_message("Hello.", 100), vec!["Hello."]);
+    }
+
+    #[test]
+    fn split_message_splits_between_paragraphs() {
+        let text = format!(
+            "{}\n\n{}\n\n{}",
+            "a".repeat(40),
+            "b".repeat(40),
+            "c".repeat(40)
+        );
+        let messages = split_message(&text, 100);
+        assert_eq!(
+            messages,
+            vec![
+                format!("{}\n\n{}", "a".repeat(40), "b".repeat(40)),
+                "c".repeat(40)
+            ]
+        );
+    }
+
+    #[test]
+    fn split_message_keeps_code_blocks_together() {
+        let code = "```\nfn main() {\n\n    println!(\"hi\");\n}\n```";
+        let text = format!("{}\n\n{}", "a".repeat(40), code);
+        let messages = split_message(&text, 60);
+        assert_eq!(messages, vec!["a".repeat(40), code.to_string()]);
+    }
+
+    #[test]
+    fn split_message_reopens_split_code_blocks() {
+        let code: Vec<String> = (0..20).map(|i| format!("let x{} = {};", i, i)).collect();
+        let text = format!("Try this:\n```rust\n{}\n```\nDone.", code.join("\n"));
+        let messages = split_message(&text, 80);
+        assert!(messages.len() > 1);
+        for message in &messages {
+            assert!(message.chars().count() <= 80, "{}", message);
+            // Every message has balanced fences.
+            assert_eq!(message.matches(CODE_FENCE).count() % 2, 0, "{}", message);
+        }
+        assert!(messages[0].starts_with("Try this:\n```rust\n"));
+        assert!(messages[1].starts_with("```rust\n"));
+        assert!(messages.last().unwrap().ends_with("```\nDone."));
+        // All the code is still there, in order.
+        let posted = messages.join("\n");
+        let mut position = 0;
+        for line in &code {
+            position += posted[position..].find(line.as_str()).unwrap();
+        }
+    }
+
+    #[test]
+    fn split_message_respects_max_length() {
+        let text = vec!["word"; 1000].join(" ");
+        let messages = split_message(&text, 100);
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|m| m.chars().count() <= 100));
+        assert_eq!(messages.join(" "), text);
+
+        let text = "x".repeat(250);
+        assert_eq!(
+            split_message(&text, 100),
+            vec!["x".repeat(100), "x".repeat(100), "x".repeat(50)]
+        );
+    }
+
+    #[test]
+    fn info_cache_expires() {
+        let channels = Mutex::new(HashMap::new());
+        let channel = Channel {
+            id: "C1".to_string(),
+            name: "general".to_string(),
+            ..Default::default()
+        };
+        assert!(cached(&channels, "C1", 1000).is_none());
+        cache(&channels, "C1", &channel, 1000);
+        assert_eq!(cached(&channels, "C1", 1001).unwrap().name, "general");
+        assert!(cached(&channels, "C2", 1001).is_none());
+        assert!(cached(&channels, "C1", 1000 + INFO_CACHE_SECONDS).is_none());
+
+        // Stale details are forgotten when anything new is cached.
+        cache(&channels, "C2", &channel, 1000 + INFO_CACHE_SECONDS);
+        assert_eq!(channels.lock().unwrap().len(), 1);
+    }
 }
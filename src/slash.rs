@@ -0,0 +1,295 @@
+// Serves Slack slash commands, for example `/convert 1 USD to EUR`, which Slack sends over HTTP
+// instead of through socket mode. Requests are verified with the app's signing secret, then
+// processed the same as a command mentioning the bot.
+//
+// Slack gives up on a slash command that isn't answered within 3 seconds, sooner than ChatGPT or a
+// currency quote may reply, so each command is acknowledged at once and the replies are posted to
+// its `response_url` once ready.
+
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::dispatch;
+use crate::slack;
+use crate::util;
+
+// Slash command requests are small, refuse anything bigger.
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+// The parts of a slash command request that are used, see
+// https://api.slack.com/interactivity/slash-commands#app_command_handling.
+#[derive(Deserialize, Debug)]
+struct SlashCommand {
+    command: String,
+    text: String,
+    user_id: String,
+    channel_id: String,
+    response_url: String,
+}
+
+// An HTTP request, with lower case header names.
+#[derive(Debug)]
+struct Request {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Listen for slash commands on `port` until the bot exits.
+pub(crate) async fn serve(port: u16, signing_secret: String) {
+    let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("failed to bind slash commands to port {}: {}", port, e);
+            return;
+        }
+    };
+    log::warn!("Serving slash commands on port {}", port);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let signing_secret = signing_secret.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream, &signing_secret).await {
+                        log::warn!("failed to respond to slash command: {}", e);
+                    }
+                });
+            }
+            Err(e) => log::error!("failed to accept slash command connection: {}", e),
+        }
+    }
+}
+
+// Verify and process a slash command, replying with the result.
+async fn respond(mut stream: TcpStream, signing_secret: &str) -> std::io::Result<()> {
+    let (status, body) = match read_request(&mut stream).await? {
+        Some(request) => process_request(&request, signing_secret).await,
+        None => ("400 Bad Request", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Read a request, up to MAX_REQUEST_BYTES. Returns `None` if it's malformed or too big.
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Option<Request>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if buffer.len() > MAX_REQUEST_BYTES {
+            return Ok(None);
+        }
+        match parse_request(&buffer) {
+            // Keep reading until the whole body has arrived.
+            Some(Ok(request)) => return Ok(Some(request)),
+            Some(Err(())) => return Ok(None),
+            None => continue,
+        }
+    }
+}
+
+// Parse a complete HTTP request. Returns `None` if more is needed.
+fn parse_request(buffer: &[u8]) -> Option<Result<Request, ()>> {
+    let header_end = buffer.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = match std::str::from_utf8(&buffer[..header_end]) {
+        Ok(head) => head,
+        Err(_) => return Some(Err(())),
+    };
+    // Skip the request line, only POSTs are expected and the path doesn't matter.
+    let headers: Vec<(String, String)> = head
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim().to_lowercase(), value.trim().to_string()))
+        })
+        .collect();
+    let content_length = match headers.iter().find(|(name, _)| name == "content-length") {
+        Some((_, length)) => match length.parse::<usize>() {
+            Ok(length) => length,
+            Err(_) => return Some(Err(())),
+        },
+        None => 0,
+    };
+    let body = &buffer[header_end + 4..];
+    if body.len() < content_length {
+        return None;
+    }
+    Some(Ok(Request {
+        headers,
+        body: body[..content_length].to_vec(),
+    }))
+}
+
+// Verify the request came from Slack, then process the slash command. Returns the HTTP status and
+// JSON body to respond with.
+async fn process_request(request: &Request, signing_secret: &str) -> (&'static str, String) {
+    let (timestamp, signature) = match (
+        request.header("x-slack-request-timestamp"),
+        request.header("x-slack-signature"),
+    ) {
+        (Some(timestamp), Some(signature)) => (timestamp, signature),
+        _ => return ("401 Unauthorized", String::new()),
+    };
+    if !slack::verify_signature(signing_secret, timestamp, &request.body, signature) {
+        log::warn!("rejected slash command with an invalid signature");
+        return ("401 Unauthorized", String::new());
+    }
+
+    let slash_command: SlashCommand = match serde_urlencoded::from_bytes(&request.body) {
+        Ok(slash_command) => slash_command,
+        Err(e) => {
+            log::warn!("failed to parse slash command: {}", e);
+            return ("400 Bad Request", String::new());
+        }
+    };
+    log::info!("slash command: {:?}", slash_command);
+
+    tokio::spawn(async move {
+        for response in process_command(&slash_command).await {
+            if let Err(e) = slack::post_response(&slash_command.response_url, &response).await {
+                log::error!("failed to answer slash command: {}", e);
+            }
+        }
+    });
+    ("200 OK", String::new())
+}
+
+// Process a slash command as if it were said mentioning the bot, ie `/convert 1 USD to EUR` is
+// processed as `convert 1 USD to EUR`. Returns the responses to post: replies are shown in the
+// channel, and private replies, such as errors, only to the user that ran the command.
+async fn process_command(slash_command: &SlashCommand) -> Vec<serde_json::Value> {
+    let text = format!(
+        "{} {}",
+        slash_command.command.trim_start_matches('/'),
+        slack::unescape_text(&slash_command.text)
+    );
+    // Slash commands aren't messages, so have no timestamp of their own.
+    let message = slack::Message::new(
+        slash_command.channel_id.clone(),
+        Some(slash_command.user_id.clone()),
+        text.trim().to_string(),
+        None,
+        format!("{}.000000", util::timestamp_now()),
+        None,
+    )
+    .collecting_ephemeral_replies();
+    let replies = dispatch::process_mention(&message).await;
+    let mut ephemeral_replies = message.take_ephemeral_replies();
+    if replies.is_empty() && ephemeral_replies.is_empty() {
+        ephemeral_replies.push(format!("Sorry, I didn't understand `{}`.", message.text));
+    }
+
+    let mut responses = Vec::new();
+    if !ephemeral_replies.is_empty() {
+        responses.push(serde_json::json!({
+            "response_type": "ephemeral",
+            "text": ephemeral_replies.join("\n"),
+        }));
+    }
+    if !replies.is_empty() {
+        let text = replies
+            .into_iter()
+            .map(|(_, reply_message)| reply_message)
+            .collect::<Vec<_>>()
+            .join("\n");
+        responses.push(serde_json::json!({
+            "response_type": "in_channel",
+            "text": text,
+        }));
+    }
+    responses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const TIMESTAMP: &str = "1531420618";
+    const BODY: &str = "token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&command=%2Fwebhook-collect&text=&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN&trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+
+    #[test]
+    fn parses_requests() {
+        let request = format!(
+            "POST /slack/commands HTTP/1.1\r\nHost: example.com\r\nX-Slack-Request-Timestamp: {}\r\nContent-Length: {}\r\n\r\n{}",
+            TIMESTAMP,
+            BODY.len(),
+            BODY
+        );
+        // Incomplete requests need more to be read.
+        assert!(parse_request(&request.as_bytes()[..40]).is_none());
+        assert!(parse_request(&request.as_bytes()[..request.len() - 1]).is_none());
+
+        let request = parse_request(request.as_bytes()).unwrap().unwrap();
+        assert_eq!(request.header("x-slack-request-timestamp"), Some(TIMESTAMP));
+        assert_eq!(request.body, BODY.as_bytes());
+
+        let slash_command: SlashCommand = serde_urlencoded::from_bytes(&request.body).unwrap();
+        assert_eq!(slash_command.command, "/webhook-collect");
+        assert_eq!(slash_command.text, "");
+        assert_eq!(slash_command.user_id, "U2CERLKJA");
+        assert_eq!(slash_command.channel_id, "G8PSS9T3V");
+        assert_eq!(
+            slash_command.response_url,
+            "https://hooks.slack.com/commands/T1DC2JH3J/397700885554/96rGlfmibIGlgcZRskXaIFfN"
+        );
+    }
+
+    fn slash_command(command: &str, text: &str) -> SlashCommand {
+        SlashCommand {
+            command: command.to_string(),
+            text: text.to_string(),
+            user_id: testing::TEST_USER_ID.to_string(),
+            channel_id: testing::TEST_CHANNEL_ID.to_string(),
+            response_url: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_slash_commands() {
+        testing::setup();
+        assert_eq!(
+            process_command(&slash_command("/convert", "1 usd to eur")).await,
+            vec![serde_json::json!({
+                "response_type": "in_channel",
+                "text": "1 USD is currently 0.92 EUR.",
+            })]
+        );
+        // Errors are only shown to the user, without also saying the command wasn't understood.
+        assert_eq!(
+            process_command(&slash_command("/roll", "0d6")).await,
+            vec![serde_json::json!({
+                "response_type": "ephemeral",
+                "text": "Sorry, I can only roll 1 to 100 dice with 2 to 1000 sides.",
+            })]
+        );
+        assert_eq!(
+            process_command(&slash_command("/frobnicate", "")).await,
+            vec![serde_json::json!({
+                "response_type": "ephemeral",
+                "text": "Sorry, I didn't understand `frobnicate`.",
+            })]
+        );
+    }
+}
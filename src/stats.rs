@@ -0,0 +1,122 @@
+// Counts what the bot has been doing since it started, and reports it for `stats`. Also reports
+// how long the bot has been running for `uptime`.
+// Nothing is persisted, all counts reset on restart.
+
+use regex::Regex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::health;
+use crate::slack;
+use crate::util;
+
+const REGEX_STATS: &str = r"(?i)^stats(?:\?)?$";
+const REGEX_UPTIME: &str = r"(?i)^uptime(?:\?)?$";
+
+// Everything that is counted.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Stat {
+    Karma,
+    Seen,
+    Conversion,
+    Weather,
+    Dice,
+    ChatGpt,
+}
+
+// How each count is described, in the same order as `Stat`.
+const STAT_LABELS: &[&str] = &[
+    "karma changes",
+    "seen lookups",
+    "currency conversions",
+    "weather reports",
+    "coin flips and dice rolls",
+    "ChatGPT requests",
+];
+
+static COUNTS: [AtomicU64; 6] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+// Tokens used by ChatGPT requests, for tracking costs.
+static PROMPT_TOKENS: AtomicU64 = AtomicU64::new(0);
+static COMPLETION_TOKENS: AtomicU64 = AtomicU64::new(0);
+
+// Count that a feature did something.
+pub(crate) fn record(stat: Stat) {
+    COUNTS[stat as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+// Count the tokens used by a ChatGPT request.
+pub(crate) fn record_tokens(prompt_tokens: u32, completion_tokens: u32) {
+    PROMPT_TOKENS.fetch_add(prompt_tokens as u64, Ordering::Relaxed);
+    COMPLETION_TOKENS.fetch_add(completion_tokens as u64, Ordering::Relaxed);
+}
+
+// Check if user is asking for stats.
+pub(crate) async fn process_message(message: &slack::Message) -> Option<(String, String)> {
+    let trimmed_text = message.text.trim();
+
+    let re = Regex::new(REGEX_UPTIME).expect("failed to compile REGEX_UPTIME");
+    if re.is_match(trimmed_text) {
+        let reply_message = format_uptime(&util::time_ago(health::started(), true));
+        return Some((slack::reply_thread_ts(message), reply_message));
+    }
+
+    let re = Regex::new(REGEX_STATS).expect("failed to compile REGEX_STATS");
+    if !re.is_match(trimmed_text) {
+        return None;
+    }
+
+    let mut lines: Vec<String> = STAT_LABELS
+        .iter()
+        .zip(COUNTS.iter())
+        .map(|(label, count)| format!("• {}: {}", label, count.load(Ordering::Relaxed)))
+        .collect();
+    let prompt_tokens = PROMPT_TOKENS.load(Ordering::Relaxed);
+    let completion_tokens = COMPLETION_TOKENS.load(Ordering::Relaxed);
+    lines.push(format!(
+        "• ChatGPT tokens: {} ({} prompt, {} completion)",
+        prompt_tokens + completion_tokens,
+        prompt_tokens,
+        completion_tokens
+    ));
+    let reply_message = format!(
+        "I started {} and have processed {} messages:\n{}",
+        util::time_ago(health::started(), false),
+        health::messages_processed(),
+        lines.join("\n")
+    );
+
+    Some((slack::reply_thread_ts(message), reply_message))
+}
+
+// Turn when the bot started, such as "3 hours 12 minutes ago", into how long it has been running.
+fn format_uptime(started_ago: &str) -> String {
+    match started_ago.strip_suffix(" ago") {
+        Some(running) => format!("I've been running for {}.", running),
+        None => "I just started.".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uptime() {
+        assert_eq!(
+            format_uptime("3 hours 12 minutes ago"),
+            "I've been running for 3 hours 12 minutes."
+        );
+        assert_eq!(
+            format_uptime("a minute ago"),
+            "I've been running for a minute."
+        );
+        assert_eq!(format_uptime("just now"), "I just started.");
+    }
+}
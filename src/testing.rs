@@ -0,0 +1,65 @@
+// Helpers for tests that run messages through the whole pipeline, from the dispatcher to the
+// replies, without Slack or any other network calls. In tests Slack is always in dry run mode,
+// the database is in memory, and currency quotes come from fixed rates.
+
+use std::env;
+use std::sync::Once;
+
+use crate::db;
+use crate::dispatch;
+use crate::slack;
+
+// Messages are said by this user in this channel.
+pub(crate) const TEST_USER_ID: &str = "UTEST";
+pub(crate) const TEST_CHANNEL_ID: &str = "CTEST";
+
+// A workspace admin, for admin-only commands. Other users can't be looked up, as Slack is never
+// called.
+pub(crate) const TEST_ADMIN_ID: &str = "UADMIN";
+
+static SETUP: Once = Once::new();
+
+// Create the test database and enable features that need configuration, once per test run.
+pub(crate) fn setup() {
+    SETUP.call_once(|| {
+        db::setup();
+        env::set_var("XE_ACCOUNT_ID", "test");
+        env::set_var("XE_API_KEY", "test");
+        slack::cache_user(slack::User {
+            id: TEST_ADMIN_ID.to_string(),
+            name: "admin".to_string(),
+            is_admin: true,
+            ..Default::default()
+        });
+    });
+}
+
+// A message said by TEST_USER_ID in TEST_CHANNEL_ID.
+pub(crate) fn message(text: &str) -> slack::Message {
+    message_from(TEST_USER_ID, TEST_CHANNEL_ID, text)
+}
+
+// A message said by `user_id` in `channel_id`.
+pub(crate) fn message_from(user_id: &str, channel_id: &str, text: &str) -> slack::Message {
+    slack::Message::new(
+        channel_id.to_string(),
+        Some(user_id.to_string()),
+        text.to_string(),
+        None,
+        "1700000000.000100".to_string(),
+        None,
+    )
+}
+
+// Process a message through the dispatcher, returning a `(thread id, message)` for each reply.
+pub(crate) async fn replies(message: &slack::Message) -> Vec<(String, String)> {
+    setup();
+    dispatch::process_message(message).await
+}
+
+// Process a message as an app mention, the way the bot receives it when mentioned.
+pub(crate) async fn mention_replies(message: &slack::Message) -> Vec<(String, String)> {
+    setup();
+    let message = message.with_text(dispatch::strip_mention(&message.text));
+    dispatch::process_mention(&message).await
+}
@@ -1,6 +1,8 @@
 // General utility functions.
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use chrono::{FixedOffset, TimeZone};
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // Basic auth is a "username:password" secret that is base64 encoded.
 // What is returned must be set in an `Authorization` header, ie:
@@ -26,6 +28,68 @@ pub fn elapsed(timestamp: u64) -> u64 {
     timestamp_now() - timestamp
 }
 
+// Proactive messages, such as currency alerts, aren't posted during quiet hours, configured as
+// hours of the day in UTC with `TAG1BOT_QUIET_START` and `TAG1BOT_QUIET_END`. For example, 22
+// and 7 are quiet from 10pm until 7am.
+fn quiet_hours() -> Option<(u64, u64)> {
+    let start = env::var("TAG1BOT_QUIET_START").ok()?;
+    let end = env::var("TAG1BOT_QUIET_END").ok()?;
+    match (start.trim().parse::<u64>(), end.trim().parse::<u64>()) {
+        (Ok(start), Ok(end)) if start < 24 && end < 24 => Some((start, end)),
+        _ => {
+            log::warn!("invalid quiet hours {} to {}, ignoring", start, end);
+            None
+        }
+    }
+}
+
+// How many seconds remain until quiet hours end, or 0 if it's not quiet hours.
+pub(crate) fn quiet_seconds_remaining() -> u64 {
+    match quiet_hours() {
+        Some((start, end)) => seconds_until_quiet_end(timestamp_now(), start, end),
+        None => 0,
+    }
+}
+
+// How many seconds remain at `timestamp` until quiet hours from the `start` hour to the `end`
+// hour end, or 0 if it's not quiet hours. Quiet hours can span midnight.
+fn seconds_until_quiet_end(timestamp: u64, start: u64, end: u64) -> u64 {
+    const DAY: u64 = 60 * 60 * 24;
+    let now = timestamp % DAY;
+    let start = start * 60 * 60;
+    let end = end * 60 * 60;
+    if start < end && now >= start && now < end {
+        end - now
+    } else if start > end && now >= start {
+        DAY - now + end
+    } else if start > end && now < end {
+        end - now
+    } else {
+        0
+    }
+}
+
+// Wait for quiet hours to end, if it's currently quiet hours.
+pub(crate) async fn wait_for_quiet_hours() {
+    let quiet_seconds = quiet_seconds_remaining();
+    if quiet_seconds > 0 {
+        log::info!("quiet hours, waiting {} seconds", quiet_seconds);
+        tokio::time::sleep(Duration::from_secs(quiet_seconds)).await;
+    }
+}
+
+// Display the timestamp as a date and time, ie "Mar 3 at 3:45pm", in the timezone `tz_offset`
+// seconds from UTC. Falls back to UTC if the offset is missing or invalid.
+pub fn format_local(timestamp: u64, tz_offset: Option<i32>) -> String {
+    let offset = tz_offset
+        .and_then(FixedOffset::east_opt)
+        .unwrap_or_else(|| FixedOffset::east_opt(0).expect("invalid UTC offset"));
+    match offset.timestamp_opt(timestamp as i64, 0).single() {
+        Some(local) => local.format("%b %-d at %-I:%M%P").to_string(),
+        None => "an unknown time".to_string(),
+    }
+}
+
 // Display "X time ago" style text.
 pub fn time_ago(timestamp: u64, precision: bool) -> String {
     let mut seconds: u64 = elapsed(timestamp);
@@ -222,3 +286,41 @@ pub fn time_ago(timestamp: u64, precision: bool) -> String {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+
+    #[test]
+    fn basic_auth() {
+        assert_eq!(generate_basic_auth("user", "pass"), "Basic dXNlcjpwYXNz");
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        // Quiet from 1am until 6am.
+        assert_eq!(seconds_until_quiet_end(0, 1, 6), 0);
+        assert_eq!(seconds_until_quiet_end(HOUR, 1, 6), 5 * HOUR);
+        assert_eq!(seconds_until_quiet_end(5 * HOUR + 1800, 1, 6), 1800);
+        assert_eq!(seconds_until_quiet_end(6 * HOUR, 1, 6), 0);
+        assert_eq!(seconds_until_quiet_end(23 * HOUR, 1, 6), 0);
+    }
+
+    #[test]
+    fn quiet_hours_across_midnight() {
+        // Quiet from 10pm until 7am, on the second day since the epoch.
+        let day = 24 * HOUR;
+        assert_eq!(seconds_until_quiet_end(day + 21 * HOUR, 22, 7), 0);
+        assert_eq!(seconds_until_quiet_end(day + 22 * HOUR, 22, 7), 9 * HOUR);
+        assert_eq!(seconds_until_quiet_end(day + 3 * HOUR, 22, 7), 4 * HOUR);
+        assert_eq!(seconds_until_quiet_end(day + 7 * HOUR, 22, 7), 0);
+        assert_eq!(seconds_until_quiet_end(day + 12 * HOUR, 22, 7), 0);
+    }
+
+    #[test]
+    fn quiet_hours_empty() {
+        assert_eq!(seconds_until_quiet_end(HOUR, 1, 1), 0);
+    }
+}
@@ -0,0 +1,212 @@
+// Reports current weather conditions.
+// For example, `weather Paris` or `weather Portland, OR`.
+
+use json::JsonValue;
+use regex::Regex;
+use serde::Serialize;
+use std::env;
+
+use crate::slack;
+use crate::stats;
+
+const REGEX_WEATHER: &str = r"(?i)^weather (.{1,100})$";
+
+const WEATHER_API: &str = "https://api.openweathermap.org/data/2.5/weather";
+
+// Query string sent to the weather API.
+#[derive(Serialize)]
+struct WeatherQuery<'a> {
+    q: &'a str,
+    appid: &'a str,
+    units: &'a str,
+}
+
+// Determine if the text is a weather command, ie `weather Paris`.
+pub(crate) fn is_command(trimmed_text: &str) -> bool {
+    let re = Regex::new(REGEX_WEATHER).expect("failed to compile REGEX_WEATHER");
+    re.is_match(trimmed_text)
+}
+
+// Check if user is asking for the weather.
+pub(crate) async fn process_message(message: &slack::Message) -> Option<(String, String)> {
+    let trimmed_text = message.text.trim();
+
+    let re = Regex::new(REGEX_WEATHER).expect("failed to compile REGEX_WEATHER");
+    let cap = re.captures(trimmed_text)?;
+    let location = cap[1].trim().trim_end_matches('?');
+
+    match current_weather(location).await {
+        Ok(response_string) => {
+            stats::record(stats::Stat::Weather);
+            let reply_thread_ts = if let Some(thread_ts) = message.thread_ts.as_ref() {
+                thread_ts.clone()
+            } else {
+                message.ts.clone()
+            };
+            Some((reply_thread_ts, response_string))
+        }
+        // Errors are only of interest to the user that made the request.
+        Err(response_string) => slack::reply_ephemeral(message, response_string).await,
+    }
+}
+
+// Look up the current weather in `location`.
+pub(crate) async fn current_weather(location: &str) -> Result<String, String> {
+    // Get weather API key from the environment.
+    let key = match env::var("WEATHER_API_KEY") {
+        Ok(key) => key,
+        Err(_) => {
+            log::error!("WEATHER_API_KEY is not set");
+            return Err("Sorry, the weather service isn't configured.".to_string());
+        }
+    };
+
+    let query = WeatherQuery {
+        q: location,
+        appid: &key,
+        units: "metric",
+    };
+    let request = match surf::get(WEATHER_API).query(&query) {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("failed to build weather request: {}", e);
+            return Err(format!(
+                "Sorry, I couldn't look up the weather in `{}`.",
+                location
+            ));
+        }
+    };
+
+    // Make the remote request.
+    let mut response = match request.await {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("weather request failed: {}", e);
+            return Err("Sorry, the weather service isn't responding right now.".to_string());
+        }
+    };
+    let body = match response.body_string().await {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("failed to read weather response: {}", e);
+            return Err("Sorry, the weather service isn't responding right now.".to_string());
+        }
+    };
+    if response.status() == 404 {
+        return Err(format!("Sorry, I don't know where `{}` is.", location));
+    } else if !response.status().is_success() {
+        log::error!("weather request failed ({}): {}", response.status(), body);
+        return Err("Sorry, the weather service isn't responding right now.".to_string());
+    }
+
+    // Parse the weather API response.
+    let parsed_response = match json::parse(&body) {
+        Ok(j) => j,
+        Err(e) => {
+            log::error!("invalid weather response: {}", e);
+            return Err(
+                "Sorry, the weather service sent me something I don't understand.".to_string(),
+            );
+        }
+    };
+
+    format_weather(&parsed_response, location)
+}
+
+// Build the reply describing the weather from a weather API response.
+fn format_weather(parsed_response: &JsonValue, location: &str) -> Result<String, String> {
+    let temperature = match parsed_response["main"]["temp"].as_f32() {
+        Some(t) => t,
+        None => {
+            log::error!("weather response missing temperature: {}", parsed_response);
+            return Err(
+                "Sorry, the weather service sent me something I don't understand.".to_string(),
+            );
+        }
+    };
+    let feels_like = parsed_response["main"]["feels_like"].as_f32();
+    let humidity = parsed_response["main"]["humidity"].as_u32();
+    let wind = parsed_response["wind"]["speed"].as_f32();
+    let conditions = parsed_response["weather"][0]["description"]
+        .as_str()
+        .unwrap_or("unknown conditions");
+    let name = parsed_response["name"].as_str().unwrap_or(location);
+    let country = parsed_response["sys"]["country"].as_str();
+
+    let mut reply = format!(
+        "It's currently {} in *{}{}*, {}",
+        conditions,
+        name,
+        country.map_or(String::new(), |c| format!(", {}", c)),
+        format_temperature(temperature),
+    );
+    if let Some(feels_like) = feels_like {
+        reply.push_str(&format!(" (feels like {})", format_temperature(feels_like)));
+    }
+    if let Some(humidity) = humidity {
+        reply.push_str(&format!(", {}% humidity", humidity));
+    }
+    if let Some(wind) = wind {
+        reply.push_str(&format!(", wind {:.1} m/s", wind));
+    }
+    reply.push('.');
+
+    Ok(reply)
+}
+
+// Show a temperature in both Celsius and Fahrenheit.
+fn format_temperature(celsius: f32) -> String {
+    format!("{:.0}°C/{:.0}°F", celsius, celsius * 9.0 / 5.0 + 32.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &str = r#"{
+        "weather": [{"id": 500, "main": "Rain", "description": "light rain"}],
+        "main": {"temp": 12.4, "feels_like": 11.0, "humidity": 81},
+        "wind": {"speed": 4.12},
+        "sys": {"country": "FR"},
+        "name": "Paris",
+        "cod": 200
+    }"#;
+
+    #[test]
+    fn formats_weather() {
+        let parsed_response = json::parse(RESPONSE).unwrap();
+        assert_eq!(
+            format_weather(&parsed_response, "paris"),
+            Ok("It's currently light rain in *Paris, FR*, 12°C/54°F (feels like 11°C/52°F), 81% humidity, wind 4.1 m/s.".to_string())
+        );
+
+        // Only the temperature is required.
+        let parsed_response = json::parse(r#"{"main": {"temp": 20}}"#).unwrap();
+        assert_eq!(
+            format_weather(&parsed_response, "Atlantis"),
+            Ok("It's currently unknown conditions in *Atlantis*, 20°C/68°F.".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_weather_without_temperature() {
+        let parsed_response = json::parse(r#"{"cod": 200, "main": {"humidity": 81}}"#).unwrap();
+        assert!(format_weather(&parsed_response, "paris").is_err());
+        assert!(format_weather(&JsonValue::Null, "paris").is_err());
+    }
+
+    #[test]
+    fn formats_temperatures() {
+        assert_eq!(format_temperature(0.0), "0°C/32°F");
+        assert_eq!(format_temperature(-40.0), "-40°C/-40°F");
+        assert_eq!(format_temperature(36.6), "37°C/98°F");
+    }
+
+    #[tokio::test]
+    async fn requires_api_key() {
+        assert_eq!(
+            current_weather("paris").await,
+            Err("Sorry, the weather service isn't configured.".to_string())
+        );
+    }
+}